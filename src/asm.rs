@@ -1,6 +1,16 @@
 use crate::common::{instruction_data, Instruction, PseudoInstruction, CHAR_TABLE};
+use crate::instruction::store;
 use crate::mix_word::{Byte, Sign, WordImpl};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+mod deck;
+mod listing;
+mod macros;
+pub use deck::{loading_deck, LOADER_SIZE};
+pub use listing::listing;
+use macros::{locate_error, preprocess_impl};
+pub use macros::{preprocess, SourceLine};
+use std::path::Path;
 use std::str::FromStr;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -41,30 +51,144 @@ fn char_to_num(c: char) -> Option<usize> {
     }
     None
 }
-fn split_into_loc_ope_addr(code: &str) -> (Option<&str>, Attribute, &str) {
-    // TODO: temporarily, assumes no whitespaces in code
+// ソースの書式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceFormat {
+    // 空白で区切る. ADDRESS の後と ; の後は注釈.
+    // ALF のオペランドは ALF の後の空白 1 つに続く 5 文字か, 引用符で囲んだ文字
+    FreeForm,
+    // Knuth のカード形式. LOC は 1-10 桁, OP は 12-15 桁, ADDRESS は 16 桁から最初の空白まで
+    Card,
+}
+
+// 行の begin 文字目から end 文字目まで (0 始まり, end は含まない)
+fn columns(line: &str, begin: usize, end: usize) -> &str {
+    let offset = |n: usize| {
+        line.char_indices()
+            .nth(n)
+            .map(|(i, _)| i)
+            .unwrap_or(line.len())
+    };
+    let begin = offset(begin);
+    let end = offset(end).max(begin);
+    &line[begin..end]
+}
+// 注釈と行頭の空白を除く. カード形式は桁を保つ.
+fn strip_remarks(line: &str, format: SourceFormat) -> &str {
+    match format {
+        SourceFormat::FreeForm => line.split(';').next().unwrap().trim(),
+        SourceFormat::Card => line.trim_end(),
+    }
+}
+// LOC, OP, ADDRESS. 読めなかったらエラーの種類, 読めなかった部分と理由
+type Fields<'a> = Result<(Option<&'a str>, Attribute, &'a str), (AsmErrorKind, &'a str, String)>;
+// 行を LOC, OP, ADDRESS に分ける.
+fn split_into_loc_ope_addr(code: &str, format: SourceFormat) -> Fields<'_> {
+    match format {
+        SourceFormat::FreeForm => split_free_form(code),
+        SourceFormat::Card => split_card(code),
+    }
+}
+fn split_free_form(code: &str) -> Fields<'_> {
+    let unknown = |part| {
+        (
+            AsmErrorKind::UnknownOperation,
+            part,
+            format!("unknown operation {}", part),
+        )
+    };
     let mut iter = code.split_whitespace();
-    let first_term = iter.next().unwrap();
+    let first_term = iter.next().ok_or_else(|| unknown(code))?;
     let first_term_attribute = get_attribute(first_term);
-    let (loc, attr) = if first_term_attribute.is_none() {
+    let (loc, attr, ope) = if first_term_attribute.is_none() {
         // first term is loc
-        let second_term = iter.next().unwrap();
-        (Some(first_term), get_attribute(second_term).unwrap())
+        let second_term = iter.next().ok_or_else(|| unknown(first_term))?;
+        match get_attribute(second_term) {
+            Some(attr) => (Some(first_term), attr, second_term),
+            // "FOO 1" のように 2 つ目がアドレスに見えれば 1 つ目が命令
+            None if iter.next().is_none()
+                && second_term.starts_with(|c: char| c.is_ascii_digit() || "=*+-".contains(c)) =>
+            {
+                return Err(unknown(first_term))
+            }
+            None => return Err(unknown(second_term)),
+        }
     } else {
-        // first term is ope
-        // ERROR: if no operation in code
-        (None, first_term_attribute.unwrap())
+        // "END EQU 5" のように 2 つ目も命令で, アドレスが続くなら 1 つ目は LOC
+        let mut lookahead = iter.clone();
+        match (lookahead.next(), lookahead.next()) {
+            (Some(second_term), Some(_)) if get_attribute(second_term).is_some() => {
+                iter.next();
+                (
+                    Some(first_term),
+                    get_attribute(second_term).unwrap(),
+                    second_term,
+                )
+            }
+            // "JMP END" のように 2 つだけなら 1 つ目が命令
+            _ => (None, first_term_attribute.unwrap(), first_term),
+        }
     };
+    if attr == Attribute::PseudoInstruction(PseudoInstruction::ALF) {
+        let rest = &code[(offset_of(code, ope) + ope.len())..];
+        // ALF "A B C" のように引用符で囲めば空白を書ける
+        if let Some(quoted) = rest.trim_start().strip_prefix('"') {
+            return Ok((loc, attr, quoted.split('"').next().unwrap()));
+        }
+        // そうでなければ ALF の後の空白 1 つに続く 5 文字. 空白も文字として読み, 後は注釈
+        if let Some(operand) = rest.strip_prefix(char::is_whitespace) {
+            let end = operand
+                .char_indices()
+                .nth(5)
+                .map_or(operand.len(), |(i, _)| i);
+            let (alf, tail) = operand.split_at(end);
+            if tail.is_empty() || tail.starts_with(char::is_whitespace) {
+                return Ok((loc, attr, alf));
+            }
+        }
+    }
     let addr = iter.next().unwrap_or("0");
 
-    (loc, attr, addr)
+    Ok((loc, attr, addr))
+}
+fn split_card(code: &str) -> Fields<'_> {
+    let loc = columns(code, 0, 10).trim();
+    let ope = columns(code, 11, 15).trim();
+    let attr = get_attribute(ope).ok_or_else(|| {
+        let part = if ope.is_empty() { code } else { ope };
+        (
+            AsmErrorKind::UnknownOperation,
+            part,
+            format!("unknown operation {}", part),
+        )
+    })?;
+    let addr = if attr == Attribute::PseudoInstruction(PseudoInstruction::ALF) {
+        // 16 桁が空白なら Knuth と同じく 17-21 桁
+        let begin = if columns(code, 15, 16).trim().is_empty() {
+            16
+        } else {
+            15
+        };
+        columns(code, begin, begin + 5)
+    } else {
+        columns(code, 15, usize::MAX)
+            .split_whitespace()
+            .next()
+            .unwrap_or("0")
+    };
+    let loc = if loc.is_empty() { None } else { Some(loc) };
+
+    Ok((loc, attr, addr))
+}
+// part が code の何バイト目から始まるか
+fn offset_of(code: &str, part: &str) -> usize {
+    part.as_ptr() as usize - code.as_ptr() as usize
 }
-fn split_into_addr_index_modi(code: &str) -> (&str, Option<&str>, Option<&str>) {
+fn split_into_addr_index_modi(code: &str) -> Result<(&str, Option<&str>, Option<&str>), String> {
     let (code, modi) = match code.find('(') {
         Some(mid) => {
-            if !code.ends_with(")") {
-                // ERROR: should end with ")"
-                panic!();
+            if !code.ends_with(')') {
+                return Err("missing )".to_string());
             }
             (
                 code.get(..mid).unwrap(),
@@ -73,11 +197,11 @@ fn split_into_addr_index_modi(code: &str) -> (&str, Option<&str>, Option<&str>)
         }
         None => (code, None),
     };
-    let (addr, index) = match code.find(",") {
+    let (addr, index) = match code.find(',') {
         Some(mid) => (code.get(..mid).unwrap(), code.get((mid + 1)..)),
         None => (code, None),
     };
-    (addr, index, modi)
+    Ok((addr, index, modi))
 }
 // here
 fn is_local_symbol_h(s: &str) -> bool {
@@ -107,66 +231,282 @@ fn is_local_symbol_b(s: &str) -> bool {
     }
 }
 
+// アセンブルの結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembledProgram {
+    pub entry_point: usize,
+    pub binary: Vec<(usize, WordImpl)>,
+    // 前処理した行. line_address, line_value, エラー以外の行番号はこの添字
+    pub source: Vec<SourceLine>,
+    // 行番号 (0 始まり) から番地
+    pub line_address: HashMap<usize, usize>,
+    // 行番号から, その行の語の binary での添字
+    pub line_binary: HashMap<usize, usize>,
+    // EQU, ORIG, END の行番号 (0 始まり) から値
+    pub line_value: HashMap<usize, i64>,
+    // END で確保した語の番地と, 元になったリテラル定数 ("=5=") か未定義シンボル ("X CON 0")
+    pub generated: Vec<(usize, String)>,
+    // シンボルテーブル. 局所シンボルとリテラル定数は含まない
+    pub symbols: BTreeMap<String, i64>,
+    // assemble_module でアセンブルした再配置できるモジュールか
+    pub relocatable: bool,
+    // 使った番地の範囲 (0..size). ORIG で確保しただけの領域も含む
+    pub size: usize,
+    // 番地から, 配置したときにその語をどう直すか. モジュールのときだけ
+    pub relocations: BTreeMap<usize, Relocation>,
+    // ENTRY で公開したシンボルの値と, 配置した位置だけずれるかどうか
+    pub exports: BTreeMap<String, (i64, bool)>,
+}
+
+// 再配置できるモジュールの語を, 配置した位置 (base) に合わせて直す方法
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Relocation {
+    // アドレス部 (0:2) に base を足す
+    Address,
+    // 語の値に base を足す
+    Value,
+    // アドレス部 (0:2) に外部シンボルの値を足す
+    External(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    // 行の形がおかしい (括弧の対応, 余計なインデックスなど)
+    Syntax,
+    // 命令でも疑似命令でもない
+    UnknownOperation,
+    // 同じシンボルを 2 回定義した
+    DuplicateSymbol,
+    // 定義されていないシンボルを使った
+    UndefinedSymbol,
+    // 式が読めない, 0 で割った
+    InvalidExpression,
+    // ALF のオペランドが 5 文字でない, MIX の文字でない
+    InvalidAlf,
+    // リテラル定数が = で閉じていない
+    InvalidLiteral,
+    // 値がアドレス, インデックス, フィールド, 番地の範囲外
+    OutOfRange,
+    // 索引部, フィールド部, W-value での未来参照
+    FutureReference,
+    // INCLUDE するファイルが読めない
+    Include,
+}
+
+// アセンブルのエラー. line は 1 始まり, columns は 1 始まりの文字位置で終わりを含まない.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub columns: std::ops::Range<usize>,
+    pub kind: AsmErrorKind,
+    pub message: String,
+}
+
+impl AsmError {
+    // part は line の一部分. そうでなければ行全体を指す.
+    fn new(kind: AsmErrorKind, line: &str, part: &str, message: String) -> Self {
+        let base = line.as_ptr() as usize;
+        let begin = part.as_ptr() as usize;
+        let columns = if base <= begin && begin + part.len() <= base + line.len() {
+            let start = line[..(begin - base)].chars().count() + 1;
+            start..(start + part.chars().count().max(1))
+        } else {
+            1..(line.chars().count() + 1)
+        };
+        AsmError {
+            line: 0,
+            columns,
+            kind,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.columns.start, self.message)
+    }
+}
+
+// 未定義のシンボルを含むアドレス. シンボルがすべて定義されたら評価して埋める.
+#[derive(Debug)]
+struct Fixup {
+    // binary の何番目か
+    position: usize,
+    exp: Exp,
+    // エラーを報告する位置
+    site: AsmError,
+}
+
+// 式を評価できなかった理由
+#[derive(Debug, PartialEq, Eq)]
+enum EvalError {
+    Undefined(String),
+    DivisionByZero,
+    Overflow,
+}
+
+impl std::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EvalError::Undefined(symbol) => write!(f, "symbol {} is not defined", symbol),
+            EvalError::DivisionByZero => write!(f, "division by zero"),
+            EvalError::Overflow => write!(f, "overflow in expression"),
+        }
+    }
+}
+
 pub fn debug_assemble(code: &str) -> (usize, Vec<(usize, WordImpl)>, HashMap<usize, usize>) {
+    match assemble(code) {
+        Ok(program) => (program.entry_point, program.binary, program.line_address),
+        Err(errors) => {
+            let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            panic!("{}", errors.join("\n"));
+        }
+    }
+}
+
+// 式の値. シンボルは symbols の値, * は location とする.
+pub fn evaluate(
+    code: &str,
+    symbols: &BTreeMap<String, i64>,
+    location: usize,
+) -> Result<i64, String> {
+    validate_exp(code)?;
+    let symbols: HashMap<String, i64> = symbols.clone().into_iter().collect();
+    let exp = replace_symbol(construct_exp(code), &symbols, &HashSet::new());
+    eval(replace_asterisk(exp, location)).map_err(|e| e.to_string())
+}
+
+pub fn assemble(code: &str) -> Result<AssembledProgram, Vec<AsmError>> {
+    assemble_with(code, SourceFormat::FreeForm)
+}
+
+pub fn assemble_with(code: &str, format: SourceFormat) -> Result<AssembledProgram, Vec<AsmError>> {
+    assemble_impl(code, format, false, Path::new(""))
+}
+
+// 0 番地から始まる再配置できるモジュールとしてアセンブルする.
+// ラベルや * を使ったアドレスは link で配置した位置だけずらす. ENTRY で公開したシンボルは
+// 他のモジュールから EXTERN で宣言して命令のアドレス部で使える.
+pub fn assemble_module(
+    code: &str,
+    format: SourceFormat,
+) -> Result<AssembledProgram, Vec<AsmError>> {
+    assemble_impl(code, format, true, Path::new(""))
+}
+
+// INCLUDE のファイルを dir から探す. module なら assemble_module と同じ.
+pub fn assemble_at(
+    code: &str,
+    format: SourceFormat,
+    module: bool,
+    dir: &Path,
+) -> Result<AssembledProgram, Vec<AsmError>> {
+    assemble_impl(code, format, module, dir)
+}
+
+fn assemble_impl(
+    code: &str,
+    format: SourceFormat,
+    module: bool,
+    dir: &Path,
+) -> Result<AssembledProgram, Vec<AsmError>> {
     use PseudoInstruction::*;
 
     // return value
     let mut binary: Vec<(usize, WordImpl)> = vec![];
     let mut line_address: HashMap<usize, usize> = HashMap::new();
+    let mut line_binary: HashMap<usize, usize> = HashMap::new();
+    let mut line_value: HashMap<usize, i64> = HashMap::new();
+    let mut generated: Vec<(usize, String)> = vec![];
     let mut entry_point = 0usize;
+    let mut errors: Vec<AsmError> = vec![];
 
     // tables
     let mut symbol_table: HashMap<String, i64> = HashMap::new();
-    let mut unresolved_symbol: HashMap<String, Vec<usize>> = HashMap::new();
-    let mut constant_literal: Vec<(String, String)> = vec![];
+    // 値が番地であるシンボル (ラベルなど)
+    let mut relocatable_symbols: HashSet<String> = HashSet::new();
+    // EXTERN と ENTRY で宣言したシンボル (ENTRY は報告する位置も)
+    let mut externals: HashSet<String> = HashSet::new();
+    let mut entries: Vec<(String, AsmError)> = vec![];
+    let mut relocations: Vec<RelocationEntry> = vec![];
+    let mut size = 0usize;
+    let mut fixups: Vec<Fixup> = vec![];
+    // 索引部, フィールド部, W-value で定義前に使われたシンボル (errors の位置, シンボル, 部分の名前)
+    let mut undefined_uses: Vec<(usize, String, &str)> = vec![];
+    // シンボル, 式, 書かれていた行
+    let mut constant_literal: Vec<(String, String, usize)> = vec![];
 
     // generated codes
     let mut generated_code: Vec<String> = vec![];
 
     // loop indices
-    let mut line_iterator = code.lines().enumerate().peekable();
+    let (source, generated_labels) = preprocess_impl(code, format, dir)?;
+    let source_lines: Vec<&str> = source.iter().map(|s| s.text.as_str()).collect();
+    let mut line_iterator = source_lines.iter().copied().enumerate().peekable();
     let mut location_counter = 0usize;
 
-    loop {
+    'lines: loop {
+        size = size.max(location_counter);
         if line_iterator.peek().is_none() {
             break;
         }
         // check whether this line is END or not
-        let (_line, content) = line_iterator.peek().unwrap();
-        let content = content.trim();
-        if content.starts_with("*") {
+        let (end_line, content) = line_iterator.peek().unwrap();
+        let end_line = *end_line;
+        if content.starts_with('*')
+            || content.trim().starts_with('*') && format == SourceFormat::FreeForm
+        {
+            // comment
+            let _ = line_iterator.next();
+            continue;
+        }
+        let content = strip_remarks(content, format);
+        if content.trim().is_empty() {
             // comment
             let _ = line_iterator.next();
             continue;
         }
-        let (eloc, attr, _addr) = split_into_loc_ope_addr(content);
+        let (eloc, attr) = match split_into_loc_ope_addr(content, format) {
+            Ok((eloc, attr, _addr)) => (eloc, Some(attr)),
+            Err(_) => (None, None),
+        };
 
-        let (line, content) = if attr == Attribute::PseudoInstruction(END) {
+        // 生成した行のエラーは, 元になった行 (リテラル定数か END) で報告する
+        let mut origin = end_line;
+        let (line, content) = if attr == Some(Attribute::PseudoInstruction(END)) {
             // if END, generate codes
             if constant_literal.len() != 0 {
-                let (unique_symbol, addr) = constant_literal.pop().unwrap();
+                let (unique_symbol, addr, literal_line) = constant_literal.pop().unwrap();
+                generated.push((location_counter, format!("={}=", addr)));
                 generated_code.push(unique_symbol + " CON " + &addr);
+                origin = literal_line;
                 // TODO: remove magic number 7777
                 (7777, generated_code.last().unwrap().as_str())
-            } else if unresolved_symbol.len() > 1 {
-                let mut itr = unresolved_symbol.keys();
-                let mut loc = itr.next().unwrap();
-                if Some(loc.as_str()) == eloc {
-                    loc = itr.next().unwrap()
-                }
-                generated_code.push(loc.clone() + " CON 0");
-                (7777, generated_code.last().unwrap().as_str())
-            } else if unresolved_symbol.len() == 1 {
-                let mut itr = unresolved_symbol.keys();
-                let loc = itr.next().unwrap();
-                if Some(loc.as_str()) == eloc {
-                    // all tables are clear
-                    line_iterator.next().unwrap()
-                } else {
-                    generated_code.push(loc.clone() + " CON 0");
-                    (7777, generated_code.last().unwrap().as_str())
-                }
             } else {
+                // 未定義のシンボルは CON 0 の番地になる
+                while let Some(symbol) = fixups
+                    .iter()
+                    .flat_map(|fixup| symbols_of(&fixup.exp))
+                    .filter(|s| {
+                        Some(s.as_str()) != eloc && !is_local_symbol_f(s) && !externals.contains(s)
+                    })
+                    .min()
+                {
+                    resolve_fixups(
+                        &mut fixups,
+                        (&symbol, location_counter as i64, true),
+                        &mut binary,
+                        &mut relocations,
+                        &mut errors,
+                    );
+                    generated.push((location_counter, format!("{} CON 0", symbol)));
+                    relocatable_symbols.insert(symbol.clone());
+                    symbol_table.insert(symbol, location_counter as i64);
+                    binary.push((location_counter, WordImpl::from_val(0)));
+                    location_counter += 1;
+                }
                 // all tables are clear
                 line_iterator.next().unwrap()
             }
@@ -174,9 +514,66 @@ pub fn debug_assemble(code: &str) -> (usize, Vec<(usize, WordImpl)>, HashMap<usi
             // if not END, step iterator
             line_iterator.next().unwrap()
         };
+        if line != 7777 {
+            origin = line;
+        }
+        let source = source_lines[origin];
 
         // 1. split content into LOC, OPE, ADDR
-        let (loc, attr, addr) = split_into_loc_ope_addr(content);
+        // 生成した行は自由形式
+        let line_format = if line == 7777 {
+            SourceFormat::FreeForm
+        } else {
+            format
+        };
+        let (loc, attr, addr) =
+            match split_into_loc_ope_addr(strip_remarks(content, line_format), line_format) {
+                Ok(v) => v,
+                Err((kind, part, message)) => {
+                    // 番地を進める命令として扱い, 後続の行の番地がずれないようにする
+                    let mut e = AsmError::new(kind, source, part, message);
+                    e.line = origin + 1;
+                    errors.push(e);
+                    location_counter += 1;
+                    continue;
+                }
+            };
+        // 語を生成する行はエラーがあっても番地を進める
+        let generates_word = matches!(
+            attr,
+            Attribute::Instruction(_)
+                | Attribute::PseudoInstruction(CON)
+                | Attribute::PseudoInstruction(ALF)
+        );
+        macro_rules! fail {
+            ($kind: expr, $part: expr, $($message: tt)*) => {{
+                let mut e = AsmError::new($kind, source, $part, format!($($message)*));
+                e.line = origin + 1;
+                errors.push(e);
+                if generates_word {
+                    location_counter += 1;
+                }
+                // 部分のループの中からでも行ごと飛ばす
+                continue 'lines;
+            }};
+        }
+        if generates_word && location_counter >= 4000 {
+            fail!(
+                AsmErrorKind::OutOfRange,
+                content,
+                "location {} is out of memory",
+                location_counter
+            );
+        }
+
+        if let Some(loc) = loc {
+            if let Some(c) = loc
+                .chars()
+                .find(|c| !c.is_ascii() && char_to_num(*c).is_none())
+            {
+                fail!(AsmErrorKind::Syntax, loc, "'{}' is not a MIX character", c);
+            }
+        }
 
         // 2. push LOC into HashMap
         if attr != Attribute::PseudoInstruction(EQU) && loc.is_some() {
@@ -186,183 +583,545 @@ pub fn debug_assemble(code: &str) -> (usize, Vec<(usize, WordImpl)>, HashMap<usi
             } else {
                 loc
             };
-            // search unresolved symbols and resolve them
-            let unresolved = unresolved_symbol.remove(loc);
-            if unresolved.is_some() {
-                let unresolved = unresolved.unwrap();
-                for pos in unresolved.iter() {
-                    let word = &mut binary.get_mut(*pos).unwrap().1;
-                    *word.byte_mut(0).unwrap() =
-                        Byte::new((location_counter / Byte::max() as usize) as u32);
-                    *word.byte_mut(1).unwrap() =
-                        Byte::new((location_counter % Byte::max() as usize) as u32);
-                }
-            }
             // if loc is local symbol, remove from symbol_table
             if loc.parse::<usize>().is_ok() {
                 let _ = symbol_table.remove(loc);
             }
-            if symbol_table.get(loc).is_some() {
-                // ERROR: cannot define twice
-                panic!();
+            if symbol_table.get(loc).is_some() || externals.contains(loc) {
+                fail!(
+                    AsmErrorKind::DuplicateSymbol,
+                    loc,
+                    "symbol {} is already defined",
+                    loc
+                );
             }
+            // search unresolved symbols and resolve them
+            resolve_fixups(
+                &mut fixups,
+                (loc, location_counter as i64, true),
+                &mut binary,
+                &mut relocations,
+                &mut errors,
+            );
+            relocatable_symbols.insert(loc.to_string());
             symbol_table.insert(loc.to_string(), location_counter as i64);
         }
 
         // 3. deal with ALF
         if attr == Attribute::PseudoInstruction(ALF) {
-            if addr.len() != 5 {
-                // ERROR: addr length should be equal to 5
-                panic!();
+            // カード形式は桁で読むので短い行は空白で埋める
+            let operand = match line_format {
+                SourceFormat::FreeForm => addr.to_string(),
+                SourceFormat::Card => format!("{:<5}", addr),
+            };
+            if operand.chars().count() != 5 {
+                fail!(
+                    AsmErrorKind::InvalidAlf,
+                    addr,
+                    "ALF operand {} should have 5 characters",
+                    addr
+                );
             }
             let mut v = vec![];
-            for c in addr.replace("_", " ").chars() {
+            for c in operand.chars() {
                 match char_to_num(c) {
                     Some(num) => v.push(num as u32),
-                    // ERROR: illegal char in addr
-                    None => panic!(),
+                    None => fail!(
+                        AsmErrorKind::InvalidAlf,
+                        addr,
+                        "'{}' is not a MIX character",
+                        c
+                    ),
                 }
             }
+            line_binary.insert(line, binary.len());
             binary.push((location_counter, WordImpl::from_seq(Sign::Positive, &v)));
             line_address.insert(line, location_counter);
             location_counter += 1;
             continue;
         }
 
+        // ENTRY と EXTERN はシンボルを , で区切って並べる
+        if let Attribute::PseudoInstruction(pseudo @ (Entry | Extern)) = &attr {
+            for name in addr.split(',') {
+                if validate_exp(name).is_err() || symbols_of(&construct_exp(name)) != [name] {
+                    fail!(AsmErrorKind::Syntax, name, "invalid symbol {}", name);
+                }
+                if *pseudo == Entry {
+                    let mut site =
+                        AsmError::new(AsmErrorKind::UndefinedSymbol, source, name, String::new());
+                    site.line = origin + 1;
+                    entries.push((name.to_string(), site));
+                } else if symbol_table.contains_key(name) {
+                    fail!(
+                        AsmErrorKind::DuplicateSymbol,
+                        name,
+                        "symbol {} is already defined",
+                        name
+                    );
+                } else {
+                    externals.insert(name.to_string());
+                }
+            }
+            continue;
+        }
+
         // 4. deal with literal constants
         let addr = if addr.starts_with("=") {
-            if !addr.ends_with("=") {
-                // ERROR: should end with "="
-                panic!();
+            if addr.len() < 2 || !addr.ends_with("=") {
+                fail!(
+                    AsmErrorKind::InvalidLiteral,
+                    addr,
+                    "literal {} should end with =",
+                    addr
+                );
+            }
+            let literal = addr.get(1..(addr.len() - 1)).unwrap();
+            if let Err((kind, message)) = validate_wvalue(literal) {
+                fail!(kind, addr, "{}", message);
             }
             // TODO: generate real unique symbol
             let unique_symbol = "UNQSYM".to_string() + &constant_literal.len().to_string();
-            constant_literal.push((
-                unique_symbol,
-                addr.get(1..(addr.len() - 1)).unwrap().to_string(),
-            ));
+            constant_literal.push((unique_symbol, literal.to_string(), origin));
 
             &constant_literal.last().as_ref().unwrap().0
         } else {
             addr
         };
 
-        // 5. split addr into ADDR, INDEX, MODIFICATION and construct expression
-        // and resolve symbols
-        // TODO: temporarily, ignore W-value
-        let (addr, index, modi) = split_into_addr_index_modi(addr);
-        let index = index
-            .map(|s| construct_exp(s))
-            .map(|exp| replace_symbol(exp, &symbol_table))
-            .map(|exp| replace_asterisk(exp, location_counter))
-            .map(|exp| eval(exp).unwrap());
-        let modi = modi
-            .map(|s| construct_exp(s))
-            .map(|exp| replace_symbol(exp, &symbol_table))
-            .map(|exp| replace_asterisk(exp, location_counter))
-            .map(|exp| eval(exp).unwrap());
-        // TODO: remove Some(..).map()....unwrap() pattern
-        let mut addr = Some(addr)
-            .map(|s| construct_exp(s))
-            .map(|exp| replace_symbol(exp, &symbol_table))
-            .map(|exp| replace_asterisk(exp, location_counter))
-            .unwrap();
+        // 索引部, フィールド部, W-value には未来参照を書けない
+        macro_rules! evaluate {
+            ($part: expr, $what: expr) => {{
+                let part = $part;
+                let exp = replace_symbol(construct_exp(part), &symbol_table, &relocatable_symbols);
+                let exp = replace_asterisk(exp, location_counter);
+                let kind = kind_of(&exp);
+                match eval(exp) {
+                    Ok(v) => match kind {
+                        Ok(kind) => (v, kind),
+                        Err(message) if module => {
+                            fail!(AsmErrorKind::InvalidExpression, part, "{}", message)
+                        }
+                        Err(_) => (v, Kind::Absolute),
+                    },
+                    Err(EvalError::Undefined(symbol)) if externals.contains(&symbol) => fail!(
+                        AsmErrorKind::InvalidExpression,
+                        part,
+                        "external symbol {} is only allowed in the address part",
+                        symbol
+                    ),
+                    Err(EvalError::Undefined(symbol)) if is_local_symbol_f(&symbol) => fail!(
+                        AsmErrorKind::FutureReference,
+                        part,
+                        "future reference {} is not allowed in the {}",
+                        symbol,
+                        $what
+                    ),
+                    Err(EvalError::Undefined(symbol)) => {
+                        undefined_uses.push((errors.len(), symbol.clone(), $what));
+                        fail!(
+                            AsmErrorKind::UndefinedSymbol,
+                            part,
+                            "symbol {} is not defined",
+                            symbol
+                        )
+                    }
+                    Err(e) => fail!(AsmErrorKind::InvalidExpression, part, "{}", e),
+                }
+            }};
+        }
 
-        // 6. if addr contains unresolved symbol, insert to unresolve_symbol HashMap
-        if get_unresolved_symbol(&addr).is_some() {
-            // ERROR: addr with unresolved symbol has empty binop
-            assert_eq!(addr.binop, vec![]);
+        // モジュールでは, 番地を索引部やフィールド部には書けない
+        macro_rules! absolute {
+            ($value: expr, $part: expr) => {{
+                let (v, kind) = $value;
+                if module && kind != Kind::Absolute {
+                    fail!(
+                        AsmErrorKind::InvalidExpression,
+                        $part,
+                        "relocatable value {} is not allowed here",
+                        $part
+                    );
+                }
+                v
+            }};
+        }
 
-            let mut s = get_unresolved_symbol(&addr).unwrap();
-            if is_local_symbol_f(s.as_ref()) {
-                s.pop();
+        // 5. EQU, ORIG, CON, END take a W-value
+        if let Attribute::PseudoInstruction(pseudo) = attr {
+            let parts = match split_wvalue(addr) {
+                Ok(parts) => parts,
+                Err((kind, message)) => fail!(kind, addr, "{}", message),
+            };
+            let mut parts_value = vec![];
+            // 番地を格納した部分があれば, その再配置の方法
+            let mut relocation = None;
+            for (exp, field) in parts.into_iter() {
+                let mut value = WordImpl::from_val(0);
+                let (e, kind) = evaluate!(exp, "W-value");
+                if e.abs() >= Byte::word_max() {
+                    fail!(
+                        AsmErrorKind::OutOfRange,
+                        exp,
+                        "{} does not fit in a word",
+                        e
+                    );
+                }
+                if e != 0 {
+                    value = WordImpl::from_val(e);
+                } else if exp.starts_with('-') {
+                    // -0
+                    *value.sign_mut() = Sign::Negative;
+                }
+                let f = match field {
+                    Some(field) => absolute!(evaluate!(field, "W-value"), field),
+                    None => 5,
+                };
+                if !is_valid_field(f) {
+                    fail!(
+                        AsmErrorKind::OutOfRange,
+                        field.unwrap_or(exp),
+                        "invalid field {}",
+                        f
+                    );
+                }
+                if module && kind == Kind::Relocatable {
+                    // 番地は語全体かアドレス部に格納する
+                    relocation = match (&relocation, f) {
+                        (None, 5) | (None, 13) => Some(Relocation::Value),
+                        (None, 2) | (None, 10) => Some(Relocation::Address),
+                        _ => fail!(
+                            AsmErrorKind::InvalidExpression,
+                            exp,
+                            "relocatable value must fill the word or the address part"
+                        ),
+                    };
+                }
+                parts_value.push((value, f as usize));
             }
-            match unresolved_symbol.get_mut(&s) {
-                Some(v) => {
-                    v.push(binary.len());
+            let word = weval(&parts_value);
+            let val = word.val();
+
+            match pseudo {
+                EQU => {
+                    let var = match loc {
+                        Some(var) => var,
+                        None => fail!(AsmErrorKind::Syntax, content, "EQU needs a symbol"),
+                    };
+                    if symbol_table.contains_key(var) && var.parse::<usize>().is_err() {
+                        fail!(
+                            AsmErrorKind::DuplicateSymbol,
+                            var,
+                            "symbol {} is already defined",
+                            var
+                        );
+                    }
+                    let relocatable = match relocation {
+                        None => false,
+                        Some(Relocation::Value) => true,
+                        Some(_) => fail!(
+                            AsmErrorKind::InvalidExpression,
+                            addr,
+                            "EQU of a relocatable address part is not allowed"
+                        ),
+                    };
+                    if externals.contains(var) {
+                        fail!(
+                            AsmErrorKind::DuplicateSymbol,
+                            var,
+                            "symbol {} is already defined",
+                            var
+                        );
+                    }
+                    resolve_fixups(
+                        &mut fixups,
+                        (var, val, relocatable),
+                        &mut binary,
+                        &mut relocations,
+                        &mut errors,
+                    );
+                    if relocatable {
+                        relocatable_symbols.insert(var.to_string());
+                    } else {
+                        relocatable_symbols.remove(var);
+                    }
+                    symbol_table.insert(var.to_string(), val);
+                    line_value.insert(line, val);
+                }
+                ORIG => {
+                    if !(0..=4000).contains(&val) {
+                        fail!(
+                            AsmErrorKind::OutOfRange,
+                            addr,
+                            "location {} is out of memory",
+                            val
+                        );
+                    }
+                    location_counter = val as usize;
+                    line_value.insert(line, val);
                 }
-                None => {
-                    unresolved_symbol.insert(s, vec![binary.len()]);
+                CON => {
+                    if let Some(relocation) = relocation {
+                        relocations.push((binary.len(), Ok(relocation)));
+                    }
+                    if line != 7777 {
+                        line_binary.insert(line, binary.len());
+                        line_address.insert(line, location_counter);
+                    }
+                    binary.push((location_counter, word));
+                    location_counter += 1;
                 }
+                END => {
+                    if !(0..4000).contains(&val) {
+                        fail!(
+                            AsmErrorKind::OutOfRange,
+                            addr,
+                            "entry point {} is out of memory",
+                            val
+                        );
+                    }
+                    entry_point = val as usize;
+                    line_value.insert(line, val);
+                }
+                ALF | Entry | Extern => unreachable!(),
             }
+            continue;
+        }
+
+        // 6. split addr into ADDR, INDEX, MODIFICATION and construct expression
+        // and resolve symbols
+        let (addr, index_part, modi_part) = match split_into_addr_index_modi(addr) {
+            Ok(v) => v,
+            Err(message) => fail!(AsmErrorKind::Syntax, addr, "{}", message),
+        };
+        for part in vec![Some(addr), index_part, modi_part]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(message) = validate_exp(part) {
+                fail!(AsmErrorKind::InvalidExpression, part, "{}", message);
+            }
+        }
+        let index = match index_part {
+            Some(index) => Some(absolute!(evaluate!(index, "index part"), index)),
+            None => None,
+        };
+        let modi = match modi_part {
+            Some(modi) => Some(absolute!(evaluate!(modi, "field part"), modi)),
+            None => None,
+        };
+        let mut addr_exp = replace_asterisk(
+            replace_symbol(construct_exp(addr), &symbol_table, &relocatable_symbols),
+            location_counter,
+        );
+
+        // 7. if addr contains unresolved symbol, evaluate it later
+        let unresolved = symbols_of(&addr_exp);
+        if let Some(s) = unresolved.iter().find(|s| is_local_symbol_b(s)) {
+            fail!(
+                AsmErrorKind::UndefinedSymbol,
+                addr,
+                "symbol {} is not defined",
+                s
+            );
+        }
+        // 未来参照は行のすべての検査が通ってから登録する
+        let mut fixup = None;
+        if !unresolved.is_empty() {
+            let mut site =
+                AsmError::new(AsmErrorKind::UndefinedSymbol, source, addr, String::new());
+            site.line = origin + 1;
+            fixup = Some(Fixup {
+                position: binary.len(),
+                exp: addr_exp,
+                site,
+            });
 
             // assign zero for temp.
-            addr = Exp {
+            addr_exp = Exp {
                 unary: UnaryOp::Plus,
                 atom: AtomicExp::Num(0),
                 binop: vec![],
             };
         };
 
-        // 7. addr is evaluatable
-        let addr = eval(addr).unwrap();
+        // 8. addr is evaluatable
+        let addr_kind = kind_of(&addr_exp);
+        let addr_value = match eval(addr_exp) {
+            Ok(v) => v,
+            Err(EvalError::Undefined(symbol)) => fail!(
+                AsmErrorKind::UndefinedSymbol,
+                addr,
+                "symbol {} is not defined",
+                symbol
+            ),
+            Err(e) => fail!(AsmErrorKind::InvalidExpression, addr, "{}", e),
+        };
 
-        // 8. finalize (encode to binary)
+        // 9. finalize (encode to binary)
         match attr {
             Attribute::Instruction(inst) => {
                 let (_a, i, f, c) = instruction_data(&inst);
-                let mut a = addr;
-                let i = index.unwrap_or(i as i64);
-                let f = modi.unwrap_or(f as i64);
-                let sign = if a < 0 {
-                    a = a.abs();
-                    Sign::Negative
-                } else {
-                    Sign::Positive
-                };
-
-                binary.push((
-                    location_counter,
-                    WordImpl::from_seq(
-                        sign,
-                        &vec![
-                            a as u32 / Byte::max(),
-                            a as u32 % Byte::max(),
-                            i as u32,
-                            f as u32,
-                            c as u32,
-                        ],
-                    ),
-                ));
+                let a = addr_value;
+                let i = index.unwrap_or(i);
+                let f = modi.unwrap_or(f);
+                let b = Byte::max() as i64;
+                if !fits_in_address(a) {
+                    fail!(
+                        AsmErrorKind::OutOfRange,
+                        addr,
+                        "address {} does not fit in two bytes",
+                        a
+                    );
+                }
+                if !(0..b).contains(&i) {
+                    fail!(
+                        AsmErrorKind::OutOfRange,
+                        index_part.unwrap_or(addr),
+                        "index {} does not fit in a byte",
+                        i
+                    );
+                }
+                if !(0..b).contains(&f) {
+                    fail!(
+                        AsmErrorKind::OutOfRange,
+                        modi_part.unwrap_or(addr),
+                        "field {} does not fit in a byte",
+                        f
+                    );
+                }
+                match addr_kind {
+                    Ok(Kind::Relocatable) => {
+                        relocations.push((binary.len(), Ok(Relocation::Address)))
+                    }
+                    Err(message) if module => {
+                        fail!(AsmErrorKind::InvalidExpression, addr, "{}", message)
+                    }
+                    _ => {}
+                }
+                let mut word =
+                    WordImpl::from_seq(Sign::Positive, &vec![0, 0, i as u32, f as u32, c as u32]);
+                set_address(&mut word, a);
+                fixups.extend(fixup);
+                line_binary.insert(line, binary.len());
+                binary.push((location_counter, word));
                 line_address.insert(line, location_counter);
                 location_counter += 1;
             }
-            Attribute::PseudoInstruction(EQU) => {
-                assert!(index.is_none());
-                let var = loc.unwrap();
-                let val = weval(addr, modi.unwrap_or(5));
-                symbol_table.insert(var.to_string(), val);
-            }
-            Attribute::PseudoInstruction(ORIG) => {
-                assert!(index.is_none());
-                location_counter = weval(addr, modi.unwrap_or(5)) as usize;
+            Attribute::PseudoInstruction(_) => unreachable!(),
+        }
+    } // main loop
+
+    size = size.max(location_counter);
+
+    // 最後まで定義されなかった未来参照. モジュールでは EXTERN のシンボルをリンク時に足す.
+    for fixup in fixups.into_iter() {
+        let symbols = symbols_of(&fixup.exp);
+        if module && symbols.iter().all(|s| externals.contains(s)) {
+            let mut site = fixup.site.clone();
+            site.kind = AsmErrorKind::InvalidExpression;
+            match kind_of(&fixup.exp) {
+                Ok(Kind::External(name)) => {
+                    // 外部シンボルを 0 とした値がアドレス部に入る
+                    match eval(define_symbol(fixup.exp, &name, 0, false)) {
+                        Ok(a) if fits_in_address(a) => {
+                            set_address(&mut binary[fixup.position].1, a);
+                            relocations.push((fixup.position, Ok(Relocation::External(name))));
+                        }
+                        Ok(a) => {
+                            site.kind = AsmErrorKind::OutOfRange;
+                            site.message = format!("address {} does not fit in two bytes", a);
+                            errors.push(site);
+                        }
+                        Err(e) => {
+                            site.message = e.to_string();
+                            errors.push(site);
+                        }
+                    }
+                }
+                Ok(_) => unreachable!(),
+                Err(message) => {
+                    site.message = message;
+                    errors.push(site);
+                }
             }
-            Attribute::PseudoInstruction(CON) => {
-                assert!(index.is_none());
-                binary.push((
-                    location_counter,
-                    WordImpl::from_val(weval(addr, modi.unwrap_or(5))),
-                ));
-                line_address.insert(line, location_counter);
-                location_counter += 1;
+            continue;
+        }
+        for symbol in symbols {
+            let mut e = fixup.site.clone();
+            e.message = format!("symbol {} is not defined", symbol);
+            errors.push(e);
+        }
+    }
+    // 後で定義されたシンボルは未来参照として報告する
+    for (position, symbol, what) in undefined_uses.into_iter() {
+        if symbol_table.contains_key(&symbol) {
+            let e = &mut errors[position];
+            e.kind = AsmErrorKind::FutureReference;
+            e.message = format!("future reference {} is not allowed in the {}", symbol, what);
+        }
+    }
+    let mut exports = BTreeMap::new();
+    for (name, mut site) in entries.into_iter() {
+        match symbol_table.get(&name) {
+            Some(value) => {
+                exports.insert(name.clone(), (*value, relocatable_symbols.contains(&name)));
             }
-            Attribute::PseudoInstruction(END) => {
-                assert!(index.is_none());
-                let val = weval(addr, modi.unwrap_or(5));
-                entry_point = val as usize;
+            None => {
+                site.message = format!("symbol {} is not defined", name);
+                errors.push(site);
             }
-            Attribute::PseudoInstruction(ALF) => {
-                unreachable!();
+        }
+    }
+    let mut relocation_table = BTreeMap::new();
+    if module {
+        for (position, relocation) in relocations.into_iter() {
+            match relocation {
+                Ok(relocation) => {
+                    relocation_table.insert(binary[position].0, relocation);
+                }
+                Err(e) => errors.push(e),
             }
         }
-    } // main loop
+    }
+    for e in errors.iter_mut() {
+        locate_error(e, &source, code);
+    }
+    errors.sort_by_key(|e| e.line);
 
-    (entry_point, binary, line_address)
+    if errors.is_empty() {
+        let symbols = symbol_table
+            .into_iter()
+            .filter(|(s, _)| {
+                s.parse::<usize>().is_err()
+                    && !s.starts_with("UNQSYM")
+                    && !generated_labels.contains(s)
+            })
+            .collect();
+        Ok(AssembledProgram {
+            entry_point,
+            binary,
+            source,
+            line_address,
+            line_binary,
+            line_value,
+            generated,
+            symbols,
+            relocatable: module,
+            size,
+            relocations: relocation_table,
+            exports,
+        })
+    } else {
+        Err(errors)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 enum AtomicExp {
     Num(i64),
+    // ラベルや * の値. モジュールでは配置した位置だけずれる
+    Address(i64),
     Symbol(String),
     Asterisk,
 }
@@ -388,16 +1147,11 @@ struct Exp {
 }
 const SYMBOL: [char; 5] = ['+', '-', '*', '/', ':'];
 fn consume_atomic(code: &str) -> (AtomicExp, usize) {
-    if code.chars().nth(0).unwrap() == '*' {
+    if code.starts_with('*') {
         (AtomicExp::Asterisk, 1)
     } else {
-        let mut end = 0;
-        for c in code.chars() {
-            if SYMBOL.contains(&c) {
-                break;
-            }
-            end += 1;
-        }
+        // end はバイトの位置
+        let end = code.find(|c| SYMBOL.contains(&c)).unwrap_or(code.len());
         let atom_string = code.get(..end).unwrap();
         let atom = match atom_string.parse::<i64>() {
             Ok(v) => AtomicExp::Num(v),
@@ -425,9 +1179,9 @@ fn consume_binop(code: &str) -> (BinaryOp, usize) {
 }
 fn construct_exp(code: &str) -> Exp {
     // TODO: temporarily, assumes no whitespaces in code
-    let (unary, mut look_at) = if code.chars().nth(0).unwrap() == '+' {
+    let (unary, mut look_at) = if code.starts_with('+') {
         (UnaryOp::Plus, 1)
-    } else if code.chars().nth(0).unwrap() == '-' {
+    } else if code.starts_with('-') {
         (UnaryOp::Minus, 1)
     } else {
         (UnaryOp::Plus, 0)
@@ -443,7 +1197,7 @@ fn construct_exp(code: &str) -> Exp {
         }
         let (bin, diff) = consume_binop(code.get(look_at..).unwrap());
         look_at += diff;
-        let (atom2, diff) = consume_atomic(code.get(look_at..).unwrap());
+        let (atom2, diff) = consume_atomic(code.get(look_at..).unwrap_or(""));
         look_at += diff;
         binop.push((bin, atom2));
     }
@@ -454,9 +1208,41 @@ fn construct_exp(code: &str) -> Exp {
         binop: binop,
     }
 }
+// 式として読めるか確かめる. シンボルは英数字 10 文字以内で, 英字を含む.
+fn validate_exp(code: &str) -> Result<(), String> {
+    let validate_atom = |atom: &AtomicExp| match atom {
+        AtomicExp::Symbol(s) if s.is_empty() => Err(format!("missing operand in {}", code)),
+        AtomicExp::Symbol(s) => {
+            if let Some(c) = s
+                .chars()
+                .find(|c| !c.is_ascii() && char_to_num(*c).is_none())
+            {
+                return Err(format!("'{}' is not a MIX character", c));
+            }
+            if s.chars().count() > 10
+                || !s.chars().all(|c| c.is_ascii_alphanumeric())
+                || s.chars().all(|c| c.is_ascii_digit())
+            {
+                Err(format!("invalid symbol {}", s))
+            } else {
+                Ok(())
+            }
+        }
+        _ => Ok(()),
+    };
+    if code.is_empty() {
+        return Err("missing expression".to_string());
+    }
+    let exp = construct_exp(code);
+    validate_atom(&exp.atom)?;
+    for (_op, atom) in exp.binop.iter() {
+        validate_atom(atom)?;
+    }
+    Ok(())
+}
 fn replace_asterisk_impl(atom: AtomicExp, location_counter: usize) -> AtomicExp {
     match atom {
-        AtomicExp::Asterisk => AtomicExp::Num(location_counter as i64),
+        AtomicExp::Asterisk => AtomicExp::Address(location_counter as i64),
         _ => atom,
     }
 }
@@ -474,7 +1260,11 @@ fn replace_asterisk(exp: Exp, location_counter: usize) -> Exp {
         binop: binop,
     }
 }
-fn replace_symbol_impl(atom: AtomicExp, symbols: &HashMap<String, i64>) -> AtomicExp {
+fn replace_symbol_impl(
+    atom: AtomicExp,
+    symbols: &HashMap<String, i64>,
+    relocatable: &HashSet<String>,
+) -> AtomicExp {
     match &atom {
         AtomicExp::Symbol(s) => {
             // TODO: move is_local_symbol_b to outside of this func.
@@ -483,17 +1273,21 @@ fn replace_symbol_impl(atom: AtomicExp, symbols: &HashMap<String, i64>) -> Atomi
             } else {
                 s
             };
-            symbols.get(s).map(|v| AtomicExp::Num(*v)).unwrap_or(atom)
+            match symbols.get(s) {
+                Some(v) if relocatable.contains(s) => AtomicExp::Address(*v),
+                Some(v) => AtomicExp::Num(*v),
+                None => atom,
+            }
         }
         _ => atom,
     }
 }
-fn replace_symbol(exp: Exp, symbols: &HashMap<String, i64>) -> Exp {
+fn replace_symbol(exp: Exp, symbols: &HashMap<String, i64>, relocatable: &HashSet<String>) -> Exp {
     let unary = exp.unary;
-    let atom = replace_symbol_impl(exp.atom, &symbols);
+    let atom = replace_symbol_impl(exp.atom, symbols, relocatable);
     let mut binop = vec![];
     for (op, atom) in exp.binop {
-        binop.push((op, replace_symbol_impl(atom, &symbols)));
+        binop.push((op, replace_symbol_impl(atom, symbols, relocatable)));
     }
 
     Exp {
@@ -502,18 +1296,108 @@ fn replace_symbol(exp: Exp, symbols: &HashMap<String, i64>) -> Exp {
         binop: binop,
     }
 }
-fn get_unresolved_symbol(e: &Exp) -> Option<String> {
-    match &e.atom {
-        AtomicExp::Symbol(s) => Some(s.clone()),
-        _ => None,
+fn symbols_of(e: &Exp) -> Vec<String> {
+    let mut symbols = vec![];
+    for atom in Some(&e.atom)
+        .into_iter()
+        .chain(e.binop.iter().map(|(_, a)| a))
+    {
+        if let AtomicExp::Symbol(s) = atom {
+            symbols.push(s.clone());
+        }
+    }
+    symbols
+}
+// 定義された name を式に代入する. 局所シンボル dH の定義は dF に代入する.
+fn define_symbol(exp: Exp, name: &str, value: i64, relocatable: bool) -> Exp {
+    let local = name.parse::<usize>().is_ok();
+    let define = |atom: AtomicExp| match &atom {
+        AtomicExp::Symbol(s)
+            if (!local && s == name) || (local && *s == name.to_string() + "F") =>
+        {
+            if relocatable {
+                AtomicExp::Address(value)
+            } else {
+                AtomicExp::Num(value)
+            }
+        }
+        _ => atom,
+    };
+    Exp {
+        unary: exp.unary,
+        atom: define(exp.atom),
+        binop: exp
+            .binop
+            .into_iter()
+            .map(|(op, a)| (op, define(a)))
+            .collect(),
+    }
+}
+// 値の決まった語の位置と, 再配置の方法 (式が再配置できなければそのエラー)
+type RelocationEntry = (usize, Result<Relocation, AsmError>);
+fn resolve_fixups(
+    fixups: &mut Vec<Fixup>,
+    (name, value, relocatable): (&str, i64, bool),
+    binary: &mut [(usize, WordImpl)],
+    relocations: &mut Vec<RelocationEntry>,
+    errors: &mut Vec<AsmError>,
+) {
+    let mut pending = vec![];
+    for mut fixup in fixups.drain(..) {
+        fixup.exp = define_symbol(fixup.exp, name, value, relocatable);
+        if !symbols_of(&fixup.exp).is_empty() {
+            pending.push(fixup);
+            continue;
+        }
+        let mut site = fixup.site;
+        match kind_of(&fixup.exp) {
+            Ok(Kind::Relocatable) => relocations.push((fixup.position, Ok(Relocation::Address))),
+            Ok(_) => {}
+            Err(message) => {
+                let mut site = site.clone();
+                site.kind = AsmErrorKind::InvalidExpression;
+                site.message = message;
+                relocations.push((fixup.position, Err(site)));
+            }
+        }
+        match eval(fixup.exp) {
+            Ok(a) if fits_in_address(a) => set_address(&mut binary[fixup.position].1, a),
+            Ok(a) => {
+                site.kind = AsmErrorKind::OutOfRange;
+                site.message = format!("address {} does not fit in two bytes", a);
+                errors.push(site);
+            }
+            Err(e) => {
+                site.kind = AsmErrorKind::InvalidExpression;
+                site.message = e.to_string();
+                errors.push(site);
+            }
+        }
     }
+    *fixups = pending;
 }
-fn eval(exp: Exp) -> Result<i64, ()> {
+pub(crate) fn fits_in_address(a: i64) -> bool {
+    let b = Byte::max() as i64;
+    a.abs() < b * b
+}
+// 命令語の (0:2) にアドレスを書き込む
+pub(crate) fn set_address(word: &mut WordImpl, a: i64) {
+    *word.sign_mut() = if a < 0 {
+        Sign::Negative
+    } else {
+        Sign::Positive
+    };
+    let a = a.unsigned_abs() as u32;
+    *word.byte_mut(0).unwrap() = Byte::new(a / Byte::max());
+    *word.byte_mut(1).unwrap() = Byte::new(a % Byte::max());
+}
+fn eval(exp: Exp) -> Result<i64, EvalError> {
     use BinaryOp::*;
 
     let mut evaluated = match exp.atom {
-        AtomicExp::Num(v) => v,
-        _ => return Err(()),
+        AtomicExp::Num(v) | AtomicExp::Address(v) => v,
+        AtomicExp::Symbol(s) => return Err(EvalError::Undefined(s)),
+        AtomicExp::Asterisk => unreachable!(),
     };
     evaluated = match exp.unary {
         UnaryOp::Plus => evaluated,
@@ -522,25 +1406,102 @@ fn eval(exp: Exp) -> Result<i64, ()> {
 
     for (binop, atom) in exp.binop {
         let evaluated_atom = match atom {
-            AtomicExp::Num(v) => v,
-            _ => return Err(()),
+            AtomicExp::Num(v) | AtomicExp::Address(v) => v,
+            AtomicExp::Symbol(s) => return Err(EvalError::Undefined(s)),
+            AtomicExp::Asterisk => unreachable!(),
         };
+        if (binop == DIV || binop == FRAC) && evaluated_atom == 0 {
+            return Err(EvalError::DivisionByZero);
+        }
 
-        match binop {
-            ADD => evaluated += evaluated_atom,
-            SUB => evaluated -= evaluated_atom,
-            MUL => evaluated *= evaluated_atom,
-            DIV => evaluated /= evaluated_atom,
-            FRAC => evaluated = evaluated * Byte::word_max() / evaluated_atom,
-            COLON => evaluated = 8 * evaluated + evaluated_atom,
+        // 語に収まらない途中の値も i64 で計算するが, i64 からあふれたらエラー
+        evaluated = match binop {
+            ADD => evaluated.checked_add(evaluated_atom),
+            SUB => evaluated.checked_sub(evaluated_atom),
+            MUL => evaluated.checked_mul(evaluated_atom),
+            DIV => evaluated.checked_div(evaluated_atom),
+            FRAC => evaluated
+                .checked_mul(Byte::word_max())
+                .and_then(|v| v.checked_div(evaluated_atom)),
+            COLON => evaluated
+                .checked_mul(8)
+                .and_then(|v| v.checked_add(evaluated_atom)),
         }
+        .ok_or(EvalError::Overflow)?;
     }
 
     Ok(evaluated)
 }
-fn weval(a: i64, f: i64) -> i64 {
-    // TODO: evaluate real W-value in the future
-    WordImpl::from_val(a).subword(f as usize).unwrap().val()
+// 式の値の種類. モジュールを配置すると, 番地を表す値はその位置だけずれる.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Absolute,
+    Relocatable,
+    // EXTERN のシンボルに数を足し引きしたもの
+    External(String),
+}
+// 式の種類を左から順に求める. 番地どうしの差は絶対値, 番地と数の和や差は番地になる.
+// 番地の積や和のように, 配置によって意味の変わる式はエラー.
+fn kind_of(exp: &Exp) -> Result<Kind, String> {
+    use BinaryOp::*;
+    use Kind::*;
+    let atom_kind = |atom: &AtomicExp| match atom {
+        AtomicExp::Num(_) => Absolute,
+        AtomicExp::Address(_) | AtomicExp::Asterisk => Relocatable,
+        AtomicExp::Symbol(s) => External(s.clone()),
+    };
+    let error = || Err("expression is not relocatable".to_string());
+    let mut kind = atom_kind(&exp.atom);
+    if exp.unary == UnaryOp::Minus && kind != Absolute {
+        return error();
+    }
+    for (op, atom) in exp.binop.iter() {
+        kind = match (op, kind, atom_kind(atom)) {
+            (_, Absolute, Absolute) => Absolute,
+            (ADD, Absolute, k) | (ADD, k, Absolute) | (SUB, k, Absolute) => k,
+            (SUB, Relocatable, Relocatable) => Absolute,
+            _ => return error(),
+        };
+    }
+    Ok(kind)
+}
+// フィールド指定 (L:R) として正しいか
+fn is_valid_field(f: i64) -> bool {
+    let (l, r) = (f / 8, f % 8);
+    f >= 0 && l <= r && r <= 5
+}
+// W-value の E と F
+type WPart<'a> = (&'a str, Option<&'a str>);
+// W-value を E(F) ごとに分ける
+fn split_wvalue(code: &str) -> Result<Vec<WPart<'_>>, (AsmErrorKind, String)> {
+    let mut parts = vec![];
+    for part in code.split(',') {
+        let (exp, field) = match part.find('(') {
+            Some(mid) => {
+                if !part.ends_with(')') {
+                    return Err((AsmErrorKind::Syntax, "missing )".to_string()));
+                }
+                (&part[..mid], Some(&part[(mid + 1)..(part.len() - 1)]))
+            }
+            None => (part, None),
+        };
+        for exp in Some(exp).into_iter().chain(field) {
+            validate_exp(exp).map_err(|message| (AsmErrorKind::InvalidExpression, message))?;
+        }
+        parts.push((exp, field));
+    }
+    Ok(parts)
+}
+fn validate_wvalue(code: &str) -> Result<(), (AsmErrorKind, String)> {
+    split_wvalue(code).map(|_| ())
+}
+// W-value を評価する. 0 から始めて, 各 E を順に F の部分へ格納する.
+fn weval(parts: &[(WordImpl, usize)]) -> WordImpl {
+    let mut word = WordImpl::from_val(0);
+    for (value, f) in parts.iter() {
+        store(value, &mut word, *f);
+    }
+    word
 }
 
 pub fn release_assemble(code: &str) -> (usize, Vec<(usize, WordImpl)>) {
@@ -598,9 +1559,9 @@ mod tests {
                     CON 2
                     ORIG BUF0-5
                     TITLE ALF FISRT
-                    ALF _FIVE
-                    ALF _HUND
-                    ALF RED_P
+                    ALF  FIVE
+                    ALF  HUND
+                    ALF RED P
                     ALF RIMES
                     ORIG BUF0+24
                     CON BUF1+10
@@ -699,4 +1660,276 @@ mod tests {
             construct_exp(s)
         );
     }
+    fn errors_of(code: &str) -> Vec<(usize, std::ops::Range<usize>, AsmErrorKind)> {
+        assemble(code)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| (e.line, e.columns, e.kind))
+            .collect()
+    }
+    #[test]
+    fn test_errors() {
+        use AsmErrorKind::*;
+        // 重複したシンボル
+        let code = "X NOP\nX NOP\n END 0";
+        assert_eq!(errors_of(code), vec![(2, 1..2, DuplicateSymbol)]);
+        // ALF の長さ
+        let code = " ALF ABC\n END 0";
+        assert_eq!(errors_of(code), vec![(1, 6..9, InvalidAlf)]);
+        // 閉じていないリテラル
+        let code = " LDA =10\n END 0";
+        assert_eq!(errors_of(code), vec![(1, 6..9, InvalidLiteral)]);
+        // 閉じていない括弧
+        let code = " LDA 1000(1:3\n END 0";
+        assert_eq!(errors_of(code), vec![(1, 6..14, Syntax)]);
+        // 知らない命令
+        let code = "START FOO 1\n END 0";
+        assert_eq!(errors_of(code), vec![(1, 7..10, UnknownOperation)]);
+        // 解決できないシンボル
+        let code = " LDA 1000,Y\n LDA 2F+1\n END 0";
+        assert_eq!(
+            errors_of(code),
+            vec![(1, 11..12, UndefinedSymbol), (2, 6..10, UndefinedSymbol)]
+        );
+        // 範囲外の値と 0 除算
+        let code = " LDA 5000\n LDA 1/0\n LDA 1,64\n END 0";
+        assert_eq!(
+            errors_of(code),
+            vec![
+                (1, 6..10, OutOfRange),
+                (2, 6..9, InvalidExpression),
+                (3, 8..10, OutOfRange)
+            ]
+        );
+        // MIX の文字でない文字
+        let code = " LDA é\n LDA A+é,1\nXé NOP\n END 0";
+        assert_eq!(
+            errors_of(code),
+            vec![
+                (1, 6..7, InvalidExpression),
+                (2, 6..9, InvalidExpression),
+                (3, 1..3, Syntax)
+            ]
+        );
+        // i64 からあふれる途中の値
+        let code =
+            " CON 9999999999*9999999999\n CON 9999999999//3\n LDA 1,1(9999999999*9999999999)\n END 0";
+        assert_eq!(
+            errors_of(code),
+            vec![
+                (1, 6..27, InvalidExpression),
+                (2, 6..19, InvalidExpression),
+                (3, 10..31, InvalidExpression)
+            ]
+        );
+    }
+    #[test]
+    fn test_error_recovery() {
+        // エラーの後も番地は進み, すべてのエラーが報告される
+        let code = "* ERRORS
+                    ORIG 100
+                    ALF TOOLONG
+                    X NOP
+                    X NOP
+                    LDA =1
+                    Y FOO
+                    JMP Y
+                    END 0";
+        let errors = assemble(code).unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![3, 5, 6, 7]);
+        assert_eq!(
+            errors[0].to_string(),
+            "3:25: ALF operand TOOLONG should have 5 characters"
+        );
+
+        let program = assemble("ORIG 100\n ALF ABCDE\nX NOP\n END X").unwrap();
+        assert_eq!(program.entry_point, 101);
+        assert_eq!(program.line_address[&2], 101);
+
+        // 番地の部分のエラーでは行ごと飛ばし, 番地は 1 つだけ進む
+        let errors = assemble(" ORIG 3998\n LDA 1,\n NOP").unwrap_err();
+        assert_eq!(
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            vec!["2:8: missing expression"]
+        );
+    }
+    #[test]
+    fn test_wvalue() {
+        let word = |v: Vec<u32>, sign| WordImpl::from_seq(sign, &v);
+        let code = "X EQU 1(4:4),2(5:5)
+                    ORIG 3(4:4),7(5:5)
+                    CON 1(1:1),2(2:2),-3(3:5)
+                    CON -1(0:0),5
+                    CON -0
+                    CON 1(1:1),2(1:1)
+                    CON X(1:2)
+                    LDA =2,1(1:1)=
+                    END 1(4:5)";
+        let program = assemble(code).unwrap();
+        assert_eq!(program.entry_point, 1);
+        // ORIG 3(4:4),7(5:5) は 3 * 64 + 7
+        let base = 3 * 64 + 7;
+        let mut binary = program.binary.clone();
+        binary.sort_by_key(|(address, _)| *address);
+        assert_eq!(
+            binary[..5].to_vec(),
+            vec![
+                (base, word(vec![1, 2, 0, 0, 3], Sign::Positive)),
+                (base + 1, word(vec![0, 0, 0, 0, 5], Sign::Positive)),
+                (base + 2, word(vec![0, 0, 0, 0, 0], Sign::Negative)),
+                (base + 3, word(vec![2, 0, 0, 0, 0], Sign::Positive)),
+                (base + 4, word(vec![1, 2, 0, 0, 0], Sign::Positive)),
+            ]
+        );
+        // リテラル定数も W-value
+        let (_, lda) = &binary[5];
+        let (_, literal) = binary
+            .iter()
+            .find(|(address, _)| *address as i64 == lda.address())
+            .unwrap();
+        assert_eq!(literal, &word(vec![1, 0, 0, 0, 2], Sign::Positive));
+    }
+    #[test]
+    fn test_wvalue_errors() {
+        use AsmErrorKind::*;
+        let code = " CON 1(3:2)\n CON 1(1:6)\n CON 1(1:2\n CON 1,\n ORIG 1(0:0),-1\n END 0";
+        assert_eq!(
+            errors_of(code),
+            vec![
+                (1, 8..11, OutOfRange),
+                (2, 8..11, OutOfRange),
+                (3, 6..11, Syntax),
+                (4, 6..8, InvalidExpression),
+                (5, 7..16, OutOfRange)
+            ]
+        );
+        let code = " CON 1073741824\n CON Y(1:1)\n END 0";
+        assert_eq!(
+            errors_of(code),
+            vec![(1, 6..16, OutOfRange), (2, 6..7, UndefinedSymbol)]
+        );
+        // 最初の部分のエラーで行ごと飛ばすので, 残りの部分は調べず番地も 1 つだけ進む
+        let code = " ORIG 3998\n CON 1(3:2),Y\n NOP\n END 0";
+        assert_eq!(errors_of(code), vec![(2, 8..11, OutOfRange)]);
+    }
+    #[test]
+    fn test_future_reference() {
+        let code = "ORIG 100
+                    START ENT1 END-START
+                    JMP 2F+1
+                    LDA TABLE-1,1
+                    LDA -X
+                    2H NOP
+                    NOP
+                    X EQU 3*2
+                    TABLE CON 5
+                    LDA Y+1
+                    END START";
+        let program = assemble(code).unwrap();
+        let mut binary = program.binary.clone();
+        binary.sort_by_key(|(address, _)| *address);
+        let addresses: Vec<i64> = binary.iter().map(|(_, w)| w.address()).collect();
+        // 未定義の END と Y は END で CON 0 として確保される
+        assert_eq!(addresses[..8], [8, 105, 105, -6, 0, 0, 0, 110]);
+        assert_eq!(binary[8], (108, WordImpl::from_val(0)));
+        assert_eq!(binary[9], (109, WordImpl::from_val(0)));
+    }
+    #[test]
+    fn test_future_reference_errors() {
+        use AsmErrorKind::*;
+        let code = " LDA 0,X\n LDA 0(2F)\n CON Y\n LDA =X=\n JMP 3F\n JMP 1B\nX EQU 1\n2H NOP\nY NOP\n END 0";
+        assert_eq!(
+            errors_of(code),
+            vec![
+                (1, 8..9, FutureReference),
+                (2, 8..10, FutureReference),
+                (3, 6..7, FutureReference),
+                (5, 6..8, UndefinedSymbol),
+                (6, 6..8, UndefinedSymbol),
+            ]
+        );
+        // エラーになった行の未来参照は残さない
+        assert_eq!(
+            errors_of(" LDA X,99\nX EQU 5\n HLT\n END 0"),
+            vec![(1, 8..10, OutOfRange)]
+        );
+        assert_eq!(
+            errors_of(" LDA X(99)\n NOP\nX EQU 9999\n END 0"),
+            vec![(1, 8..10, OutOfRange)]
+        );
+    }
+    #[test]
+    fn test_card_format() {
+        // LOC は 1-10 桁, OP は 12-15 桁, ADDRESS は 17 桁から
+        let code = "\
+* CARD FORMAT
+           ORIG 1000
+START      LDA  TITLE(1:5)      LOAD THE TITLE
+LDA        ENT1 LDA             LABEL NAMED LIKE AN OPCODE
+           JMP  END
+TITLE      ALF   A B            SPACES BY COLUMN
+           ALF  XYZ
+END        HLT  5
+           END  START           REMARKS";
+        let program = assemble_with(code, SourceFormat::Card).unwrap();
+        assert_eq!(program.entry_point, 1000);
+        let mut binary = program.binary.clone();
+        binary.sort_by_key(|(address, _)| *address);
+        let alf = |s: &str| {
+            let v: Vec<u32> = s.chars().map(|c| char_to_num(c).unwrap() as u32).collect();
+            WordImpl::from_seq(Sign::Positive, &v)
+        };
+        assert_eq!(binary[0].1.address(), 1003);
+        assert_eq!(binary[1].1.address(), 1001);
+        assert_eq!(binary[2].1.address(), 1005);
+        assert_eq!(binary[3].1, alf(" A B "));
+        assert_eq!(binary[4].1, alf("XYZ  "));
+        assert_eq!(program.line_address[&5], 1003);
+
+        let code = "           ORIG 1000\n           FOO  1\n           END  0";
+        let errors = assemble_with(code, SourceFormat::Card).unwrap_err();
+        assert_eq!(
+            (errors[0].line, errors[0].columns.clone(), errors[0].kind),
+            (2, 12..15, AsmErrorKind::UnknownOperation)
+        );
+    }
+    #[test]
+    fn test_free_form_remarks() {
+        let code = "ORIG 1000
+                    START LDA X,1 LOAD X ; REMARKS
+                    HLT ; STOP HERE
+                    END EQU 5 ; LABEL NAMED LIKE AN OPCODE
+                    X ALF \"A B C\" ; QUOTED
+                    ALF  FIVE
+                    JMP END
+                    END START";
+        let program = assemble(code).unwrap();
+        let mut binary = program.binary.clone();
+        binary.sort_by_key(|(address, _)| *address);
+        assert_eq!(binary[0].1.address(), 1002);
+        assert_eq!(binary[0].1.index(), 1);
+        assert_eq!(binary[1].1.address(), 0);
+        assert_eq!(binary[2].1.val(), {
+            let v = vec![1, 0, 2, 0, 3];
+            WordImpl::from_seq(Sign::Positive, &v).val()
+        });
+        assert_eq!(binary[4].1.address(), 5);
+        assert_eq!(
+            binary[3].1,
+            WordImpl::from_seq(Sign::Positive, &vec![0, 6, 9, 25, 5])
+        );
+
+        // 命令の名前が 2 つだけ並ぶと, 1 つ目が命令で 2 つ目がアドレス
+        let program = assemble(" ORIG 1000\n JMP END\n HLT\n END 1000").unwrap();
+        assert_eq!(program.symbols.get("JMP"), None);
+        assert_eq!(
+            program.binary[0],
+            (1000, {
+                let mut word = WordImpl::from_seq(Sign::Positive, &vec![0, 0, 0, 0, 39]);
+                set_address(&mut word, 1002);
+                word
+            })
+        );
+    }
 }
@@ -0,0 +1,146 @@
+// カードによるプログラムの読み込み (TAOCP 1.3.1 演習問題 26).
+//
+// GO ボタンで 1 枚目のローダのカードが 0..=15 番地に読み込まれ, 0 番地から実行される.
+// ローダは 2 枚目を 16..=31 番地に読み, 続くデータカードを 1 枚ずつ BUFF に読んで語を置く.
+//
+// データカード: 1-5 桁は使わない, 6 桁目は語数 (1..=7), 7-10 桁目は先頭の番地,
+// 11-20 桁目から 10 桁ずつ語の値. 負の語は最後の桁に 11 パンチを重ねる (0 は Δ, 1..9 は J..R).
+// 語数が 0 の転送カードを読むと, その番地へ飛ぶ.
+use super::assemble;
+use crate::common::CHAR_TABLE;
+use crate::mix_word::{Sign, WordImpl};
+use std::collections::BTreeMap;
+
+// カードの文字だけで書けるよう, どの語も符号が正で各バイトが 56 未満になっている
+const LOADER: &str = "BUFF EQU 32
+ ORIG 0
+LOC IN 16(16)
+READ IN BUFF(16)
+ ENT1 0
+ JBUS *(16)
+ LDA BUFF+1
+ SLA 1
+ SRAX 6
+ NUM
+ STA LOC
+ LDA BUFF+1(1:1)
+ SUB C30
+LOOP LD3 LOC
+ JAZ 0,3
+ STA COUNT
+ LDA LOC
+ INCA 1
+ STA LOC
+ LDA BUFF+3,1(5:5)
+ SUB C25
+ STA 0,3(0:0)
+ LDA BUFF+2,1
+ LDX BUFF+3,1
+ NUM
+ STA 0,3(1:5)
+ INC1 2
+ LDA COUNT
+ DECA 1
+ JAP LOOP
+ JMP READ
+C30 CON 30
+C25 CON 25
+COUNT CON 0
+ END LOC";
+
+// ローダと入力バッファが使う 0..=47 番地. プログラムはこれより後に置く.
+pub const LOADER_SIZE: usize = 48;
+const WORDS_PER_CARD: usize = 7;
+
+fn word_to_chars(word: &WordImpl) -> String {
+    (0..5)
+        .map(|x| CHAR_TABLE[word.byte(x).unwrap().val() as usize])
+        .collect()
+}
+
+// 10 桁の 10 進数. 負なら最後の桁に 11 パンチを重ねる.
+fn word_to_digits(word: &WordImpl) -> String {
+    let mut digits: Vec<char> = format!("{:010}", word.val().abs()).chars().collect();
+    if *word.sign() == Sign::Negative {
+        let d = digits[9].to_digit(10).unwrap() as usize;
+        digits[9] = CHAR_TABLE[10 + d];
+    }
+    digits.into_iter().collect()
+}
+
+// GO ボタンで読み込むカードの束. ローダの 2 枚, データカード, 転送カードの順.
+// 同じ番地は後の語で上書きする. ローダの領域に語があればエラー.
+pub fn loading_deck(
+    entry_point: usize,
+    binary: &[(usize, WordImpl)],
+) -> Result<Vec<String>, String> {
+    if let Some((address, _)) = binary.iter().find(|(address, _)| *address < LOADER_SIZE) {
+        return Err(format!(
+            "address {} overlaps the loader (0..={})",
+            address,
+            LOADER_SIZE - 1
+        ));
+    }
+    if entry_point < LOADER_SIZE {
+        return Err(format!("entry point {} is inside the loader", entry_point));
+    }
+
+    let loader = assemble(LOADER).unwrap();
+    let mut memory = vec![WordImpl::from_val(0); 32];
+    for (address, word) in loader.binary.iter() {
+        memory[*address] = word.clone();
+    }
+    let mut deck: Vec<String> = memory
+        .chunks(16)
+        .map(|card| card.iter().map(word_to_chars).collect())
+        .collect();
+
+    let memory: BTreeMap<usize, &WordImpl> = binary
+        .iter()
+        .map(|(address, word)| (*address, word))
+        .collect();
+
+    // 連続する番地を 7 語ずつカードにまとめる
+    let mut cards: Vec<(usize, Vec<&WordImpl>)> = vec![];
+    for (address, word) in memory {
+        match cards.last_mut() {
+            Some((origin, v)) if *origin + v.len() == address && v.len() < WORDS_PER_CARD => {
+                v.push(word)
+            }
+            _ => cards.push((address, vec![word])),
+        }
+    }
+    for (origin, v) in cards {
+        let mut card = format!("     {}{:04}", v.len(), origin);
+        for word in v {
+            card += &word_to_digits(word);
+        }
+        deck.push(card);
+    }
+    deck.push(format!("TRANS0{:04}", entry_point));
+    Ok(deck)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loader_is_punchable() {
+        let loader = assemble(LOADER).unwrap();
+        assert!(loader.binary.iter().all(|(address, _)| *address < 32));
+        for (_, word) in loader.binary.iter() {
+            assert_eq!(*word.sign(), Sign::Positive);
+            assert!((0..5).all(|x| (word.byte(x).unwrap().val() as usize) < CHAR_TABLE.len()));
+        }
+    }
+
+    #[test]
+    fn test_word_to_digits() {
+        assert_eq!(word_to_digits(&WordImpl::from_val(1234)), "0000001234");
+        assert_eq!(word_to_digits(&WordImpl::from_val(-1234)), "000000123M");
+        let mut minus_zero = WordImpl::from_val(0);
+        *minus_zero.sign_mut() = Sign::Negative;
+        assert_eq!(word_to_digits(&minus_zero), "000000000Δ");
+    }
+}
@@ -0,0 +1,86 @@
+use super::AssembledProgram;
+use crate::mix_word::{Sign, WordImpl};
+use std::collections::HashMap;
+
+// 語を ± AA I F C の形で書く
+fn format_word(word: &WordImpl) -> String {
+    let sign = match word.sign() {
+        Sign::Positive => '+',
+        Sign::Negative => '-',
+    };
+    format!(
+        "{} {:04} {:02} {:02} {:02}",
+        sign,
+        word.address().abs(),
+        word.index(),
+        word.modification(),
+        word.operation()
+    )
+}
+
+// アセンブルリスト. 各行の番地と語, EQU などの値, END で確保した語, シンボルテーブルを並べる.
+// マクロの展開と INCLUDE した行は呼び出しの行の後に並べる.
+pub fn listing(code: &str, program: &AssembledProgram) -> String {
+    // END で確保した語. 行の語は ORIG で同じ番地に戻ることがあるので line_binary で引く
+    let memory: HashMap<usize, &WordImpl> = program
+        .binary
+        .iter()
+        .map(|(address, word)| (*address, word))
+        .collect();
+
+    // 元の行と, その行から展開した行 (LINE を + とする) の program.source での位置
+    let mut original: HashMap<usize, usize> = HashMap::new();
+    let mut expanded: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (index, s) in program.source.iter().enumerate() {
+        if s.expanded {
+            expanded.entry(s.line).or_default().push(index);
+        } else {
+            original.insert(s.line, index);
+        }
+    }
+    let row = |index: Option<&usize>, line: String, source: &str| {
+        let index = index.copied().unwrap_or(usize::MAX);
+        let (loc, word) = match (
+            program.line_binary.get(&index),
+            program.line_value.get(&index),
+        ) {
+            (Some(k), _) => {
+                let (address, word) = &program.binary[*k];
+                (format!("{:04}", address), format_word(word))
+            }
+            (None, Some(value)) => (String::new(), format!("= {}", value)),
+            (None, None) => (String::new(), String::new()),
+        };
+        format!(
+            "{:<4} {:<15} {:>5}  {}\n",
+            loc,
+            word,
+            line,
+            source.trim_end()
+        )
+    };
+
+    let mut text = String::new();
+    text += &format!("{:<4} {:<15} {:>5}  {}\n", "LOC", "WORD", "LINE", "SOURCE");
+    for (line, source) in code.lines().enumerate() {
+        text += &row(original.get(&line), (line + 1).to_string(), source);
+        for index in expanded.get(&line).into_iter().flatten() {
+            text += &row(Some(index), "+".to_string(), &program.source[*index].text);
+        }
+    }
+    for (address, source) in program.generated.iter() {
+        text += &format!(
+            "{:04} {:<15} {:>5}  {}\n",
+            address,
+            format_word(memory[address]),
+            "",
+            source
+        );
+    }
+
+    text += "\nSYMBOL     VALUE\n";
+    for (symbol, value) in program.symbols.iter() {
+        text += &format!("{:<10} {:>5}\n", symbol, value);
+    }
+    text
+}
@@ -0,0 +1,665 @@
+// アセンブルの前に, マクロ, INCLUDE, 条件アセンブリを展開する.
+//
+// NAME MACRO P1,P2     マクロの定義. ENDM までが本体
+//      ENDM
+// LOOP NAME X,Y        呼び出し. 本体の P1, P2 を X, Y に置き換えた行になる. LOOP は最初の語の番地
+//      INCLUDE FILE    ファイルの行をここに読み込む. FILE は読み込む側のファイルのディレクトリから探す
+//      IF EXP          EXP が 0 でなければ ELSE まで, 0 なら ELSE から ENDIF までをアセンブルする
+//      ELSE
+//      ENDIF
+//
+// 仮引数は, 本体の中でシンボルとして書かれたところを ALF のオペランドを除いてすべて置き換える.
+// 本体で定義したラベルは呼び出しごとに, ソースの名前と重ならない別のシンボル (1MLOOP など)
+// にする. 本体の dB, dF は本体の中の dH を指し, 本体に dH がなければ呼び出した側の dH を指す.
+// IF の式には数と, それまでに数だけで定義した EQU のシンボルを書ける.
+use super::{
+    columns, construct_exp, eval, get_attribute, is_local_symbol_b, is_local_symbol_f,
+    is_local_symbol_h, offset_of, replace_symbol, strip_remarks, symbols_of, validate_exp,
+    AsmError, AsmErrorKind, AtomicExp, EvalError, SourceFormat,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+const DIRECTIVES: [&str; 6] = ["MACRO", "ENDM", "INCLUDE", "IF", "ELSE", "ENDIF"];
+// マクロの呼び出しと INCLUDE の入れ子の上限
+const MAX_DEPTH: usize = 32;
+
+// 前処理した行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    // 元のソースの行番号 (0 始まり). 展開した行は呼び出しや INCLUDE の行
+    pub line: usize,
+    // マクロの展開や INCLUDE で生成した行か
+    pub expanded: bool,
+    pub text: String,
+}
+
+// 英数字の並び
+fn tokens(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|token| !token.is_empty())
+}
+
+// 英数字の並びを map に従って置き換える
+fn substitute(text: &str, map: &HashMap<String, String>) -> String {
+    let mut result = String::new();
+    let mut token = String::new();
+    for c in text.chars().map(Some).chain(Some(None)) {
+        match c {
+            Some(c) if c.is_ascii_alphanumeric() => token.push(c),
+            _ => {
+                result += map.get(&token).unwrap_or(&token);
+                token.clear();
+                result.extend(c);
+            }
+        }
+    }
+    result
+}
+
+// マクロの実引数. 括弧の外の , で区切る
+fn split_arguments(operand: &str) -> Vec<&str> {
+    if operand.is_empty() {
+        return vec![];
+    }
+    let mut arguments = vec![];
+    let (mut depth, mut begin) = (0, 0);
+    for (i, c) in operand.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                arguments.push(&operand[begin..i]);
+                begin = i + 1;
+            }
+            _ => {}
+        }
+    }
+    arguments.push(&operand[begin..]);
+    arguments
+}
+
+// シンボルとして正しい名前か
+fn is_symbol(name: &str) -> bool {
+    validate_exp(name).is_ok() && symbols_of(&construct_exp(name)) == [name]
+}
+
+struct Preprocessor<'a> {
+    format: SourceFormat,
+    source_lines: Vec<&'a str>,
+    // 名前から仮引数と本体
+    macros: HashMap<String, (Vec<String>, Vec<String>)>,
+    // 定義中のマクロの名前, 仮引数, 本体, MACRO の行
+    defining: Option<(String, Vec<String>, Vec<String>, usize)>,
+    // IF の入れ子. 各段の条件, ELSE の後か, IF の行
+    conditions: Vec<(bool, bool, usize)>,
+    // 数だけで定義した EQU のシンボルの値
+    values: HashMap<String, i64>,
+    // 展開した回数. 本体のラベルの名前に使う
+    expansions: usize,
+    // ソースに書かれた英数字の並び. 本体のラベルの名前はこれと重ならないようにする
+    taken: HashSet<String>,
+    // 本体のラベルに付けた名前
+    generated: HashSet<String>,
+    lines: Vec<SourceLine>,
+    errors: Vec<AsmError>,
+}
+
+impl<'a> Preprocessor<'a> {
+    // 展開した行のエラーは, 元のソースの行全体で報告する
+    fn error(
+        &mut self,
+        kind: AsmErrorKind,
+        (text, part): (&str, &str),
+        (line, depth): (usize, usize),
+        message: String,
+    ) {
+        let mut e = if depth == 0 {
+            AsmError::new(kind, text, part, message)
+        } else {
+            let source = self.source_lines[line];
+            AsmError::new(
+                kind,
+                source,
+                source,
+                format!("{} in `{}`", message, text.trim()),
+            )
+        };
+        e.line = line + 1;
+        self.errors.push(e);
+    }
+
+    fn is_op(&self, s: &str, params: &[String]) -> bool {
+        get_attribute(s).is_some()
+            || DIRECTIVES.contains(&s)
+            || self.macros.contains_key(s)
+            || params.iter().any(|p| p == s)
+    }
+
+    // 行を LOC, OP, ADDRESS に分ける. params は本体の OP に書ける仮引数
+    fn fields<'b>(
+        &self,
+        text: &'b str,
+        params: &[String],
+    ) -> (Option<&'b str>, Option<&'b str>, &'b str) {
+        match self.format {
+            SourceFormat::FreeForm => {
+                let mut iter = strip_remarks(text, self.format).split_whitespace();
+                let (t0, t1, t2) = (iter.next(), iter.next(), iter.next());
+                let is_op = |t: Option<&str>| t.is_some_and(|t| self.is_op(t, params));
+                // "END EQU 5" や "LDA MACRO" のように 2 つ目も命令で, アドレスが続くか
+                // マクロの定義なら 1 つ目は LOC. ALF の後は空白を含む文字の並び
+                if t0 == Some("ALF")
+                    || is_op(t0) && !(is_op(t1) && (t2.is_some() || t1 == Some("MACRO")))
+                {
+                    (None, t0, t1.unwrap_or(""))
+                } else if is_op(t1) {
+                    (t0, t1, t2.unwrap_or(""))
+                } else {
+                    (t0.filter(|_| t1.is_some()), None, "")
+                }
+            }
+            SourceFormat::Card => {
+                let text = strip_remarks(text, self.format);
+                let loc = Some(columns(text, 0, 10).trim()).filter(|s| !s.is_empty());
+                let rest = columns(text, 11, usize::MAX);
+                // OP の 4 桁に収まらない前処理の命令とマクロは, 空白までを OP とする
+                let (op, operand) = match rest.split_whitespace().next() {
+                    Some(word) if word.chars().count() > 4 && self.is_op(word, params) => {
+                        let after = &rest[(offset_of(rest, word) + word.len())..];
+                        (word, after.split_whitespace().next().unwrap_or(""))
+                    }
+                    _ => (
+                        columns(text, 11, 15).trim(),
+                        columns(text, 15, usize::MAX)
+                            .split_whitespace()
+                            .next()
+                            .unwrap_or(""),
+                    ),
+                };
+                (loc, Some(op).filter(|s| !s.is_empty()), operand)
+            }
+        }
+    }
+
+    fn is_comment(&self, text: &str) -> bool {
+        text.starts_with('*')
+            || text.trim_start().starts_with('*') && self.format == SourceFormat::FreeForm
+    }
+
+    fn active(&self) -> bool {
+        self.conditions.iter().all(|(c, e, _)| c != e)
+    }
+
+    fn emit(&mut self, text: String, (line, depth): (usize, usize)) {
+        self.lines.push(SourceLine {
+            line,
+            expanded: depth > 0,
+            text,
+        });
+    }
+
+    // 前処理の命令やマクロの呼び出しに付いたラベルは, 次の語の番地とする
+    fn emit_label(&mut self, loc: Option<&str>, (line, depth): (usize, usize)) {
+        if let (Some(loc), true) = (loc, self.active()) {
+            let label = match self.format {
+                SourceFormat::FreeForm => format!("{} ORIG *", loc),
+                SourceFormat::Card => format!("{:<10} ORIG *", loc),
+            };
+            self.emit(label, (line, depth + 1));
+        }
+    }
+
+    // 数と数だけで定義した EQU のシンボルからなる式の値
+    fn evaluate(&self, operand: &str) -> Result<i64, (AsmErrorKind, String)> {
+        validate_exp(operand).map_err(|message| (AsmErrorKind::InvalidExpression, message))?;
+        let exp = replace_symbol(construct_exp(operand), &self.values, &HashSet::new());
+        let asterisk = Some(&exp.atom)
+            .into_iter()
+            .chain(exp.binop.iter().map(|(_, atom)| atom))
+            .any(|atom| *atom == AtomicExp::Asterisk);
+        if asterisk {
+            return Err((
+                AsmErrorKind::InvalidExpression,
+                "location counter is not known before assembly".to_string(),
+            ));
+        }
+        match eval(exp) {
+            Ok(v) => Ok(v),
+            Err(EvalError::Undefined(symbol)) => Err((
+                AsmErrorKind::UndefinedSymbol,
+                format!("symbol {} has no numeric EQU value here", symbol),
+            )),
+            Err(e) => Err((AsmErrorKind::InvalidExpression, e.to_string())),
+        }
+    }
+
+    fn process(&mut self, text: &str, site: (usize, usize), dir: &Path) {
+        let comment = self.is_comment(text);
+        let (loc, op, operand) = if comment {
+            (None, None, "")
+        } else {
+            self.fields(text, &[])
+        };
+
+        // 1. マクロの本体は ENDM まで貯める
+        if let Some((_, _, body, _)) = &mut self.defining {
+            match op {
+                Some("ENDM") => {
+                    let (name, params, body, _) = self.defining.take().unwrap();
+                    if !name.is_empty() {
+                        self.macros.insert(name, (params, body));
+                    }
+                }
+                Some("MACRO") => self.error(
+                    AsmErrorKind::Syntax,
+                    (text, text),
+                    site,
+                    "MACRO is not allowed inside a macro definition".to_string(),
+                ),
+                _ => body.push(text.to_string()),
+            }
+            return;
+        }
+
+        // 2. 条件アセンブリ. 読み飛ばす部分でも IF の入れ子は数える
+        match op {
+            Some("IF") => {
+                self.emit_label(loc, site);
+                let condition = if self.active() {
+                    match self.evaluate(operand) {
+                        Ok(v) => v != 0,
+                        Err((kind, message)) => {
+                            self.error(kind, (text, operand), site, message);
+                            false
+                        }
+                    }
+                } else {
+                    false
+                };
+                self.conditions.push((condition, false, site.0));
+                return;
+            }
+            Some("ELSE") => {
+                match self.conditions.last_mut() {
+                    Some((_, in_else, _)) if !*in_else => *in_else = true,
+                    Some(_) => self.error(
+                        AsmErrorKind::Syntax,
+                        (text, text),
+                        site,
+                        "ELSE appears twice".to_string(),
+                    ),
+                    None => self.error(
+                        AsmErrorKind::Syntax,
+                        (text, text),
+                        site,
+                        "ELSE without IF".to_string(),
+                    ),
+                }
+                self.emit_label(loc, site);
+                return;
+            }
+            Some("ENDIF") => {
+                if self.conditions.pop().is_none() {
+                    self.error(
+                        AsmErrorKind::Syntax,
+                        (text, text),
+                        site,
+                        "ENDIF without IF".to_string(),
+                    );
+                }
+                self.emit_label(loc, site);
+                return;
+            }
+            _ => {}
+        }
+        if !self.active() {
+            return;
+        }
+
+        match op {
+            // 3. マクロの定義
+            Some("MACRO") => {
+                // 定義が正しくなくても ENDM までは本体として読み飛ばす
+                self.defining = Some((String::new(), vec![], vec![], site.0));
+                let name = match loc {
+                    Some(name) if is_symbol(name) && !self.is_op(name, &[]) => name,
+                    Some(name) => {
+                        return self.error(
+                            AsmErrorKind::Syntax,
+                            (text, name),
+                            site,
+                            format!("invalid macro name {}", name),
+                        )
+                    }
+                    None => {
+                        return self.error(
+                            AsmErrorKind::Syntax,
+                            (text, text),
+                            site,
+                            "MACRO needs a name".to_string(),
+                        )
+                    }
+                };
+                let mut params: Vec<String> = vec![];
+                for param in split_arguments(operand) {
+                    if !is_symbol(param)
+                        || is_local_symbol_h(param)
+                        || is_local_symbol_f(param)
+                        || is_local_symbol_b(param)
+                    {
+                        return self.error(
+                            AsmErrorKind::Syntax,
+                            (text, param),
+                            site,
+                            format!("invalid parameter {}", param),
+                        );
+                    }
+                    if params.iter().any(|p| p == param) {
+                        return self.error(
+                            AsmErrorKind::DuplicateSymbol,
+                            (text, param),
+                            site,
+                            format!("parameter {} appears twice", param),
+                        );
+                    }
+                    params.push(param.to_string());
+                }
+                self.defining = Some((name.to_string(), params, vec![], site.0));
+            }
+            Some("ENDM") => self.error(
+                AsmErrorKind::Syntax,
+                (text, text),
+                site,
+                "ENDM without MACRO".to_string(),
+            ),
+            // 4. ファイルの読み込み
+            Some("INCLUDE") => {
+                if operand.is_empty() {
+                    return self.error(
+                        AsmErrorKind::Syntax,
+                        (text, text),
+                        site,
+                        "INCLUDE needs a file name".to_string(),
+                    );
+                }
+                if site.1 >= MAX_DEPTH {
+                    return self.error(
+                        AsmErrorKind::Include,
+                        (text, operand),
+                        site,
+                        format!("INCLUDE {} is nested too deeply", operand),
+                    );
+                }
+                self.emit_label(loc, site);
+                let path = dir.join(operand);
+                match fs::read_to_string(&path) {
+                    Ok(code) => {
+                        self.taken.extend(tokens(&code).map(String::from));
+                        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                        for text in code.lines() {
+                            self.process(text, (site.0, site.1 + 1), dir);
+                        }
+                    }
+                    Err(e) => self.error(
+                        AsmErrorKind::Include,
+                        (text, operand),
+                        site,
+                        format!("cannot include {}: {}", operand, e),
+                    ),
+                }
+            }
+            // 5. マクロの呼び出し
+            Some(name) if self.macros.contains_key(name) => {
+                if site.1 >= MAX_DEPTH {
+                    return self.error(
+                        AsmErrorKind::Syntax,
+                        (text, name),
+                        site,
+                        format!("macro {} is nested too deeply", name),
+                    );
+                }
+                let (params, _) = &self.macros[name];
+                let arguments = split_arguments(operand);
+                if arguments.len() > params.len() {
+                    let message = format!(
+                        "macro {} takes {} arguments but {} were given",
+                        name,
+                        params.len(),
+                        arguments.len()
+                    );
+                    return self.error(AsmErrorKind::Syntax, (text, operand), site, message);
+                }
+                self.emit_label(loc, site);
+                for text in self.expand(name, &arguments) {
+                    match text {
+                        Ok(text) => self.process(&text, (site.0, site.1 + 1), dir),
+                        Err((part, message)) => self.error(
+                            AsmErrorKind::Syntax,
+                            (&part, &part),
+                            (site.0, site.1 + 1),
+                            message,
+                        ),
+                    }
+                }
+            }
+            _ => {
+                // IF で使えるように数だけで定義した EQU の値を覚えておく
+                if let (Some(loc), Some("EQU")) = (loc, op) {
+                    match self.evaluate(operand) {
+                        Ok(v) => self.values.insert(loc.to_string(), v),
+                        Err(_) => self.values.remove(loc),
+                    };
+                }
+                self.emit(text.to_string(), site);
+            }
+        }
+    }
+
+    // 本体の仮引数とラベルを置き換えた行. 置き換えられなかった行はその行とエラー
+    fn expand(&mut self, name: &str, arguments: &[&str]) -> Vec<Result<String, (String, String)>> {
+        let (params, body) = self.macros[name].clone();
+        self.expansions += 1;
+        let n = self.expansions;
+
+        let mut renames: HashMap<String, String> = HashMap::new();
+        for (k, param) in params.iter().enumerate() {
+            renames.insert(param.clone(), arguments.get(k).unwrap_or(&"").to_string());
+        }
+        let labels: Vec<Option<&str>> = body
+            .iter()
+            .map(|text| {
+                if self.is_comment(text) {
+                    None
+                } else {
+                    self.fields(text, &params).0
+                }
+            })
+            .collect();
+        for (k, label) in labels.iter().enumerate() {
+            if let Some(label) = label {
+                if params.contains(&label.to_string()) || is_local_symbol_h(label) {
+                    continue;
+                }
+                if is_symbol(label) {
+                    let unique = self.unique_label(n, k, Some(label));
+                    renames.insert(label.to_string(), unique);
+                }
+            }
+        }
+        let heres: HashMap<usize, String> = (0..body.len())
+            .filter(|k| labels[*k].is_some_and(is_local_symbol_h))
+            .map(|k| (k, self.unique_label(n, k, None)))
+            .collect();
+        let mut lines = vec![];
+        for (k, text) in body.iter().enumerate() {
+            if self.is_comment(text) {
+                lines.push(Ok(text.clone()));
+                continue;
+            }
+            let mut map = renames.clone();
+            for d in labels
+                .iter()
+                .flatten()
+                .filter(|label| is_local_symbol_h(label))
+                .map(|label| &label[..(label.len() - 1)])
+            {
+                let here = format!("{}H", d);
+                let is_here = |j: &usize| labels[*j] == Some(here.as_str());
+                if is_here(&k) {
+                    map.insert(here.clone(), heres[&k].clone());
+                }
+                if let Some(j) = (0..k).rev().find(is_here) {
+                    map.insert(format!("{}B", d), heres[&j].clone());
+                }
+                if let Some(j) = ((k + 1)..body.len()).find(is_here) {
+                    map.insert(format!("{}F", d), heres[&j].clone());
+                }
+            }
+            lines.push(self.substitute_line(text, &map, &params));
+        }
+        lines
+    }
+
+    // 呼び出し n で本体の k 行目のラベル (dH は None) に付ける名前.
+    // 1MLOOP のように呼び出しの番号を前に付け, 長すぎるかソースの名前と重なれば行の番号を使う
+    fn unique_label(&mut self, n: usize, k: usize, label: Option<&str>) -> String {
+        let taken = &self.taken;
+        let unique = label
+            .map(|label| format!("{}M{}", n, label))
+            .filter(|s| s.len() <= 10)
+            .into_iter()
+            .chain(Some(format!("{}M{}", n, k)))
+            .chain((0..).map(|j| format!("{}M{}X{}", n, k, j)))
+            .find(|s| !taken.contains(s))
+            .unwrap();
+        self.taken.insert(unique.clone());
+        self.generated.insert(unique.clone());
+        unique
+    }
+
+    fn substitute_line(
+        &self,
+        text: &str,
+        map: &HashMap<String, String>,
+        params: &[String],
+    ) -> Result<String, (String, String)> {
+        match self.format {
+            SourceFormat::FreeForm => {
+                // ALF のオペランドは文字の並びなので置き換えない
+                let (code, rest) = match self.fields(text, params) {
+                    (_, Some("ALF"), operand) if !operand.is_empty() => {
+                        text.split_at(offset_of(text, operand))
+                    }
+                    _ => text.split_at(text.find(';').unwrap_or(text.len())),
+                };
+                Ok(substitute(code, map) + rest)
+            }
+            // カード形式は桁を保つように並べ直す
+            SourceFormat::Card => {
+                let (loc, op, operand) = self.fields(text, params);
+                let (loc, op) = (loc.unwrap_or(""), op.unwrap_or(""));
+                let new_loc = substitute(loc, map);
+                if new_loc.chars().count() > 10 {
+                    return Err((
+                        text.to_string(),
+                        format!("label {} does not fit in columns 1-10", new_loc),
+                    ));
+                }
+                let (new_op, new_operand) = if op == "ALF" {
+                    (op.to_string(), operand.to_string())
+                } else {
+                    (substitute(op, map), substitute(operand, map))
+                };
+                if new_op == op && new_operand == operand {
+                    return Ok(format!("{:<10}{}", new_loc, columns(text, 10, usize::MAX)));
+                }
+                let tail = if operand.is_empty() {
+                    ""
+                } else {
+                    &text[(offset_of(text, operand) + operand.len())..]
+                };
+                let space = if new_op.chars().count() > 4 { " " } else { "" };
+                Ok(format!(
+                    "{:<10} {:<4}{}{}{}",
+                    new_loc, new_op, space, new_operand, tail
+                ))
+            }
+        }
+    }
+}
+
+// マクロ, INCLUDE, 条件アセンブリを展開する. INCLUDE のファイルは dir から探す.
+pub fn preprocess(
+    code: &str,
+    format: SourceFormat,
+    dir: &Path,
+) -> Result<Vec<SourceLine>, Vec<AsmError>> {
+    preprocess_impl(code, format, dir).map(|(lines, _generated)| lines)
+}
+
+// preprocess と, マクロの本体のラベルに付けた名前
+pub(super) fn preprocess_impl(
+    code: &str,
+    format: SourceFormat,
+    dir: &Path,
+) -> Result<(Vec<SourceLine>, HashSet<String>), Vec<AsmError>> {
+    let mut p = Preprocessor {
+        format,
+        source_lines: code.lines().collect(),
+        macros: HashMap::new(),
+        defining: None,
+        conditions: vec![],
+        values: HashMap::new(),
+        expansions: 0,
+        taken: tokens(code).map(String::from).collect(),
+        generated: HashSet::new(),
+        lines: vec![],
+        errors: vec![],
+    };
+    for (line, text) in code.lines().enumerate() {
+        p.process(text, (line, 0), dir);
+    }
+    if let Some((name, _, _, line)) = p.defining.take() {
+        let text = p.source_lines[line];
+        p.error(
+            AsmErrorKind::Syntax,
+            (text, text),
+            (line, 0),
+            match name.as_str() {
+                "" => "MACRO has no ENDM".to_string(),
+                name => format!("macro {} has no ENDM", name),
+            },
+        );
+    }
+    while let Some((_, _, line)) = p.conditions.pop() {
+        let text = p.source_lines[line];
+        p.error(
+            AsmErrorKind::Syntax,
+            (text, text),
+            (line, 0),
+            "IF without ENDIF".to_string(),
+        );
+    }
+    if p.errors.is_empty() {
+        Ok((p.lines, p.generated))
+    } else {
+        p.errors.sort_by_key(|e| e.line);
+        Err(p.errors)
+    }
+}
+
+// 展開した行 (1 始まり) のエラーを元のソースの行に付け替える
+pub(super) fn locate_error(e: &mut AsmError, source: &[SourceLine], code: &str) {
+    let s = match e.line.checked_sub(1).and_then(|i| source.get(i)) {
+        Some(s) => s,
+        None => return,
+    };
+    e.line = s.line + 1;
+    if s.expanded {
+        let original = code.lines().nth(s.line).unwrap_or("");
+        e.columns = 1..(original.chars().count().max(1) + 1);
+        e.message = format!("{} in `{}`", e.message, s.text.trim());
+    }
+}
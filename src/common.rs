@@ -14,6 +14,9 @@ pub enum PseudoInstruction {
     CON,
     ALF,
     END,
+    // 再配置できるモジュールで公開するシンボルと, 他のモジュールから使うシンボル
+    Entry,
+    Extern,
 }
 
 impl FromStr for PseudoInstruction {
@@ -26,6 +29,8 @@ impl FromStr for PseudoInstruction {
             "CON" => Ok(CON),
             "ALF" => Ok(ALF),
             "END" => Ok(END),
+            "ENTRY" => Ok(Entry),
+            "EXTERN" => Ok(Extern),
             _ => Err(format!("{} is not PseudoInstruction", s)),
         }
     }
@@ -39,6 +44,8 @@ impl ToString for PseudoInstruction {
             CON => "CON",
             ALF => "ALF",
             END => "END",
+            Entry => "ENTRY",
+            Extern => "EXTERN",
         }
         .to_string()
     }
@@ -48,12 +55,19 @@ impl ToString for PseudoInstruction {
 pub enum Instruction {
     NOP,
     ADD,
+    FADD,
     SUB,
+    FSUB,
     MUL,
+    FMUL,
     DIV,
+    FDIV,
     NUM,
     CHAR,
     HLT,
+    INT,
+    FLOT,
+    FIX,
     SLA,
     SRA,
     SLAX,
@@ -200,12 +214,19 @@ impl FromStr for Instruction {
         match s {
             "NOP" => Ok(NOP),
             "ADD" => Ok(ADD),
+            "FADD" => Ok(FADD),
             "SUB" => Ok(SUB),
+            "FSUB" => Ok(FSUB),
             "MUL" => Ok(MUL),
+            "FMUL" => Ok(FMUL),
             "DIV" => Ok(DIV),
+            "FDIV" => Ok(FDIV),
             "NUM" => Ok(NUM),
             "CHAR" => Ok(CHAR),
             "HLT" => Ok(HLT),
+            "INT" => Ok(INT),
+            "FLOT" => Ok(FLOT),
+            "FIX" => Ok(FIX),
             "SLA" => Ok(SLA),
             "SRA" => Ok(SRA),
             "SLAX" => Ok(SLAX),
@@ -354,12 +375,19 @@ impl ToString for Instruction {
         match self {
             NOP => "NOP",
             ADD => "ADD",
+            FADD => "FADD",
             SUB => "SUB",
+            FSUB => "FSUB",
             MUL => "MUL",
+            FMUL => "FMUL",
             DIV => "DIV",
+            FDIV => "FDIV",
             NUM => "NUM",
             CHAR => "CHAR",
             HLT => "HLT",
+            INT => "INT",
+            FLOT => "FLOT",
+            FIX => "FIX",
             SLA => "SLA",
             SRA => "SRA",
             SLAX => "SLAX",
@@ -502,6 +530,46 @@ impl ToString for Instruction {
     }
 }
 
+// すべての命令. C と F から命令を逆引きするのに使う.
+pub const INSTRUCTIONS: [Instruction; 152] = {
+    use Instruction::*;
+    [
+        NOP, ADD, FADD, SUB, FSUB, MUL, FMUL, DIV, FDIV, NUM, CHAR, HLT, INT, FLOT, FIX, SLA, SRA,
+        SLAX, SRAX, SLC, SRC, MOVE, LDA, LD1, LD2, LD3, LD4, LD5, LD6, LDX, LDAN, LD1N, LD2N, LD3N,
+        LD4N, LD5N, LD6N, LDXN, STA, ST1, ST2, ST3, ST4, ST5, ST6, STX, STJ, STZ, JBUS, IOC, IN,
+        OUT, JRED, JMP, JSJ, JOV, JNOV, JL, JE, JG, JGE, JNE, JLE, JAN, JAZ, JAP, JANN, JANZ, JANP,
+        J1N, J1Z, J1P, J1NN, J1NZ, J1NP, J2N, J2Z, J2P, J2NN, J2NZ, J2NP, J3N, J3Z, J3P, J3NN,
+        J3NZ, J3NP, J4N, J4Z, J4P, J4NN, J4NZ, J4NP, J5N, J5Z, J5P, J5NN, J5NZ, J5NP, J6N, J6Z,
+        J6P, J6NN, J6NZ, J6NP, JXN, JXZ, JXP, JXNN, JXNZ, JXNP, INCA, DECA, ENTA, ENNA, INC1, DEC1,
+        ENT1, ENN1, INC2, DEC2, ENT2, ENN2, INC3, DEC3, ENT3, ENN3, INC4, DEC4, ENT4, ENN4, INC5,
+        DEC5, ENT5, ENN5, INC6, DEC6, ENT6, ENN6, INCX, DECX, ENTX, ENNX, CMPA, FCMP, CMP1, CMP2,
+        CMP3, CMP4, CMP5, CMP6, CMPX,
+    ]
+};
+
+// C と F から命令を求める. F がフィールド指定や装置番号の命令は C だけで決まる.
+pub fn decode_instruction(c: i64, f: i64) -> Option<Instruction> {
+    let candidates: Vec<&Instruction> = INSTRUCTIONS
+        .iter()
+        .filter(|inst| instruction_data(inst).3 == c)
+        .collect();
+    if let Some(inst) = candidates.iter().find(|inst| instruction_data(inst).2 == f) {
+        return Some((*inst).clone());
+    }
+    match c {
+        // F で命令が決まる
+        5 | 6 | 39..=55 => None,
+        // ADD, SUB, MUL, DIV, CMPA の F は FADD などを除いてフィールド指定
+        _ => candidates
+            .iter()
+            .find(|inst| {
+                let (_, _, default_f, _) = instruction_data(inst);
+                default_f != 6
+            })
+            .map(|inst| (*inst).clone()),
+    }
+}
+
 pub fn instruction_data(inst: &Instruction) -> (i64, i64, i64, i64) {
     use Instruction::*;
 
@@ -509,12 +577,19 @@ pub fn instruction_data(inst: &Instruction) -> (i64, i64, i64, i64) {
     match inst {
         &NOP => (0, 0, 5, 0),
         &ADD => (0, 0, 5, 1),
+        &FADD => (0, 0, 6, 1),
         &SUB => (0, 0, 5, 2),
+        &FSUB => (0, 0, 6, 2),
         &MUL => (0, 0, 5, 3),
+        &FMUL => (0, 0, 6, 3),
         &DIV => (0, 0, 5, 4),
-        &NUM => (0, 0, 5, 5),
+        &FDIV => (0, 0, 6, 4),
+        &NUM => (0, 0, 0, 5),
         &CHAR => (0, 0, 1, 5),
         &HLT => (0, 0, 2, 5),
+        &INT => (0, 0, 9, 5),
+        &FLOT => (0, 0, 6, 5),
+        &FIX => (0, 0, 7, 5),
         &SLA => (0, 0, 0, 6),
         &SRA => (0, 0, 1, 6),
         &SLAX => (0, 0, 2, 6),
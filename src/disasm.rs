@@ -0,0 +1,133 @@
+use crate::common::{decode_instruction, instruction_data, CHAR_TABLE};
+use crate::mix_word::{Sign, WordImpl};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// 語を MIXAL の命令やデータに戻す
+#[derive(Debug, Clone, Default)]
+pub struct Disassembler {
+    // 番地からラベル
+    labels: HashMap<i64, String>,
+    // 常にデータとして読む番地
+    data: BTreeSet<i64>,
+}
+
+impl Disassembler {
+    pub fn new() -> Self {
+        Disassembler::default()
+    }
+    // アセンブラのシンボルテーブルからラベルを作る. 同じ値のシンボルは辞書順で最初のもの.
+    pub fn with_symbols(symbols: &BTreeMap<String, i64>) -> Self {
+        let mut labels = HashMap::new();
+        for (symbol, value) in symbols.iter() {
+            labels.entry(*value).or_insert_with(|| symbol.clone());
+        }
+        Disassembler {
+            labels,
+            data: BTreeSet::new(),
+        }
+    }
+    pub fn set_data(&mut self, address: i64) {
+        self.data.insert(address);
+    }
+    pub fn label(&self, address: i64) -> Option<&str> {
+        self.labels.get(&address).map(|s| s.as_str())
+    }
+
+    // 命令として読めれば "LDA 2000,1(1:3)" の形にする
+    pub fn instruction(&self, word: &WordImpl) -> Option<String> {
+        let (a, i, f, c) = (
+            word.address(),
+            word.index(),
+            word.modification(),
+            word.operation(),
+        );
+        let inst = decode_instruction(c, f)?;
+        let (_, _, default_f, _) = instruction_data(&inst);
+        let field = match c {
+            // F は命令の一部
+            0 | 5 | 6 | 39..=55 => None,
+            // F は装置番号や語数
+            7 | 34..=38 if f != default_f => Some(format!("({})", f)),
+            7 | 34..=38 => None,
+            _ if f == default_f => None,
+            _ if f / 8 <= f % 8 && f % 8 <= 5 => Some(format!("({}:{})", f / 8, f % 8)),
+            _ => Some(format!("({})", f)),
+        };
+
+        let mut text = inst.to_string();
+        if a == 0 && i == 0 && field.is_none() && *word.sign() == Sign::Positive {
+            return Some(text);
+        }
+        text += " ";
+        text += &match (word.sign(), self.label(a)) {
+            (Sign::Positive, Some(label)) => label.to_string(),
+            (Sign::Positive, None) => a.to_string(),
+            (Sign::Negative, _) => format!("-{}", a.abs()),
+        };
+        if i != 0 {
+            text += &format!(",{}", i);
+        }
+        if let Some(field) = field {
+            text += &field;
+        }
+        Some(text)
+    }
+    // データとして読む. 5 文字とも MIX の文字なら ALF, そうでなければ CON.
+    pub fn data(&self, word: &WordImpl) -> String {
+        let chars: Option<String> = (0..5)
+            .map(|x| CHAR_TABLE.get(word.byte(x).unwrap().val() as usize))
+            .collect();
+        match chars {
+            // 小さな数が ALF にならないよう, 空白でない文字が 2 つ以上あるときだけ
+            Some(chars)
+                if *word.sign() == Sign::Positive
+                    && chars.chars().filter(|c| *c != ' ').count() >= 2 =>
+            {
+                format!("ALF \"{}\"", chars)
+            }
+            _ if word.val() == 0 && *word.sign() == Sign::Negative => "CON -0".to_string(),
+            _ => format!("CON {}", word.val()),
+        }
+    }
+    // 命令として読めなければデータとして読む.
+    // 0 の語, 索引が 7 以上, アドレスが -0 の語, アドレスと索引が 0 なのにフィールドが
+    // 既定値と違う語 (小さな数) は命令らしくないのでデータとする.
+    pub fn word(&self, word: &WordImpl) -> String {
+        let (a, i, f, c) = (
+            word.address(),
+            word.index(),
+            word.modification(),
+            word.operation(),
+        );
+        let small_number = a == 0
+            && i == 0
+            && !matches!(c, 0 | 5 | 6 | 7 | 34..=55)
+            && decode_instruction(c, f).is_some_and(|inst| instruction_data(&inst).2 != f);
+        let plausible = word.val() != 0
+            && i <= 6
+            && !(a == 0 && *word.sign() == Sign::Negative)
+            && !small_number;
+        match self.instruction(word) {
+            Some(text) if plausible => text,
+            _ => self.data(word),
+        }
+    }
+    // メモリダンプ. 番地, ラベル, 命令を 1 行ずつ並べる.
+    pub fn dump(&self, words: &[(usize, WordImpl)]) -> String {
+        let mut text = String::new();
+        for (address, word) in words.iter() {
+            let address = *address as i64;
+            text += &format!(
+                "{:04} {:<10} {}\n",
+                address,
+                self.label(address).unwrap_or(""),
+                if self.data.contains(&address) {
+                    self.data(word)
+                } else {
+                    self.word(word)
+                }
+            );
+        }
+        text
+    }
+}
@@ -257,12 +257,10 @@ pub fn rotate_right_pair(n: i64, reg_a: &mut Register, reg_x: &mut Register) {
         *reg_x.byte_mut(i).unwrap() = *reg.byte(5 + i).unwrap();
     }
 }
-pub fn mov(from: i64, reg_i1: &mut Register, n: i64, mem: &mut Memory) {
-    let to = reg_i1.val();
+pub fn mov(from: usize, to: usize, n: usize, mem: &mut Memory) {
     for x in 0..n {
-        mem[(to + x) as usize] = mem[(from + x) as usize].clone();
+        mem[to + x] = mem[from + x].clone();
     }
-    *reg_i1 = Register::from_val(to + n);
 }
 
 // 入出力装置
@@ -310,6 +308,255 @@ pub fn to_char(reg_a: &mut Register, reg_x: &mut Register) {
     *reg_a.byte_mut(0).unwrap() = Byte::new(30 + (val % 10) as u32);
 }
 
+// 浮動小数点演算命令
+// 単精度浮動小数点数 (TAOCP 4.2.1) は ±, 指数部 (1:1), 小数部 (2:5) からなる。
+// 値は ± f * b^(e - q) (ただし f は 4 バイトの小数, q = b / 2) を表す。
+const FLOAT_PRECISION: u32 = 4;
+
+fn float_base() -> i128 {
+    Byte::max() as i128
+}
+fn float_excess() -> i64 {
+    (Byte::max() / 2) as i64
+}
+fn float_unpack(w: &Register) -> (Sign, i64, i128) {
+    let e = w.byte(0).unwrap().val() as i64;
+    let f = w.subword(Register::pair(2, 5)).unwrap().val() as i128;
+    (*w.sign(), e, f)
+}
+fn float_signed(sign: Sign, f: i128) -> i128 {
+    match sign {
+        Sign::Positive => f,
+        Sign::Negative => -f,
+    }
+}
+fn float_zero(sign: Sign) -> Register {
+    let mut ret = Register::from_val(0);
+    *ret.sign_mut() = sign;
+    ret
+}
+// 値 f * b^(e - q - k) を正規化し, 小数部を 4 バイトに丸めて詰め直す (Algorithm 4.2.1N).
+// sticky は f より下の桁に 0 でない値が残っているか (割り算の余り)
+fn float_normalize(
+    sign: Sign,
+    mut e: i64,
+    mut f: i128,
+    mut k: u32,
+    sticky: bool,
+    overflow: &mut bool,
+) -> Register {
+    let b = float_base();
+    if f == 0 {
+        return float_zero(sign);
+    }
+    // N4: 小数部が 1 以上なら右にずらす
+    while f >= b.pow(k) {
+        k += 1;
+        e += 1;
+    }
+    // N3: 小数部が 1/b 未満なら左にずらす
+    while f < b.pow(k - 1) {
+        f *= b;
+        e -= 1;
+    }
+    // N5: 丸め. ちょうど半分なら偶数に丸める
+    if k > FLOAT_PRECISION {
+        let div = b.pow(k - FLOAT_PRECISION);
+        let remainder = f % div;
+        f /= div;
+        if 2 * remainder > div || (2 * remainder == div && (sticky || f % 2 == 1)) {
+            f += 1;
+        }
+        // N6: 丸めによる桁あふれ
+        if f == b.pow(FLOAT_PRECISION) {
+            f /= b;
+            e += 1;
+        }
+    } else {
+        f *= b.pow(FLOAT_PRECISION - k);
+    }
+    // N7: 指数のあふれ
+    if e < 0 || e >= b as i64 {
+        *overflow = true;
+        e = e.rem_euclid(b as i64);
+    }
+    let mut ret = Register::from_val(f as i64);
+    *ret.byte_mut(0).unwrap() = Byte::new(e as u32);
+    *ret.sign_mut() = sign;
+    ret
+}
+// rA と V の差を正規化せずに求める (指数部, 符号付き小数部, 小数部の桁数)
+fn float_difference(u: &Register, v: &Register) -> (i64, i128, u32) {
+    let (su, eu, fu) = float_unpack(u);
+    let (sv, ev, fv) = float_unpack(v);
+    let mut u = (eu, float_signed(su, fu));
+    let mut v = (ev, -float_signed(sv, fv));
+    if u.1 == 0 {
+        return (v.0, v.1, FLOAT_PRECISION);
+    }
+    if v.1 == 0 {
+        return (u.0, u.1, FLOAT_PRECISION);
+    }
+    if u.0 < v.0 {
+        std::mem::swap(&mut u, &mut v);
+    }
+    let d = (u.0 - v.0) as u32;
+    if d >= FLOAT_PRECISION + 2 {
+        (u.0, u.1, FLOAT_PRECISION)
+    } else {
+        (u.0, u.1 * float_base().pow(d) + v.1, FLOAT_PRECISION + d)
+    }
+}
+// lhs * b^lhs_exp と rhs * b^rhs_exp を比較する (lhs, rhs >= 0)
+fn float_cmp_scaled(lhs: i128, lhs_exp: i64, rhs: i128, rhs_exp: i64) -> Ordering {
+    if lhs == 0 || rhs == 0 {
+        return lhs.cmp(&rhs);
+    }
+    let d = lhs_exp - rhs_exp;
+    if d >= 0 {
+        match float_base()
+            .checked_pow(d as u32)
+            .and_then(|scale| lhs.checked_mul(scale))
+        {
+            Some(lhs) => lhs.cmp(&rhs),
+            None => Ordering::Greater,
+        }
+    } else {
+        match float_base()
+            .checked_pow((-d) as u32)
+            .and_then(|scale| rhs.checked_mul(scale))
+        {
+            Some(rhs) => lhs.cmp(&rhs),
+            None => Ordering::Less,
+        }
+    }
+}
+pub fn fadd(from: &Register, to: &mut Register, overflow: &mut bool) {
+    let mut negated = from.clone();
+    *negated.sign_mut() = match *from.sign() {
+        Sign::Positive => Sign::Negative,
+        Sign::Negative => Sign::Positive,
+    };
+    fsub(&negated, to, overflow);
+}
+pub fn fsub(from: &Register, to: &mut Register, overflow: &mut bool) {
+    let (e, f, k) = float_difference(to, from);
+    let sign = if f < 0 {
+        Sign::Negative
+    } else {
+        Sign::Positive
+    };
+    *to = float_normalize(sign, e, f.abs(), k, false, overflow);
+}
+pub fn fmul(from: &Register, to: &mut Register, overflow: &mut bool) {
+    let (su, eu, fu) = float_unpack(to);
+    let (sv, ev, fv) = float_unpack(from);
+    let sign = if su != sv {
+        Sign::Negative
+    } else {
+        Sign::Positive
+    };
+    *to = float_normalize(
+        sign,
+        eu + ev - float_excess(),
+        fu * fv,
+        2 * FLOAT_PRECISION,
+        false,
+        overflow,
+    );
+}
+pub fn fdiv(from: &Register, to: &mut Register, overflow: &mut bool) {
+    let (su, eu, fu) = float_unpack(to);
+    let (sv, ev, fv) = float_unpack(from);
+    if fv == 0 {
+        *overflow = true;
+        return;
+    }
+    let sign = if su != sv {
+        Sign::Negative
+    } else {
+        Sign::Positive
+    };
+    if fu == 0 {
+        *to = float_zero(sign);
+        return;
+    }
+    // 正規化していない被除数は先に左にずらし, 丸めに十分な桁数まで商を求める
+    let b = float_base();
+    let (mut eu, mut fu) = (eu, fu);
+    while fu < b.pow(FLOAT_PRECISION - 1) {
+        fu *= b;
+        eu -= 1;
+    }
+    let k = 2 * FLOAT_PRECISION;
+    let n = fu * b.pow(k);
+    *to = float_normalize(
+        sign,
+        eu - ev + float_excess(),
+        n / fv,
+        k,
+        n % fv != 0,
+        overflow,
+    );
+}
+pub fn flot(reg_a: &mut Register, overflow: &mut bool) {
+    let sign = *reg_a.sign();
+    let f = reg_a.val().abs() as i128;
+    *reg_a = float_normalize(sign, float_excess() + 5, f, 5, false, overflow);
+}
+pub fn fix(reg_a: &mut Register, overflow: &mut bool) {
+    let (sign, e, f) = float_unpack(reg_a);
+    let b = float_base();
+    let word_max = Byte::word_max() as i128;
+    let x = e - float_excess() - FLOAT_PRECISION as i64;
+    let mut n = if f == 0 {
+        0
+    } else if x >= 0 {
+        // 整数部が 5 バイトに収まらない
+        match b
+            .checked_pow(x as u32)
+            .and_then(|scale| f.checked_mul(scale))
+        {
+            Some(n) if x <= 5 => n,
+            _ => {
+                *overflow = true;
+                0
+            }
+        }
+    } else if -x > 2 * FLOAT_PRECISION as i64 {
+        0
+    } else {
+        let div = b.pow((-x) as u32);
+        let remainder = f % div;
+        if 2 * remainder >= div {
+            f / div + 1
+        } else {
+            f / div
+        }
+    };
+    if n >= word_max {
+        *overflow = true;
+        n %= word_max;
+    }
+    *reg_a = Register::from_val(n as i64);
+    *reg_a.sign_mut() = sign;
+}
+// epsilon (通常は 0 番地の内容) を用いて rA と V を比較する (TAOCP 4.2.2)
+pub fn fcmp(lhs: &Register, rhs: &Register, epsilon: &Register) -> Ordering {
+    let (_, f, k) = float_difference(lhs, rhs);
+    let (_, ee, fe) = float_unpack(epsilon);
+    // |u - v| <= epsilon * b^(e - q) なら u ~ v
+    // |f| * b^(e - q - k) <= fe * b^(ee - q - 4) * b^(e - q)
+    let lhs_exp = float_excess() + FLOAT_PRECISION as i64 - ee;
+    if float_cmp_scaled(f.abs(), lhs_exp, fe, k as i64) != Ordering::Greater {
+        Ordering::Equal
+    } else if f < 0 {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -572,6 +819,139 @@ mod tests {
         );
     }
     #[test]
+    fn test_floating_point() {
+        macro_rules! float {
+            ($sign: expr, $e: expr, $f1: expr, $f2: expr, $f3: expr, $f4: expr) => {
+                Register::from_seq($sign, &vec![$e, $f1, $f2, $f3, $f4])
+            };
+        }
+        let one = float!(Sign::Positive, 33, 1, 0, 0, 0);
+        let three = float!(Sign::Positive, 33, 3, 0, 0, 0);
+        let mut overflow = false;
+
+        // FLOT
+        let mut reg_a = Register::from_val(1);
+        flot(&mut reg_a, &mut overflow);
+        assert_eq!(one, reg_a);
+        let mut reg_a = Register::from_val(-3);
+        flot(&mut reg_a, &mut overflow);
+        assert_eq!(float!(Sign::Negative, 33, 3, 0, 0, 0), reg_a);
+        let mut reg_a = Register::from_val(Byte::word_max() - 1);
+        flot(&mut reg_a, &mut overflow);
+        assert_eq!(float!(Sign::Positive, 38, 1, 0, 0, 0), reg_a);
+
+        // FADD/FSUB
+        let mut reg_a = one.clone();
+        fadd(&one, &mut reg_a, &mut overflow);
+        assert_eq!(float!(Sign::Positive, 33, 2, 0, 0, 0), reg_a);
+        let mut reg_a = one.clone();
+        fadd(
+            &float!(Sign::Positive, 32, 1, 0, 0, 0),
+            &mut reg_a,
+            &mut overflow,
+        );
+        assert_eq!(float!(Sign::Positive, 33, 1, 1, 0, 0), reg_a);
+        let mut reg_a = one.clone();
+        fsub(&three, &mut reg_a, &mut overflow);
+        assert_eq!(float!(Sign::Negative, 33, 2, 0, 0, 0), reg_a);
+        let mut reg_a = one.clone();
+        fsub(&one, &mut reg_a, &mut overflow);
+        assert_eq!(0, reg_a.val());
+        let mut reg_a = one.clone();
+        fadd(
+            &float!(Sign::Positive, 20, 1, 0, 0, 0),
+            &mut reg_a,
+            &mut overflow,
+        );
+        assert_eq!(one, reg_a);
+
+        // FMUL/FDIV
+        let mut reg_a = float!(Sign::Negative, 33, 2, 0, 0, 0);
+        fmul(&three, &mut reg_a, &mut overflow);
+        assert_eq!(float!(Sign::Negative, 33, 6, 0, 0, 0), reg_a);
+        let mut reg_a = one.clone();
+        fdiv(&three, &mut reg_a, &mut overflow);
+        assert_eq!(float!(Sign::Positive, 32, 21, 21, 21, 21), reg_a);
+        let mut reg_a = float!(Sign::Positive, 33, 2, 0, 0, 0);
+        fdiv(&three, &mut reg_a, &mut overflow);
+        assert_eq!(float!(Sign::Positive, 32, 42, 42, 42, 43), reg_a);
+        assert!(!overflow);
+
+        // ちょうど半分は偶数に丸める
+        let half = float!(Sign::Positive, 29, 32, 0, 0, 0);
+        let mut reg_a = one.clone();
+        fadd(&half, &mut reg_a, &mut overflow);
+        assert_eq!(one, reg_a);
+        let mut reg_a = float!(Sign::Positive, 33, 1, 0, 0, 1);
+        fadd(&half, &mut reg_a, &mut overflow);
+        assert_eq!(float!(Sign::Positive, 33, 1, 0, 0, 2), reg_a);
+        let mut reg_a = float!(Sign::Positive, 33, 2, 0, 0, 1);
+        fdiv(
+            &float!(Sign::Positive, 33, 0, 0, 0, 2),
+            &mut reg_a,
+            &mut overflow,
+        );
+        assert_eq!(float!(Sign::Positive, 36, 1, 0, 0, 0), reg_a);
+        // 切り捨てた商はちょうど半分だが, 余りがあるので切り上げる
+        let mut reg_a = float!(Sign::Positive, 33, 1, 0, 0, 3);
+        fdiv(
+            &float!(Sign::Positive, 33, 53, 30, 27, 23),
+            &mut reg_a,
+            &mut overflow,
+        );
+        assert_eq!(float!(Sign::Positive, 32, 1, 12, 38, 13), reg_a);
+        // 正規化していない被除数
+        let mut reg_a = float!(Sign::Positive, 33, 0, 0, 0, 1);
+        fdiv(&three, &mut reg_a, &mut overflow);
+        assert_eq!(float!(Sign::Positive, 29, 21, 21, 21, 21), reg_a);
+        assert!(!overflow);
+
+        // exponent overflow and division by zero
+        let mut reg_a = float!(Sign::Positive, 63, 1, 0, 0, 0);
+        fmul(
+            &float!(Sign::Positive, 63, 1, 0, 0, 0),
+            &mut reg_a,
+            &mut overflow,
+        );
+        assert!(overflow);
+        assert_eq!(float!(Sign::Positive, 29, 1, 0, 0, 0), reg_a);
+        let mut overflow = false;
+        let mut reg_a = one.clone();
+        fdiv(&Register::from_val(0), &mut reg_a, &mut overflow);
+        assert!(overflow);
+        assert_eq!(one, reg_a);
+
+        // FIX
+        let mut overflow = false;
+        let mut reg_a = float!(Sign::Positive, 33, 2, 32, 0, 0);
+        fix(&mut reg_a, &mut overflow);
+        assert_eq!(3, reg_a.val());
+        let mut reg_a = float!(Sign::Negative, 33, 2, 31, 63, 63);
+        fix(&mut reg_a, &mut overflow);
+        assert_eq!(-2, reg_a.val());
+        let mut reg_a = float!(Sign::Positive, 32, 21, 21, 21, 21);
+        fix(&mut reg_a, &mut overflow);
+        assert_eq!(0, reg_a.val());
+        assert!(!overflow);
+        let mut reg_a = float!(Sign::Positive, 40, 1, 0, 0, 0);
+        fix(&mut reg_a, &mut overflow);
+        assert!(overflow);
+
+        // FCMP
+        let near_one = float!(Sign::Positive, 33, 1, 0, 0, 1);
+        let zero = Register::from_val(0);
+        let epsilon = float!(Sign::Positive, 32, 0, 0, 1, 0);
+        assert_eq!(Ordering::Less, fcmp(&one, &near_one, &zero));
+        assert_eq!(Ordering::Greater, fcmp(&near_one, &one, &zero));
+        assert_eq!(Ordering::Equal, fcmp(&one, &one, &zero));
+        assert_eq!(Ordering::Equal, fcmp(&one, &near_one, &epsilon));
+        assert_eq!(Ordering::Less, fcmp(&one, &three, &epsilon));
+        assert_eq!(
+            Ordering::Greater,
+            fcmp(&one, &float!(Sign::Negative, 33, 3, 0, 0, 0), &epsilon)
+        );
+    }
+    #[test]
     fn problem22() {
         macro_rules! solve1 {
             ($val: expr) => {
@@ -1,16 +1,46 @@
 use crate::common::CHAR_TABLE;
 use crate::mix_word::{Sign, WordImpl};
 
+// 入出力装置の所要時間 (単位時間 u)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Latency {
+    // 1 ブロックの転送にかかる時間
+    pub transfer: i64,
+    // 1 ブロック分の位置決め (巻き戻し, 改ページ) にかかる時間
+    pub seek: i64,
+}
+
+// 装置の状態. スナップショットで保存, 復元する.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitState {
+    pub data: Vec<WordImpl>,
+    pub pos: usize,
+    pub busy_until: i64,
+    pub latency: Option<Latency>,
+}
+
 pub trait IOUnit {
     type Word;
     fn block_size() -> usize;
+    fn default_latency() -> Latency;
 
-    fn read(&mut self) -> Vec<&Self::Word>;
+    fn read(&mut self) -> Option<Vec<&Self::Word>>;
     fn write(&mut self, w: Vec<&Self::Word>);
-    fn seek(&mut self, offset: i64);
+    // writes ブロックを書いた後に 1 ブロック読めるか
+    fn can_read(&self, writes: usize) -> bool;
+    // 移動したブロック数を返す
+    fn seek(&mut self, offset: i64) -> usize;
+
+    fn latency(&self) -> Latency;
+    fn set_latency(&mut self, latency: Latency);
+    // clock の時点で転送中かどうか
+    fn ready(&self, clock: i64) -> bool;
+    fn busy(&self, clock: i64) -> bool;
+    // done まで転送中にする
+    fn start(&mut self, done: i64);
 
-    fn ready(&self) -> bool;
-    fn busy(&self) -> bool;
+    fn save_state(&self) -> UnitState;
+    fn restore_state(&mut self, state: UnitState);
 
     fn print(&self) -> String;
     fn print_binary(&self) -> Vec<i64>;
@@ -22,37 +52,56 @@ pub trait IOUnit {
 pub struct Tape {
     data: Vec<WordImpl>,
     pos: usize,
+    busy_until: i64,
+    latency: Option<Latency>,
 }
-#[derive(Debug, Default, Clone)]
+// ディスクの既定の容量 (ブロック数)
+const DISK_CAPACITY: usize = 4096;
+
+// ブロック番号 (rX) で読み書きする位置を指定する
+#[derive(Debug, Clone)]
 pub struct Disk {
     data: Vec<WordImpl>,
     pos: usize,
+    busy_until: i64,
+    latency: Option<Latency>,
+    capacity: usize,
 }
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct CardReader {
     data: Vec<WordImpl>,
     pos: usize,
+    busy_until: i64,
+    latency: Option<Latency>,
 }
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct CardPunch {
     data: Vec<WordImpl>,
     pos: usize,
+    busy_until: i64,
+    latency: Option<Latency>,
 }
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct LinePrinter {
     data: Vec<WordImpl>,
     page: Vec<usize>,
     pos: usize,
+    busy_until: i64,
+    latency: Option<Latency>,
 }
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct TypeWriterTerminal {
     data: Vec<WordImpl>,
     pos: usize,
+    busy_until: i64,
+    latency: Option<Latency>,
 }
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct PaperTape {
     data: Vec<WordImpl>,
     pos: usize,
+    busy_until: i64,
+    latency: Option<Latency>,
 }
 
 fn num_to_char(n: usize) -> char {
@@ -78,19 +127,28 @@ fn chars_to_word(s: &str) -> Option<WordImpl> {
 }
 
 macro_rules! impl_io_trait {
-    ($machine: ty, $block_size: expr) => {
+    ($machine: ty, $block_size: expr, $transfer: expr, $seek: expr) => {
         impl IOUnit for $machine {
             type Word = WordImpl;
             fn block_size() -> usize {
                 $block_size
             }
-            fn read(&mut self) -> Vec<&Self::Word> {
+            fn default_latency() -> Latency {
+                Latency {
+                    transfer: $transfer,
+                    seek: $seek,
+                }
+            }
+            fn read(&mut self) -> Option<Vec<&Self::Word>> {
+                if self.data.len() < self.pos + Self::block_size() {
+                    return None;
+                }
                 let mut ret = vec![];
                 for i in 0..(Self::block_size()) {
                     ret.push(&self.data[self.pos + i]);
                 }
                 self.pos += Self::block_size();
-                ret
+                Some(ret)
             }
             fn write(&mut self, w: Vec<&Self::Word>) {
                 // resize
@@ -103,19 +161,48 @@ macro_rules! impl_io_trait {
                 }
                 self.pos += Self::block_size();
             }
-            fn seek(&mut self, offset: i64) {
+            fn can_read(&self, writes: usize) -> bool {
+                self.pos + (writes + 1) * Self::block_size() <= self.data.len()
+            }
+            fn seek(&mut self, offset: i64) -> usize {
                 let offset = offset * Self::block_size() as i64;
-                self.pos = (if (self.pos as i64) + offset >= 0 {
+                let pos = (if (self.pos as i64) + offset >= 0 {
                     self.pos as i64 + offset
                 } else {
                     0
                 }) as usize;
+                let moved = pos.abs_diff(self.pos);
+                self.pos = pos;
+                moved / Self::block_size()
+            }
+            fn latency(&self) -> Latency {
+                self.latency.unwrap_or_else(Self::default_latency)
             }
-            fn ready(&self) -> bool {
-                true
+            fn set_latency(&mut self, latency: Latency) {
+                self.latency = Some(latency);
             }
-            fn busy(&self) -> bool {
-                false
+            fn ready(&self, clock: i64) -> bool {
+                !self.busy(clock)
+            }
+            fn busy(&self, clock: i64) -> bool {
+                clock < self.busy_until
+            }
+            fn start(&mut self, done: i64) {
+                self.busy_until = done;
+            }
+            fn save_state(&self) -> UnitState {
+                UnitState {
+                    data: self.data.clone(),
+                    pos: self.pos,
+                    busy_until: self.busy_until,
+                    latency: self.latency,
+                }
+            }
+            fn restore_state(&mut self, state: UnitState) {
+                self.data = state.data;
+                self.pos = state.pos;
+                self.busy_until = state.busy_until;
+                self.latency = state.latency;
             }
             fn print(&self) -> String {
                 let mut ret = "".to_string();
@@ -153,16 +240,61 @@ macro_rules! impl_io_trait {
     };
 }
 
-impl_io_trait!(Tape, 100);
-impl_io_trait!(Disk, 100);
-impl_io_trait!(CardReader, 16);
-impl_io_trait!(CardPunch, 16);
-impl_io_trait!(LinePrinter, 24);
-impl_io_trait!(TypeWriterTerminal, 14);
-impl_io_trait!(PaperTape, 14);
+// 装置, ブロックの大きさ, 1 ブロックの転送時間, 1 ブロック分の位置決め時間
+impl_io_trait!(Tape, 100, 1000, 100);
+impl_io_trait!(Disk, 100, 500, 100);
+impl_io_trait!(CardReader, 16, 10000, 0);
+impl_io_trait!(CardPunch, 16, 20000, 0);
+impl_io_trait!(LinePrinter, 24, 7500, 7500);
+impl_io_trait!(TypeWriterTerminal, 14, 50000, 0);
+impl_io_trait!(PaperTape, 14, 5000, 100);
+
+impl Default for Disk {
+    fn default() -> Self {
+        Disk {
+            data: vec![],
+            pos: 0,
+            busy_until: 0,
+            latency: None,
+            capacity: DISK_CAPACITY,
+        }
+    }
+}
+
+impl Disk {
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    pub fn set_capacity(&mut self, blocks: usize) {
+        self.capacity = blocks;
+    }
+    pub fn contains_block(&self, block: i64) -> bool {
+        0 <= block && (block as usize) < self.capacity
+    }
+    // block 番目のブロックに位置決めし, 移動したブロック数を返す.
+    // まだ書き込まれていないブロックは 0 で埋めておく.
+    pub fn seek_block(&mut self, block: i64) -> usize {
+        debug_assert!(self.contains_block(block));
+        let pos = block as usize * Self::block_size();
+        if self.data.len() < pos + Self::block_size() {
+            self.data
+                .resize(pos + Self::block_size(), WordImpl::from_val(0));
+        }
+        let moved = pos.abs_diff(self.pos);
+        self.pos = pos;
+        moved / Self::block_size()
+    }
+}
 
 impl LinePrinter {
     pub fn next_page(&mut self) {
         self.page.push(self.pos);
     }
+    // 改ページした位置
+    pub fn pages(&self) -> &Vec<usize> {
+        &self.page
+    }
+    pub fn set_pages(&mut self, page: Vec<usize>) {
+        self.page = page;
+    }
 }
@@ -1,7 +1,10 @@
 pub mod asm;
 mod common;
+pub mod disasm;
 mod instruction;
 mod iounit;
+pub mod link;
 mod mix_word;
+pub mod object;
 pub mod tools;
 pub mod vm;
@@ -0,0 +1,172 @@
+// 再配置できるモジュール (asm::assemble_module) をつなげて, MixVM::load で読める 1 つの
+// オブジェクトにする.
+//
+// モジュールは origin 番地から順に並べる. 絶対番地のオブジェクトは書かれた番地のまま置く.
+// ENTRY で公開したシンボルは他のモジュールから EXTERN で宣言して使える.
+//
+// サブルーチンは TAOCP 1.4.1 の流儀で呼ぶ. 呼ぶ側は JMP SUB で飛び, rJ に戻り番地が入る.
+// サブルーチンは入口で STJ EXIT として rJ を EXIT のアドレス部に保存し, EXIT JMP * で戻る.
+// 入口が STJ で始まる公開シンボルは, 出力の linkage に EXIT の番地を記録する.
+use crate::asm::{fits_in_address, set_address, Relocation};
+use crate::mix_word::{Byte, WordImpl};
+use crate::object::ObjectFile;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    // 同じシンボルを公開した 2 つのモジュール
+    DuplicateSymbol(String, String, String),
+    // どのモジュールも公開していないシンボルと, それを使ったモジュール
+    UndefinedSymbol(String, String),
+    // 2 つのモジュールが同じ番地に語を置いた
+    Overlap(usize, String, String),
+    // モジュールがメモリに収まらない, または直したアドレスが 2 バイトに収まらない
+    OutOfRange(String, usize),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::DuplicateSymbol(symbol, first, second) => write!(
+                f,
+                "symbol {} is exported by both {} and {}",
+                symbol, first, second
+            ),
+            LinkError::UndefinedSymbol(symbol, module) => {
+                write!(f, "{}: external symbol {} is not defined", module, symbol)
+            }
+            LinkError::Overlap(address, first, second) => {
+                write!(f, "{} and {} both use location {}", first, second, address)
+            }
+            LinkError::OutOfRange(module, address) => {
+                write!(f, "{}: location {} is out of range", module, address)
+            }
+        }
+    }
+}
+
+pub fn link(modules: &[(String, ObjectFile)], origin: usize) -> Result<ObjectFile, Vec<LinkError>> {
+    let mut errors = vec![];
+
+    // 1. モジュールを置く番地を決める
+    let mut bases = vec![];
+    let mut next = origin;
+    for (name, object) in modules.iter() {
+        match object.module {
+            Some(size) => {
+                if next + size > 4000 {
+                    errors.push(LinkError::OutOfRange(name.clone(), next + size - 1));
+                }
+                bases.push(next);
+                next += size;
+            }
+            None => bases.push(0),
+        }
+    }
+
+    // 2. 公開されたシンボルの値を決める
+    let mut globals: BTreeMap<String, (i64, &String, bool)> = BTreeMap::new();
+    for ((name, object), base) in modules.iter().zip(bases.iter()) {
+        for (symbol, (value, relocatable)) in object.exports.iter() {
+            let value = if *relocatable {
+                value + *base as i64
+            } else {
+                *value
+            };
+            match globals.get(symbol) {
+                Some((_, first, _)) => errors.push(LinkError::DuplicateSymbol(
+                    symbol.clone(),
+                    (*first).clone(),
+                    name.clone(),
+                )),
+                None => {
+                    globals.insert(symbol.clone(), (value, name, *relocatable));
+                }
+            }
+        }
+    }
+
+    // 3. 語を置き, 再配置する
+    let mut binary: Vec<(usize, WordImpl)> = vec![];
+    let mut owner: HashMap<usize, &String> = HashMap::new();
+    for ((name, object), base) in modules.iter().zip(bases.iter()) {
+        for (address, word) in object.binary() {
+            let mut word = word;
+            let location = address + base;
+            let a = match object.relocations.get(&address) {
+                Some(Relocation::Address) => Some(word.address() + *base as i64),
+                Some(Relocation::External(symbol)) => match globals.get(symbol) {
+                    Some((value, _, _)) => Some(word.address() + value),
+                    None => {
+                        let e = LinkError::UndefinedSymbol(symbol.clone(), name.clone());
+                        if !errors.contains(&e) {
+                            errors.push(e);
+                        }
+                        None
+                    }
+                },
+                Some(Relocation::Value) => {
+                    let v = word.val() + *base as i64;
+                    if v.abs() >= Byte::word_max() {
+                        errors.push(LinkError::OutOfRange(name.clone(), location));
+                    } else {
+                        word = WordImpl::from_val(v);
+                    }
+                    None
+                }
+                None => None,
+            };
+            if let Some(a) = a {
+                if fits_in_address(a) {
+                    set_address(&mut word, a);
+                } else {
+                    errors.push(LinkError::OutOfRange(name.clone(), location));
+                }
+            }
+            if location >= 4000 {
+                continue;
+            }
+            match owner.get(&location) {
+                Some(first) if *first != name => {
+                    errors.push(LinkError::Overlap(location, (*first).clone(), name.clone()))
+                }
+                _ => {
+                    owner.insert(location, name);
+                }
+            }
+            binary.push((location, word));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // 開始番地は先頭のモジュール (主プログラム) のもの
+    let entry_point = match modules.first() {
+        Some((_, object)) if object.module.is_some() => object.entry_point + bases[0],
+        Some((_, object)) => object.entry_point,
+        None => origin,
+    };
+    let mut linked = ObjectFile::from_binary(entry_point, &binary);
+    let memory: HashMap<usize, &WordImpl> = binary.iter().map(|(a, w)| (*a, w)).collect();
+    for (symbol, (value, _, relocatable)) in globals.into_iter() {
+        // 入口が STJ EXIT (索引なし, フィールド (0:2)) の番地
+        let word = memory
+            .get(&(value as usize))
+            .filter(|_| relocatable && value >= 0);
+        if let Some(word) = word {
+            if word.operation() == 32
+                && word.index() == 0
+                && word.modification() == 2
+                && word.address() >= 0
+            {
+                linked
+                    .linkage
+                    .insert(symbol.clone(), word.address() as usize);
+            }
+        }
+        linked.symbols.insert(symbol, value);
+    }
+    Ok(linked)
+}
@@ -1,3 +1,495 @@
+use mix_emulator::asm::{self, AssembledProgram, SourceFormat};
+use mix_emulator::disasm::Disassembler;
+use mix_emulator::link;
+use mix_emulator::object::ObjectFile;
+use mix_emulator::tools::{
+    self, repl, text_to_block, CallGraph, DebugSession, RunInfo, TraceFilter, TraceStep, Tracer,
+};
+use mix_emulator::vm::{MixVM, StepOutcome};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "usage:
+    mix assemble FILE [--fixed] [--module] [--listing] [--object OUT [--strip]] [--deck OUT]
+    mix link FILE... [--object OUT] [--origin N]
+    mix run FILE [OPTIONS]
+    mix trace FILE [OPTIONS]
+    mix debug FILE [OPTIONS]
+    mix trace-diff TRACE1 TRACE2
+
+options:
+    --fixed               read the source in Knuth's card format (LOC 1-10, OP 12-15, ADDRESS 16-)
+    --module              assemble a relocatable module (ENTRY/EXTERN) for mix link
+    --listing             print the assembly listing instead of the words
+    --object OUT          write a MIX object file instead of printing the words
+    --strip               leave the symbol table and line map out of the object file
+    --deck OUT            write a card loading deck (TAOCP 1.3.1-26), one card per line
+    --go                  boot from a loading deck with the GO button instead of loading directly
+    --origin N            place the linked modules from location N (default: 0)
+    --card FILE           card reader (16) input, one card per line
+    --tape U=FILE         tape unit U (0..=7) input, word values separated by whitespace
+    --printer FILE        line printer (18) output (default: stdout)
+    --punch FILE          card punch (17) output (default: stdout)
+    --max-steps N         stop after N instructions
+    --profile OUT         write the execution count and time of each source line to OUT (- for stdout)
+    --csv                 write the profile as CSV
+    --calls OUT           write the time of each subroutine called through rJ and its callers to OUT
+    --dot                 write the call graph in Graphviz DOT
+    --folded              write the call stacks in the folded format of flame graph tools
+    --record OUT          write a trace of every instruction and the state it changed to OUT
+    --range A..B          record only the instructions at locations A..=B
+    --op NAMES            record only these operations (comma separated, e.g. JMP,STA)";
+
+#[derive(Debug, Default)]
+struct Options {
+    source: String,
+    // mix link で source の後に続くファイル
+    modules: Vec<String>,
+    cards: Option<String>,
+    tapes: Vec<(usize, String)>,
+    printer: Option<String>,
+    punch: Option<String>,
+    max_steps: Option<usize>,
+    fixed: bool,
+    listing: bool,
+    object: Option<String>,
+    strip: bool,
+    deck: Option<String>,
+    go: bool,
+    module: bool,
+    origin: Option<usize>,
+    profile: Option<String>,
+    csv: bool,
+    calls: Option<String>,
+    dot: bool,
+    folded: bool,
+    record: Option<String>,
+    filter: TraceFilter,
+}
+
+impl Options {
+    fn format(&self) -> SourceFormat {
+        if self.fixed {
+            SourceFormat::Card
+        } else {
+            SourceFormat::FreeForm
+        }
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("mix: {}", message);
+    process::exit(1);
+}
+
+fn parse_options(args: &[String]) -> Options {
+    let mut options = Options::default();
+    let mut iter = args.iter();
+    let value = |name: &str, iter: &mut std::slice::Iter<String>| -> String {
+        match iter.next() {
+            Some(value) => value.clone(),
+            None => fail(&format!("{} needs a value", name)),
+        }
+    };
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--fixed" => options.fixed = true,
+            "--listing" => options.listing = true,
+            "--object" => options.object = Some(value(arg, &mut iter)),
+            "--strip" => options.strip = true,
+            "--deck" => options.deck = Some(value(arg, &mut iter)),
+            "--go" => options.go = true,
+            "--module" => options.module = true,
+            "--origin" => match value(arg, &mut iter).parse::<usize>() {
+                Ok(n) if n < 4000 => options.origin = Some(n),
+                _ => fail("--origin expects a location"),
+            },
+            "--card" => options.cards = Some(value(arg, &mut iter)),
+            "--tape" => {
+                let spec = value(arg, &mut iter);
+                let (unit, path) = match spec.find('=') {
+                    Some(i) => (&spec[..i], &spec[(i + 1)..]),
+                    None => fail("--tape expects U=FILE"),
+                };
+                match unit.parse::<usize>() {
+                    Ok(unit) if unit <= 7 => options.tapes.push((unit, path.to_string())),
+                    _ => fail(&format!("invalid tape unit {}", unit)),
+                }
+            }
+            "--profile" => options.profile = Some(value(arg, &mut iter)),
+            "--csv" => options.csv = true,
+            "--calls" => options.calls = Some(value(arg, &mut iter)),
+            "--dot" => options.dot = true,
+            "--folded" => options.folded = true,
+            "--record" => options.record = Some(value(arg, &mut iter)),
+            "--range" => {
+                let range = value(arg, &mut iter);
+                let bounds: Vec<Option<i64>> =
+                    range.split("..").map(|b| b.parse::<i64>().ok()).collect();
+                match bounds[..] {
+                    [Some(begin), Some(end)] => options.filter.range = Some((begin, end)),
+                    _ => fail("--range expects A..B"),
+                }
+            }
+            "--op" => {
+                let names = value(arg, &mut iter);
+                options.filter.operations = names.split(',').map(|op| op.to_uppercase()).collect();
+            }
+            "--printer" => options.printer = Some(value(arg, &mut iter)),
+            "--punch" => options.punch = Some(value(arg, &mut iter)),
+            "--max-steps" => match value(arg, &mut iter).parse::<usize>() {
+                Ok(n) => options.max_steps = Some(n),
+                Err(_) => fail("--max-steps expects a number"),
+            },
+            _ if arg.starts_with("--") => fail(&format!("unknown option {}", arg)),
+            _ if options.source.is_empty() => options.source = arg.clone(),
+            _ => options.modules.push(arg.clone()),
+        }
+    }
+    if options.source.is_empty() {
+        fail("no source file");
+    }
+    options
+}
+
+// link 以外はファイルを 1 つだけ取る
+fn single_file(options: Options) -> Options {
+    if let Some(arg) = options.modules.first() {
+        fail(&format!("unexpected argument {}", arg));
+    }
+    options
+}
+
+fn read_file(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => fail(&format!("{}: {}", path, e)),
+    }
+}
+
+fn write_output(path: &Option<String>, text: &str) {
+    match path {
+        Some(path) if path != "-" => {
+            if let Err(e) = fs::write(path, text) {
+                fail(&format!("{}: {}", path, e));
+            }
+        }
+        _ => {
+            print!("{}", text);
+            io::stdout().flush().unwrap();
+        }
+    }
+}
+
+// アセンブルする. エラーがあればすべて表示して終了する
+fn assemble_file(path: &str, format: SourceFormat, module: bool) -> AssembledProgram {
+    let code = read_file(path);
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    match asm::assemble_at(&code, format, module, dir) {
+        Ok(program) => program,
+        Err(errors) => {
+            for e in errors.iter() {
+                eprintln!("{}:{}", path, e);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+// オブジェクトファイルならそのまま読み, そうでなければアセンブルする
+fn load_file(path: &str, format: SourceFormat, module: bool) -> ObjectFile {
+    let text = read_file(path);
+    if !text.starts_with("MIX OBJECT") {
+        return ObjectFile::new(&assemble_file(path, format, module));
+    }
+    match ObjectFile::read(text.as_bytes()) {
+        Ok(object) => object,
+        Err(e) => fail(&format!("{}: {}", path, e)),
+    }
+}
+
+// 入力ファイルを読み込んだ VM と, 読み込んだオブジェクトを用意する
+fn setup(options: &Options) -> (MixVM, ObjectFile) {
+    let object = load_file(&options.source, options.format(), false);
+    let mut vm = MixVM::new();
+    if options.go {
+        // ローダのデッキを入力カードの前に置く
+        match asm::loading_deck(object.entry_point, &object.binary()) {
+            Ok(deck) => {
+                for card in deck.iter() {
+                    vm.read(16, text_to_block(card, 16).unwrap());
+                }
+            }
+            Err(e) => fail(&format!("{}: {}", options.source, e)),
+        }
+    } else {
+        vm.load(&object);
+    }
+    read_devices(&mut vm, options);
+    if options.go {
+        if let Err(e) = vm.press_go() {
+            fail(&e.to_string());
+        }
+    }
+    (vm, object)
+}
+
+// カードリーダとテープに入力ファイルを読み込む
+fn read_devices(vm: &mut MixVM, options: &Options) {
+    if let Some(path) = &options.cards {
+        for (n, line) in read_file(path).lines().enumerate() {
+            match text_to_block(line, 16) {
+                Ok(card) => vm.read(16, card),
+                Err(e) => fail(&format!("{}:{}: {}", path, n + 1, e)),
+            }
+        }
+    }
+    for (unit, path) in options.tapes.iter() {
+        let words: Result<Vec<i64>, _> = read_file(path)
+            .split_whitespace()
+            .map(|word| word.parse::<i64>())
+            .collect();
+        match words {
+            Ok(words) if words.len() % 100 == 0 => vm.read_binary(*unit, words),
+            Ok(_) => fail(&format!("{}: tape blocks must have 100 words", path)),
+            Err(e) => fail(&format!("{}: {}", path, e)),
+        }
+    }
+}
+
+// ソースをアセンブルしてデバッガを標準入力から動かす
+fn debug(options: &Options) {
+    let program = assemble_file(&options.source, options.format(), false);
+    let mut vm = MixVM::new();
+    read_devices(&mut vm, options);
+    let mut session = DebugSession::new(vm, &program);
+    let stdin = io::stdin();
+    if let Err(e) = repl(&mut session, stdin.lock(), io::stdout()) {
+        fail(&e.to_string());
+    }
+}
+
+fn print_summary(vm: &MixVM, info: &RunInfo) {
+    eprintln!("---");
+    match info.halt() {
+        Some(halt) => eprintln!("halted at {:04} (M = {})", halt.pc, halt.address),
+        None => eprintln!("stopped at {:04}", vm.pc()),
+    }
+    eprintln!(
+        "executed {} instructions in {}u",
+        info.count_execs(0, 3999).unwrap(),
+        info.count_clocks(0, 3999).unwrap()
+    );
+    eprintln!("clock {}u", vm.clock());
+}
+
+fn run(options: &Options, trace: bool) {
+    let (mut vm, object) = setup(options);
+    let disasm = Disassembler::with_symbols(&object.symbols);
+    let mut info = RunInfo::new();
+    let mut graph = CallGraph::new(&vm, &object.symbols);
+    let mut tracer = Tracer::new(disasm.clone());
+    let mut recorded = vec![];
+    let mut steps = 0;
+    loop {
+        if options.max_steps.is_some_and(|max| steps >= max) {
+            break;
+        }
+        steps += 1;
+        let clock = vm.clock();
+        let result = if options.record.is_some() {
+            tracer.step(&mut vm).map(|(outcome, step)| {
+                if options.filter.matches(&step) {
+                    recorded.push(step);
+                }
+                outcome
+            })
+        } else {
+            vm.step()
+        };
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(fault) => {
+                write_record(options, &recorded);
+                write_output(&options.printer, &vm.print(18));
+                eprintln!("mix: {}", fault);
+                process::exit(2);
+            }
+        };
+        let (pc, inst, halted) = match outcome {
+            StepOutcome::Stepped(pc, inst) => (pc, inst, false),
+            StepOutcome::Halted(pc, inst) => (pc, inst, true),
+        };
+        let clock = (vm.clock() - clock) as usize;
+        if pc >= 0 {
+            info.update(pc as usize, clock);
+        }
+        graph.update(&vm, pc, &inst, clock);
+        if trace {
+            eprintln!(
+                "{:04} {} {:<16} rA={} rX={} clock={}",
+                pc,
+                inst,
+                disasm.word(&inst),
+                vm.reg_a().val(),
+                vm.reg_x().val(),
+                vm.clock()
+            );
+        }
+        if halted {
+            info.set_halt(vm.halt_info().cloned());
+            break;
+        }
+    }
+
+    write_record(options, &recorded);
+    write_output(&options.printer, &vm.print(18));
+    let punched = vm.print(17);
+    if !punched.is_empty() {
+        write_output(&options.punch, &punched);
+    }
+    print_summary(&vm, &info);
+    if let Some(path) = &options.profile {
+        write_profile(path, options, &info);
+    }
+    if let Some(path) = &options.calls {
+        let text = if options.dot {
+            graph.dot()
+        } else if options.folded {
+            graph.folded()
+        } else {
+            graph.report()
+        };
+        write_output(&Some(path.clone()), &text);
+    }
+}
+
+fn write_record(options: &Options, steps: &[TraceStep]) {
+    if let Some(path) = &options.record {
+        let mut text = vec![];
+        tools::write_trace(steps, &mut text).unwrap();
+        write_output(&Some(path.clone()), &String::from_utf8(text).unwrap());
+    }
+}
+
+fn read_trace_file(path: &str) -> Vec<TraceStep> {
+    match tools::read_trace(read_file(path).as_bytes()) {
+        Ok(steps) => steps,
+        Err((line, message)) => fail(&format!("{}:{}: {}", path, line, message)),
+    }
+}
+
+// 2 つの記録で最初に食い違う命令を表示する. 食い違えば終了コードは 1
+fn diff_traces(options: &Options) {
+    if options.modules.len() != 1 {
+        fail("trace-diff expects two trace files");
+    }
+    let a = read_trace_file(&options.source);
+    let b = read_trace_file(&options.modules[0]);
+    match tools::trace_diff(&a, &b) {
+        Some(k) => {
+            let line = |steps: &[TraceStep]| match steps.get(k) {
+                Some(step) => step.to_string(),
+                None => "(end of trace)".to_string(),
+            };
+            println!("traces differ at entry {}", k + 1);
+            println!("< {}", line(&a));
+            println!("> {}", line(&b));
+            process::exit(1);
+        }
+        None => println!("traces match ({} steps)", a.len()),
+    }
+}
+
+// 実行回数をソースの行に並べる. オブジェクトファイルには行の対応しかないので, ソースが要る
+fn write_profile(path: &str, options: &Options, info: &RunInfo) {
+    let code = read_file(&options.source);
+    if code.starts_with("MIX OBJECT") {
+        fail("--profile needs a MIXAL source");
+    }
+    let program = assemble_file(&options.source, options.format(), false);
+    let text = if options.csv {
+        tools::profile_csv(&program, info)
+    } else {
+        tools::profile(&code, &program, info)
+    };
+    write_output(&Some(path.to_string()), &text);
+}
+
+fn assemble(options: &Options) {
+    let program = assemble_file(&options.source, options.format(), options.module);
+    if options.listing {
+        print!("{}", asm::listing(&read_file(&options.source), &program));
+        return;
+    }
+    if let Some(path) = &options.deck {
+        match asm::loading_deck(program.entry_point, &program.binary) {
+            Ok(deck) => write_output(&Some(path.clone()), &(deck.join("\n") + "\n")),
+            Err(e) => fail(&format!("{}: {}", options.source, e)),
+        }
+        return;
+    }
+    if let Some(path) = &options.object {
+        let mut object = ObjectFile::new(&program);
+        if options.strip {
+            object.symbols.clear();
+            object.line_address.clear();
+        }
+        write_object(&Some(path.clone()), &object);
+        return;
+    }
+    println!("entry {:04}", program.entry_point);
+    for (address, word) in program.binary.iter() {
+        println!("{:04} {}", address, word);
+    }
+}
+
+fn write_object(path: &Option<String>, object: &ObjectFile) {
+    let mut text = vec![];
+    object.write(&mut text).unwrap();
+    write_output(path, &String::from_utf8(text).unwrap());
+}
+
+// モジュールをつなげて 1 つのオブジェクトファイルにする. ソースはモジュールとしてアセンブルする
+fn link_files(options: &Options) {
+    let modules: Vec<(String, ObjectFile)> = Some(&options.source)
+        .into_iter()
+        .chain(options.modules.iter())
+        .map(|path| (path.clone(), load_file(path, options.format(), true)))
+        .collect();
+    match link::link(&modules, options.origin.unwrap_or(0)) {
+        Ok(linked) => write_object(&options.object, &linked),
+        Err(errors) => {
+            for e in errors.iter() {
+                eprintln!("mix: {}", e);
+            }
+            process::exit(1);
+        }
+    }
+}
+
 fn main() {
-    println!("Hello World");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+    match command {
+        "assemble" => assemble(&single_file(parse_options(&args[1..]))),
+        "link" => link_files(&parse_options(&args[1..])),
+        "run" => run(&single_file(parse_options(&args[1..])), false),
+        "trace" => run(&single_file(parse_options(&args[1..])), true),
+        "debug" => debug(&single_file(parse_options(&args[1..]))),
+        "trace-diff" => diff_traces(&parse_options(&args[1..])),
+        "help" | "--help" | "-h" => println!("{}", USAGE),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    }
 }
@@ -75,6 +75,9 @@ impl WordImpl {
     pub fn len(&self) -> usize {
         self.bytes.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
     pub fn val(&self) -> i64 {
         let mut sum = 0;
         for x in &self.bytes {
@@ -157,6 +160,7 @@ impl WordImpl {
         *self = WordImpl::from_val(self.val() + 1);
     }
     pub fn shift_left(&mut self, n: usize) {
+        let n = n.min(self.len());
         for i in 0..(self.len() - n) {
             self.bytes[i] = self.bytes[i + n];
         }
@@ -165,6 +169,7 @@ impl WordImpl {
         }
     }
     pub fn shift_right(&mut self, n: usize) {
+        let n = n.min(self.len());
         for i in (n..self.len()).rev() {
             self.bytes[i] = self.bytes[i - n];
         }
@@ -204,6 +209,34 @@ impl WordImpl {
     }
 }
 
+// 語を +b.b.b.b.b の形の文字列にする. スナップショットとオブジェクトファイルで使う.
+pub(crate) fn word_to_str(word: &WordImpl) -> String {
+    let sign = match word.sign() {
+        Sign::Positive => "+",
+        Sign::Negative => "-",
+    };
+    let bytes: Vec<String> = (0..5)
+        .map(|i| word.byte(i).unwrap().val().to_string())
+        .collect();
+    format!("{}{}", sign, bytes.join("."))
+}
+
+pub(crate) fn str_to_word(s: &str) -> Option<WordImpl> {
+    let sign = match s.chars().next()? {
+        '+' => Sign::Positive,
+        '-' => Sign::Negative,
+        _ => return None,
+    };
+    let bytes = s[1..]
+        .split('.')
+        .map(|b| b.parse::<u32>().ok().filter(|b| *b < Byte::max()))
+        .collect::<Option<Vec<u32>>>()?;
+    if bytes.len() != 5 {
+        return None;
+    }
+    Some(WordImpl::from_seq(sign, &bytes))
+}
+
 impl std::fmt::Display for WordImpl {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let s = match self.sign {
@@ -0,0 +1,309 @@
+// アセンブル済みのプログラムを行単位のテキストとして保存, 復元する.
+//
+// MIX OBJECT 1
+// byte 64
+// entry 3000
+// seg 3000 +0.0.0.0.1 ...     (先頭の番地と, そこから連続する語. 1 行 8 語まで)
+// sym START 3000              (シンボルテーブル. 省略できる)
+// line 5 3000                 (ソースの行番号 (1 始まり) と番地. 省略できる)
+// module 120                  (再配置できるモジュール. 0..120 番地を使う)
+// reloc 5 address             (配置したときの語の直し方. address, value, extern NAME)
+// export MAX 0 rel            (ENTRY で公開したシンボル. rel なら配置した位置だけずれる)
+// linkage MAX 3010            (JMP MAX で呼ぶサブルーチン. rJ を 3010 番地の (0:2) に保存し,
+//                              そこから戻る)
+// end
+use crate::asm::{AssembledProgram, Relocation};
+use crate::mix_word::{str_to_word, word_to_str, Byte, WordImpl};
+use crate::vm::Loadable;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+const HEADER: &str = "MIX OBJECT 1";
+const WORDS_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectFile {
+    pub entry_point: usize,
+    // 先頭の番地と, そこから連続する語. 番地の昇順で重ならない
+    pub segments: Vec<(usize, Vec<WordImpl>)>,
+    pub symbols: BTreeMap<String, i64>,
+    // 行番号 (0 始まり) から番地
+    pub line_address: BTreeMap<usize, usize>,
+    // 再配置できるモジュールなら使う番地の範囲 (0..size)
+    pub module: Option<usize>,
+    pub relocations: BTreeMap<usize, Relocation>,
+    // ENTRY で公開したシンボルの値と, 配置した位置だけずれるかどうか
+    pub exports: BTreeMap<String, (i64, bool)>,
+    // rJ で呼ばれるサブルーチンの入口と, 戻り番地を保存する語の番地
+    pub linkage: BTreeMap<String, usize>,
+}
+
+#[derive(Debug)]
+pub enum ObjectError {
+    Io(io::Error),
+    // 読めなかった行 (1 始まり) と理由
+    Parse(usize, String),
+    // 書き出したときと Byte::max() が異なる
+    ByteSize(u32),
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectError::Io(e) => write!(f, "{}", e),
+            ObjectError::Parse(line, message) => write!(f, "line {}: {}", line, message),
+            ObjectError::ByteSize(size) => write!(
+                f,
+                "object uses byte size {} but the machine uses {}",
+                size,
+                Byte::max()
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for ObjectError {
+    fn from(e: io::Error) -> Self {
+        ObjectError::Io(e)
+    }
+}
+
+// 番地と語の並びを連続する区間にまとめる. 同じ番地は後の語で上書きする.
+fn to_segments(binary: &[(usize, WordImpl)]) -> Vec<(usize, Vec<WordImpl>)> {
+    let memory: BTreeMap<usize, &WordImpl> = binary
+        .iter()
+        .map(|(address, word)| (*address, word))
+        .collect();
+    let mut segments: Vec<(usize, Vec<WordImpl>)> = vec![];
+    for (address, word) in memory {
+        match segments.last_mut() {
+            Some((origin, words)) if *origin + words.len() == address => words.push(word.clone()),
+            _ => segments.push((address, vec![word.clone()])),
+        }
+    }
+    segments
+}
+
+// 元のソースの行から番地. マクロの呼び出しなど複数の語になる行は最初の番地
+fn source_line_address(program: &AssembledProgram) -> BTreeMap<usize, usize> {
+    let mut line_address = BTreeMap::new();
+    for (index, address) in program.line_address.iter() {
+        let first = line_address
+            .entry(program.source[*index].line)
+            .or_insert(*address);
+        *first = (*first).min(*address);
+    }
+    line_address
+}
+
+impl ObjectFile {
+    // シンボルテーブルと行の対応を含めて作る. 不要なら clear すればよい.
+    pub fn new(program: &AssembledProgram) -> Self {
+        ObjectFile {
+            entry_point: program.entry_point,
+            segments: to_segments(&program.binary),
+            symbols: program.symbols.clone(),
+            line_address: source_line_address(program),
+            module: if program.relocatable {
+                Some(program.size)
+            } else {
+                None
+            },
+            relocations: program.relocations.clone(),
+            exports: program.exports.clone(),
+            linkage: BTreeMap::new(),
+        }
+    }
+    pub fn from_binary(entry_point: usize, binary: &[(usize, WordImpl)]) -> Self {
+        ObjectFile {
+            entry_point,
+            segments: to_segments(binary),
+            ..ObjectFile::default()
+        }
+    }
+    // 番地と語の並び. MixVM::load や Disassembler::dump に渡せる.
+    pub fn binary(&self) -> Vec<(usize, WordImpl)> {
+        self.segments
+            .iter()
+            .flat_map(|(origin, words)| {
+                words
+                    .iter()
+                    .enumerate()
+                    .map(move |(k, word)| (origin + k, word.clone()))
+            })
+            .collect()
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "{}", HEADER)?;
+        writeln!(w, "byte {}", Byte::max())?;
+        writeln!(w, "entry {}", self.entry_point)?;
+        for (origin, words) in self.segments.iter() {
+            for (k, chunk) in words.chunks(WORDS_PER_LINE).enumerate() {
+                let chunk: Vec<String> = chunk.iter().map(word_to_str).collect();
+                writeln!(w, "seg {} {}", origin + k * WORDS_PER_LINE, chunk.join(" "))?;
+            }
+        }
+        for (symbol, value) in self.symbols.iter() {
+            writeln!(w, "sym {} {}", symbol, value)?;
+        }
+        for (line, address) in self.line_address.iter() {
+            writeln!(w, "line {} {}", line + 1, address)?;
+        }
+        if let Some(size) = self.module {
+            writeln!(w, "module {}", size)?;
+        }
+        for (address, relocation) in self.relocations.iter() {
+            match relocation {
+                Relocation::Address => writeln!(w, "reloc {} address", address)?,
+                Relocation::Value => writeln!(w, "reloc {} value", address)?,
+                Relocation::External(name) => writeln!(w, "reloc {} extern {}", address, name)?,
+            }
+        }
+        for (name, (value, relocatable)) in self.exports.iter() {
+            let kind = if *relocatable { "rel" } else { "abs" };
+            writeln!(w, "export {} {} {}", name, value, kind)?;
+        }
+        for (name, exit) in self.linkage.iter() {
+            writeln!(w, "linkage {} {}", name, exit)?;
+        }
+        writeln!(w, "end")
+    }
+
+    // write で書き出したオブジェクトを読み込む
+    pub fn read<R: BufRead>(r: R) -> Result<ObjectFile, ObjectError> {
+        let mut entry_point = None;
+        let mut binary = vec![];
+        let mut symbols = BTreeMap::new();
+        let mut line_address = BTreeMap::new();
+        let mut object = ObjectFile::default();
+        let mut header = false;
+        let mut end = false;
+
+        for (n, line) in r.lines().enumerate() {
+            let line = line?;
+            let n = n + 1;
+            let error = |message: &str| ObjectError::Parse(n, message.to_string());
+            if !header {
+                if line.trim() != HEADER {
+                    return Err(error("not a MIX object"));
+                }
+                header = true;
+                continue;
+            }
+            let terms: Vec<&str> = line.split_whitespace().collect();
+            if terms.is_empty() {
+                continue;
+            }
+            if end {
+                return Err(error("unexpected line after end"));
+            }
+            let int = |i: usize| -> Result<i64, ObjectError> {
+                terms
+                    .get(i)
+                    .and_then(|t| t.parse::<i64>().ok())
+                    .ok_or_else(|| error("expected an integer"))
+            };
+            // 0..=3999 の番地
+            let address = |i: usize| -> Result<usize, ObjectError> {
+                match int(i)? {
+                    a if (0..4000).contains(&a) => Ok(a as usize),
+                    _ => Err(error("address out of range")),
+                }
+            };
+
+            match terms[0] {
+                "byte" => {
+                    let size = int(1)? as u32;
+                    if size != Byte::max() {
+                        return Err(ObjectError::ByteSize(size));
+                    }
+                }
+                "entry" => entry_point = Some(address(1)?),
+                "seg" => {
+                    let origin = address(1)?;
+                    if terms.len() < 3 || origin + terms.len() - 2 > 4000 {
+                        return Err(error("address out of range"));
+                    }
+                    for (k, t) in terms[2..].iter().enumerate() {
+                        let word = str_to_word(t).ok_or_else(|| error("expected a word"))?;
+                        binary.push((origin + k, word));
+                    }
+                }
+                "sym" => {
+                    let symbol = terms.get(1).ok_or_else(|| error("expected a symbol"))?;
+                    symbols.insert(symbol.to_string(), int(2)?);
+                }
+                "line" => {
+                    let line = int(1)?;
+                    if line < 1 {
+                        return Err(error("line numbers start at 1"));
+                    }
+                    line_address.insert(line as usize - 1, address(2)?);
+                }
+                "module" => match int(1)? {
+                    size if (0..=4000).contains(&size) => object.module = Some(size as usize),
+                    _ => return Err(error("module size out of range")),
+                },
+                "reloc" => {
+                    let relocation = match (terms.get(2), terms.get(3)) {
+                        (Some(&"address"), _) => Relocation::Address,
+                        (Some(&"value"), _) => Relocation::Value,
+                        (Some(&"extern"), Some(name)) => Relocation::External(name.to_string()),
+                        _ => return Err(error("expected address, value or extern NAME")),
+                    };
+                    object.relocations.insert(address(1)?, relocation);
+                }
+                "export" => {
+                    let name = terms.get(1).ok_or_else(|| error("expected a symbol"))?;
+                    let relocatable = match terms.get(3) {
+                        Some(&"rel") => true,
+                        Some(&"abs") => false,
+                        _ => return Err(error("expected rel or abs")),
+                    };
+                    object
+                        .exports
+                        .insert(name.to_string(), (int(2)?, relocatable));
+                }
+                "linkage" => {
+                    let name = terms.get(1).ok_or_else(|| error("expected a symbol"))?;
+                    object.linkage.insert(name.to_string(), address(2)?);
+                }
+                "end" => end = true,
+                _ => return Err(error("unknown item")),
+            }
+        }
+        if !end {
+            return Err(ObjectError::Parse(0, "missing end".to_string()));
+        }
+        let entry_point =
+            entry_point.ok_or_else(|| ObjectError::Parse(0, "missing entry".to_string()))?;
+
+        Ok(ObjectFile {
+            entry_point,
+            segments: to_segments(&binary),
+            symbols,
+            line_address,
+            ..object
+        })
+    }
+}
+
+impl Loadable for ObjectFile {
+    fn binary(&self) -> Vec<(usize, WordImpl)> {
+        ObjectFile::binary(self)
+    }
+    fn entry_point(&self) -> Option<usize> {
+        Some(self.entry_point)
+    }
+}
+
+impl Loadable for AssembledProgram {
+    fn binary(&self) -> Vec<(usize, WordImpl)> {
+        self.binary.clone()
+    }
+    fn entry_point(&self) -> Option<usize> {
+        Some(self.entry_point)
+    }
+}
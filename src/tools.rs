@@ -1,56 +1,245 @@
-use crate::vm::MixVM;
+use crate::common::CHAR_TABLE;
+use crate::vm::{HaltInfo, Location, MixFault, MixVM, StepOutcome, WordImpl};
+use std::collections::BTreeMap;
 
-pub fn run(vm: &mut MixVM) -> Result<RunInfo, ()> {
+mod callgraph;
+mod profile;
+mod repl;
+mod trace;
+pub use callgraph::{profile_calls, CallGraph, Routine};
+pub use profile::{profile, profile_csv};
+pub use repl::{repl, DebugSession};
+pub use trace::{
+    read_trace, trace, trace_diff, write_trace, Change, TraceFilter, TraceStep, Tracer,
+};
+
+pub fn run(vm: &mut MixVM) -> Result<RunInfo, MixFault> {
     let mut info = RunInfo::new();
-    let mut clock = 0;
+    let mut clock = vm.clock();
     loop {
         match vm.step() {
-            Ok((pc, _inst)) => {
+            Ok(StepOutcome::Stepped(pc, _inst)) => {
                 let current_clock = vm.clock();
                 let clock_diff = (current_clock - clock) as usize;
                 clock = current_clock;
-                info.update(pc, clock_diff);
+                info.update_pc(pc, clock_diff);
             }
-            Err(()) => {
+            Ok(StepOutcome::Halted(pc, _inst)) => {
                 // REACH HLT
-                // TODO: get more info.
+                let current_clock = vm.clock();
+                let clock_diff = (current_clock - clock) as usize;
+                info.update_pc(pc, clock_diff);
+                info.halt = vm.halt_info().cloned();
                 break;
             }
+            Err(fault) => {
+                return Err(fault);
+            }
         }
     }
 
     Ok(info)
 }
 
-pub fn debug_run(vm: &mut MixVM) -> Result<(Vec<usize>, RunInfo), ()> {
+// GO ボタンを押して, カードから読み込んだプログラムを HLT まで実行する
+pub fn boot(vm: &mut MixVM) -> Result<RunInfo, MixFault> {
+    if let Err(kind) = vm.press_go() {
+        return Err(MixFault {
+            pc: vm.pc(),
+            inst: WordImpl::from_val(0),
+            kind,
+        });
+    }
+    run(vm)
+}
+
+pub fn debug_run(vm: &mut MixVM) -> Result<(Vec<i64>, RunInfo), MixFault> {
     let mut info = RunInfo::new();
     let mut ret = vec![];
-    let mut clock = 0;
+    let mut clock = vm.clock();
     loop {
         // for _i in 0..100 {
         match vm.step() {
-            Ok((pc, _inst)) => {
+            Ok(StepOutcome::Stepped(pc, _inst)) => {
                 ret.push(pc);
                 let current_clock = vm.clock();
                 let clock_diff = (current_clock - clock) as usize;
                 clock = current_clock;
-                info.update(pc, clock_diff);
+                info.update_pc(pc, clock_diff);
             }
-            Err(()) => {
+            Ok(StepOutcome::Halted(pc, _inst)) => {
                 // REACH HLT
-                // TODO: get more info.
+                ret.push(pc);
+                let current_clock = vm.clock();
+                let clock_diff = (current_clock - clock) as usize;
+                info.update_pc(pc, clock_diff);
+                info.halt = vm.halt_info().cloned();
                 break;
             }
+            Err(fault) => {
+                return Err(fault);
+            }
         }
     }
 
     Ok((ret, info))
 }
 
+// テキストの 1 行を 5 文字ずつの語に分け, block_size 語の入力ブロックにする.
+// 足りない分は空白で埋める. MIX の文字でないものや長すぎる行はエラー.
+pub fn text_to_block(line: &str, block_size: usize) -> Result<Vec<String>, String> {
+    let mut chars: Vec<char> = line.trim_end_matches(&['\r', '\n'][..]).chars().collect();
+    if let Some(c) = chars.iter().find(|c| !CHAR_TABLE.contains(c)) {
+        return Err(format!("'{}' is not a MIX character", c));
+    }
+    if chars.len() > 5 * block_size {
+        return Err(format!("line is longer than {} characters", 5 * block_size));
+    }
+    chars.resize(5 * block_size, ' ');
+    Ok(chars
+        .chunks(5)
+        .map(|word| word.iter().collect::<String>())
+        .collect())
+}
+
+// ウォッチポイントが見張るアクセスの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+// Debugger::run_until が止まった理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    // ブレークポイントの番地. その番地の命令はまだ実行していない.
+    Breakpoint(i64),
+    // ウォッチポイントの番号, 実際のアクセス (Read か Write), 場所, 命令の番地
+    Watchpoint(usize, WatchKind, Location, i64),
+    // 条件の番号. 条件を満たした直後で止まる.
+    Condition(usize),
+    // HLT に到達した
+    Halted(HaltInfo),
+}
+
+type Condition = Box<dyn Fn(&MixVM) -> bool>;
+
+// MixVM を実行しながらブレークポイント, ウォッチポイント, 条件で止める
+#[derive(Default)]
+pub struct Debugger {
+    // 条件付きのブレークポイントは条件を満たすときだけ止まる
+    breakpoints: BTreeMap<i64, Option<Condition>>,
+    watchpoints: Vec<Option<(Location, WatchKind)>>,
+    conditions: Vec<Option<Condition>>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+    // 既に設定されていれば false
+    pub fn add_breakpoint(&mut self, address: i64) -> bool {
+        self.breakpoints.insert(address, None).is_none()
+    }
+    // condition を満たすときだけ止まるブレークポイント
+    pub fn add_conditional_breakpoint<F>(&mut self, address: i64, condition: F) -> bool
+    where
+        F: Fn(&MixVM) -> bool + 'static,
+    {
+        self.breakpoints
+            .insert(address, Some(Box::new(condition)))
+            .is_none()
+    }
+    // 設定されていなければ false
+    pub fn remove_breakpoint(&mut self, address: i64) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+    pub fn breakpoints(&self) -> impl Iterator<Item = &i64> {
+        self.breakpoints.keys()
+    }
+    // ウォッチポイントの番号を返す
+    pub fn add_watchpoint(&mut self, location: Location, kind: WatchKind) -> usize {
+        self.watchpoints.push(Some((location, kind)));
+        self.watchpoints.len() - 1
+    }
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        match self.watchpoints.get_mut(id) {
+            Some(watchpoint) => watchpoint.take().is_some(),
+            None => false,
+        }
+    }
+    // 各 step の後に評価する条件を加え, その番号を返す
+    pub fn add_condition<F>(&mut self, condition: F) -> usize
+    where
+        F: Fn(&MixVM) -> bool + 'static,
+    {
+        self.conditions.push(Some(Box::new(condition)));
+        self.conditions.len() - 1
+    }
+    pub fn remove_condition(&mut self, id: usize) -> bool {
+        match self.conditions.get_mut(id) {
+            Some(condition) => condition.take().is_some(),
+            None => false,
+        }
+    }
+    // いずれかの停止条件を満たすまで実行する.
+    // 最初の命令はブレークポイントがあっても実行するので, 止まった場所から続けて呼べる.
+    pub fn run_until(&self, vm: &mut MixVM) -> Result<Stop, MixFault> {
+        let mut first = true;
+        loop {
+            if !first {
+                match self.breakpoints.get(&vm.pc()) {
+                    Some(None) => return Ok(Stop::Breakpoint(vm.pc())),
+                    Some(Some(condition)) if condition(vm) => {
+                        return Ok(Stop::Breakpoint(vm.pc()));
+                    }
+                    _ => {}
+                }
+            }
+            first = false;
+
+            let pc = match vm.step()? {
+                StepOutcome::Stepped(pc, _inst) => pc,
+                StepOutcome::Halted(_pc, _inst) => {
+                    return Ok(Stop::Halted(vm.halt_info().cloned().unwrap()));
+                }
+            };
+            if let Some(stop) = self.check_watchpoints(vm, pc) {
+                return Ok(stop);
+            }
+            for (id, condition) in self.conditions.iter().enumerate() {
+                if let Some(condition) = condition {
+                    if condition(vm) {
+                        return Ok(Stop::Condition(id));
+                    }
+                }
+            }
+        }
+    }
+    fn check_watchpoints(&self, vm: &MixVM, pc: i64) -> Option<Stop> {
+        let access = vm.last_access();
+        for (id, watchpoint) in self.watchpoints.iter().enumerate() {
+            let (location, kind) = match watchpoint {
+                Some(watchpoint) => *watchpoint,
+                None => continue,
+            };
+            if kind != WatchKind::Read && access.write.contains(&location) {
+                return Some(Stop::Watchpoint(id, WatchKind::Write, location, pc));
+            }
+            if kind != WatchKind::Write && access.read.contains(&location) {
+                return Some(Stop::Watchpoint(id, WatchKind::Read, location, pc));
+            }
+        }
+        None
+    }
+}
+
 #[derive(Debug)]
 pub struct RunInfo {
     exec: Vec<usize>,
     clock: Vec<usize>,
+    halt: Option<HaltInfo>,
 }
 
 impl RunInfo {
@@ -58,12 +247,26 @@ impl RunInfo {
         RunInfo {
             exec: vec![0; 4000],
             clock: vec![0; 4000],
+            halt: None,
         }
     }
     pub fn update(&mut self, address: usize, clock: usize) {
         self.exec[address] += 1;
         self.clock[address] += clock;
     }
+    // 負の番地 (割り込みハンドラなど制御状態のコード) は集計しない
+    fn update_pc(&mut self, pc: i64, clock: usize) {
+        if pc >= 0 {
+            self.update(pc as usize, clock);
+        }
+    }
+    // 最後に到達した HLT の情報
+    pub fn halt(&self) -> Option<&HaltInfo> {
+        self.halt.as_ref()
+    }
+    pub fn set_halt(&mut self, halt: Option<HaltInfo>) {
+        self.halt = halt;
+    }
     pub fn count_exec(&self, address: usize) -> usize {
         self.exec[address]
     }
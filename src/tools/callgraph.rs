@@ -0,0 +1,230 @@
+// サブルーチン単位の解析. TAOCP 1.4.1 の流儀で rJ を使って呼ぶサブルーチンを追いかけ,
+// サブルーチンごとの時間と, 呼び出しの関係を集計する.
+//
+// 飛び越し命令 (JSJ 以外は rJ を設定する) で STJ から始まる番地に移ったときを呼び出し,
+// 飛び越しでその呼び出しの次の番地 (rJ の値) に戻ったときを戻りとみなす.
+// 戻らずに外へ飛んだサブルーチンは, 呼び出し元に戻ったときにまとめて終わる.
+use super::RunInfo;
+use crate::vm::{MixFault, MixVM, StepOutcome, WordImpl};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Routine {
+    pub calls: usize,
+    // 自分の命令の時間と, 呼んだサブルーチンを含む時間 (u)
+    pub exclusive: usize,
+    pub inclusive: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    names: HashMap<usize, String>,
+    // 入口の番地ごとの集計. 最初の番地は主プログラム
+    root: usize,
+    routines: BTreeMap<usize, Routine>,
+    // 呼んだ側と呼ばれた側の入口から, 呼んだ回数と呼ばれた側で使った時間
+    edges: BTreeMap<(usize, usize), (usize, usize)>,
+    // 呼び出しの列 (主プログラムから) ごとの自分の時間
+    stacks: BTreeMap<Vec<usize>, usize>,
+    // 実行中のサブルーチンの入口と戻り番地
+    frames: Vec<(usize, i64)>,
+}
+
+// rJ を設定する飛び越し命令か
+fn is_jump(inst: &WordImpl) -> bool {
+    match inst.operation() {
+        39 => inst.modification() != 1,
+        40..=47 => true,
+        _ => false,
+    }
+}
+
+impl CallGraph {
+    // vm の今の PC を主プログラムの入口とする. シンボルは入口の名前に使う
+    pub fn new(vm: &MixVM, symbols: &BTreeMap<String, i64>) -> Self {
+        let root = vm.pc().max(0) as usize;
+        let mut names = HashMap::new();
+        for (symbol, value) in symbols.iter() {
+            if *value >= 0 {
+                names
+                    .entry(*value as usize)
+                    .or_insert_with(|| symbol.clone());
+            }
+        }
+        let mut routines = BTreeMap::new();
+        routines.insert(
+            root,
+            Routine {
+                calls: 1,
+                ..Routine::default()
+            },
+        );
+        CallGraph {
+            names,
+            root,
+            routines,
+            edges: BTreeMap::new(),
+            stacks: BTreeMap::new(),
+            frames: vec![(root, -1)],
+        }
+    }
+
+    // vm.step() で pc の inst を実行し, clock だけ時間がかかった後に呼ぶ
+    pub fn update(&mut self, vm: &MixVM, pc: i64, inst: &WordImpl, clock: usize) {
+        // 時間は実行した命令のあるサブルーチンのもの. 再帰では 1 回だけ数える
+        let entries: Vec<usize> = self.frames.iter().map(|(entry, _)| *entry).collect();
+        let mut seen = BTreeSet::new();
+        for entry in entries.iter() {
+            if seen.insert(*entry) {
+                self.routine(*entry).inclusive += clock;
+            }
+        }
+        let mut seen = BTreeSet::new();
+        for pair in entries.windows(2) {
+            if seen.insert((pair[0], pair[1])) {
+                self.edges.entry((pair[0], pair[1])).or_default().1 += clock;
+            }
+        }
+        self.routine(*entries.last().unwrap()).exclusive += clock;
+        *self.stacks.entry(entries).or_default() += clock;
+
+        let next = vm.pc();
+        if pc < 0 || next == pc + 1 || !is_jump(inst) {
+            return;
+        }
+        // 戻り
+        if let Some(k) = self.frames.iter().rposition(|(_, ret)| *ret == next) {
+            self.frames.truncate(k);
+            return;
+        }
+        // 呼び出し
+        if vm.memory(next).is_some_and(|w| w.operation() == 32) {
+            let caller = self.frames.last().unwrap().0;
+            let callee = next as usize;
+            self.routine(callee).calls += 1;
+            self.edges.entry((caller, callee)).or_default().0 += 1;
+            self.frames.push((callee, pc + 1));
+        }
+    }
+
+    fn routine(&mut self, entry: usize) -> &mut Routine {
+        self.routines.entry(entry).or_default()
+    }
+    pub fn routines(&self) -> &BTreeMap<usize, Routine> {
+        &self.routines
+    }
+    pub fn edges(&self) -> &BTreeMap<(usize, usize), (usize, usize)> {
+        &self.edges
+    }
+    // 入口の名前. シンボルがなければ番地
+    pub fn name(&self, entry: usize) -> String {
+        match self.names.get(&entry) {
+            Some(name) => name.clone(),
+            None => format!("{:04}", entry),
+        }
+    }
+
+    // サブルーチンの表 (含む時間の順) と呼び出しの一覧
+    pub fn report(&self) -> String {
+        let total = self.routines[&self.root].inclusive.max(1);
+        let mut routines: Vec<(&usize, &Routine)> = self.routines.iter().collect();
+        routines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        let mut text = format!(
+            "{:<10} {:>5} {:>6} {:>8} {:>8} {:>6}\n",
+            "ROUTINE", "ENTRY", "CALLS", "SELF", "TOTAL", "%"
+        );
+        for (entry, routine) in routines {
+            text += &format!(
+                "{:<10} {:04} {:>6} {:>8} {:>8} {:>5.1}%\n",
+                self.name(*entry),
+                entry,
+                routine.calls,
+                routine.exclusive,
+                routine.inclusive,
+                routine.inclusive as f64 * 100.0 / total as f64
+            );
+        }
+        if !self.edges.is_empty() {
+            text += &format!(
+                "\n{:<10} {:<10} {:>6} {:>8}\n",
+                "CALLER", "CALLEE", "CALLS", "TIME"
+            );
+        }
+        for ((caller, callee), (calls, clock)) in self.edges.iter() {
+            text += &format!(
+                "{:<10} {:<10} {:>6} {:>8}\n",
+                self.name(*caller),
+                self.name(*callee),
+                calls,
+                clock
+            );
+        }
+        text
+    }
+
+    // Graphviz の DOT. 節はサブルーチン, 辺は呼び出し
+    pub fn dot(&self) -> String {
+        let total = self.routines[&self.root].inclusive.max(1);
+        let mut text = "digraph calls {\n    node [shape=box];\n".to_string();
+        for (entry, routine) in self.routines.iter() {
+            text += &format!(
+                "    \"{}\" [label=\"{}\\n{}u ({:.1}%)\\nself {}u\\n{} calls\"];\n",
+                self.name(*entry),
+                self.name(*entry),
+                routine.inclusive,
+                routine.inclusive as f64 * 100.0 / total as f64,
+                routine.exclusive,
+                routine.calls
+            );
+        }
+        for ((caller, callee), (calls, clock)) in self.edges.iter() {
+            text += &format!(
+                "    \"{}\" -> \"{}\" [label=\"{} calls\\n{}u\"];\n",
+                self.name(*caller),
+                self.name(*callee),
+                calls,
+                clock
+            );
+        }
+        text + "}\n"
+    }
+
+    // flamegraph.pl などが読む形式. 呼び出しの列を ; でつなぎ, 自分の時間を続ける
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .filter(|(_, clock)| **clock > 0)
+            .map(|(stack, clock)| {
+                let names: Vec<String> = stack.iter().map(|entry| self.name(*entry)).collect();
+                format!("{} {}\n", names.join(";"), clock)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+// run と同じく HLT まで実行し, サブルーチンの集計も返す
+pub fn profile_calls(
+    vm: &mut MixVM,
+    symbols: &BTreeMap<String, i64>,
+) -> Result<(RunInfo, CallGraph), MixFault> {
+    let mut info = RunInfo::new();
+    let mut graph = CallGraph::new(vm, symbols);
+    loop {
+        let clock = vm.clock();
+        let outcome = vm.step()?;
+        let clock = (vm.clock() - clock) as usize;
+        let (pc, inst, halted) = match outcome {
+            StepOutcome::Stepped(pc, inst) => (pc, inst, false),
+            StepOutcome::Halted(pc, inst) => (pc, inst, true),
+        };
+        info.update_pc(pc, clock);
+        graph.update(vm, pc, &inst, clock);
+        if halted {
+            info.set_halt(vm.halt_info().cloned());
+            return Ok((info, graph));
+        }
+    }
+}
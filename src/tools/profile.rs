@@ -0,0 +1,194 @@
+// 実行回数の解析. TAOCP のプログラムの解析のように, アセンブルしたソースの各行に
+// 実行回数と使った時間 (u) を並べ, 合計と時間のかかった行, 実行されなかった命令をまとめる.
+use super::RunInfo;
+use crate::asm::AssembledProgram;
+use std::collections::BTreeMap;
+
+// 時間のかかった行として表示する数
+const HOTTEST: usize = 5;
+
+// 語のある行. 元の行番号 (0 始まり), 展開した行か, 番地, 命令か (CON, ALF でない), ソース
+struct Row<'a> {
+    line: usize,
+    expanded: bool,
+    address: usize,
+    instruction: bool,
+    text: &'a str,
+}
+
+// 行の命令の部分. 注釈と空行は None
+fn operation(text: &str) -> Option<&str> {
+    if text.starts_with('*') {
+        return None;
+    }
+    let mut terms = text.split_whitespace();
+    if !text.starts_with(char::is_whitespace) {
+        terms.next();
+    }
+    terms.next()
+}
+
+fn rows(program: &AssembledProgram) -> Vec<Row<'_>> {
+    let mut rows: Vec<Row> = program
+        .source
+        .iter()
+        .enumerate()
+        .filter_map(|(index, s)| {
+            let address = *program.line_address.get(&index)?;
+            Some(Row {
+                line: s.line,
+                expanded: s.expanded,
+                address,
+                instruction: !matches!(operation(&s.text), Some("CON") | Some("ALF")),
+                text: &s.text,
+            })
+        })
+        .collect();
+    rows.sort_by_key(|row| (row.line, row.expanded));
+    rows
+}
+
+fn line_label(row: &Row) -> String {
+    if row.expanded {
+        "+".to_string()
+    } else {
+        (row.line + 1).to_string()
+    }
+}
+
+// 元のソースに実行回数と時間を並べ, 要約を付ける.
+// マクロの展開と INCLUDE した行は listing と同じく呼び出しの行の後に + として並べる.
+pub fn profile(code: &str, program: &AssembledProgram, info: &RunInfo) -> String {
+    let rows = rows(program);
+    let mut by_line: BTreeMap<usize, Vec<&Row>> = BTreeMap::new();
+    for row in rows.iter() {
+        by_line.entry(row.line).or_default().push(row);
+    }
+    let lines: Vec<&str> = code.lines().collect();
+
+    let format_row = |loc: String, count: String, clock: String, line: String, text: &str| {
+        format!(
+            "{:<4} {:>8} {:>8} {:>5}  {}\n",
+            loc,
+            count,
+            clock,
+            line,
+            text.trim_end()
+        )
+    };
+    let mut text = format_row(
+        "LOC".to_string(),
+        "COUNT".to_string(),
+        "TIME".to_string(),
+        "LINE".to_string(),
+        "SOURCE",
+    );
+    for (line, source) in lines.iter().enumerate() {
+        let mut original = true;
+        for row in by_line.get(&line).into_iter().flatten() {
+            let count = info.count_exec(row.address);
+            // データの語は実行されたときだけ数える
+            let (count, clock) = if row.instruction || count > 0 {
+                (count.to_string(), info.count_clock(row.address).to_string())
+            } else {
+                (String::new(), String::new())
+            };
+            let loc = format!("{:04}", row.address);
+            if row.expanded {
+                if original {
+                    text += &format_row(
+                        String::new(),
+                        String::new(),
+                        String::new(),
+                        (line + 1).to_string(),
+                        source,
+                    );
+                    original = false;
+                }
+                text += &format_row(loc, count, clock, line_label(row), row.text);
+            } else {
+                text += &format_row(loc, count, clock, line_label(row), source);
+                original = false;
+            }
+        }
+        if original {
+            text += &format_row(
+                String::new(),
+                String::new(),
+                String::new(),
+                (line + 1).to_string(),
+                source,
+            );
+        }
+    }
+
+    // 要約
+    let total = info.count_clocks(0, 3999).unwrap();
+    text += &format!(
+        "\ntotal {}u, {} instructions executed\n",
+        total,
+        info.count_execs(0, 3999).unwrap()
+    );
+    // 行ごとの時間. マクロの呼び出しは展開した語の合計, 回数は最大
+    let mut hottest: Vec<(usize, usize, usize)> = by_line
+        .iter()
+        .map(|(line, rows)| {
+            let count = rows.iter().map(|row| info.count_exec(row.address)).max();
+            let clock = rows.iter().map(|row| info.count_clock(row.address)).sum();
+            (*line, count.unwrap_or(0), clock)
+        })
+        .filter(|(_, _, clock)| *clock > 0)
+        .collect();
+    hottest.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+    if !hottest.is_empty() {
+        text += "\nhottest lines:\n";
+        text += &format!(
+            "{:>5} {:>8} {:>8} {:>6}  {}\n",
+            "LINE", "COUNT", "TIME", "%", "SOURCE"
+        );
+    }
+    for (line, count, clock) in hottest.into_iter().take(HOTTEST) {
+        text += &format!(
+            "{:>5} {:>8} {:>8} {:>5.1}%  {}\n",
+            line + 1,
+            count,
+            clock,
+            clock as f64 * 100.0 / total as f64,
+            lines.get(line).map_or("", |s| s.trim())
+        );
+    }
+    let never: Vec<&Row> = rows
+        .iter()
+        .filter(|row| row.instruction && info.count_exec(row.address) == 0)
+        .collect();
+    if !never.is_empty() {
+        text += "\nnever executed:\n";
+        text += &format!("{:>5} {:>4}  {}\n", "LINE", "LOC", "SOURCE");
+    }
+    for row in never {
+        text += &format!(
+            "{:>5} {:04}  {}\n",
+            row.line + 1,
+            row.address,
+            row.text.trim()
+        );
+    }
+    text
+}
+
+// 語のある行ごとに 1 行. 展開した行の line は呼び出しの行
+pub fn profile_csv(program: &AssembledProgram, info: &RunInfo) -> String {
+    let mut text = "line,expanded,address,count,time,source\n".to_string();
+    for row in rows(program) {
+        text += &format!(
+            "{},{},{},{},{},\"{}\"\n",
+            row.line + 1,
+            row.expanded,
+            row.address,
+            info.count_exec(row.address),
+            info.count_clock(row.address),
+            row.text.trim().replace('"', "\"\"")
+        );
+    }
+    text
+}
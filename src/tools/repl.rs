@@ -0,0 +1,430 @@
+// ソースレベルのデバッガ. コマンドを 1 行ずつ読んで MixVM を動かし, ソースの行と
+// シンボルを使って状態を表示する.
+use super::{Debugger, Stop};
+use crate::asm::{self, AsmError, AssembledProgram, SourceLine};
+use crate::disasm::Disassembler;
+use crate::vm::{MixVM, Sign, StepOutcome, WordImpl};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+use std::ops::RangeInclusive;
+
+const HELP: &str = "\
+step [N]       (s) execute N instructions
+next [N]       (n) like step, but run JMP subroutine calls until they return
+continue       (c) run until a breakpoint or HLT
+break LOC      (b) set a breakpoint at LOC: a line number, a label or expression, or *EXPR
+delete [LOC]   (d) delete the breakpoint at LOC, or all breakpoints
+info           list the breakpoints
+where          (w) show the current location
+list [LINE]    (l) show the source around the current location or LINE
+registers      (r) show the registers
+print EXPR     (p) evaluate a MIXAL expression (* is the current location)
+x EXPR [N]     show N words of memory from EXPR
+output         show the line printer output
+quit           (q) leave the debugger
+";
+// list で表示する行数
+const LIST_LINES: usize = 10;
+
+pub struct DebugSession {
+    vm: MixVM,
+    debugger: Debugger,
+    disassembler: Disassembler,
+    symbols: BTreeMap<String, i64>,
+    source: Vec<SourceLine>,
+    // 番地から, その語を生成した source の位置
+    address_source: HashMap<usize, usize>,
+    // 元のソースの行 (0 始まり) から最初の番地
+    line_address: BTreeMap<usize, usize>,
+    // プログラムが使う番地の範囲 (ORIG で空けた所も含む) と, その範囲を値とするシンボル
+    range: RangeInclusive<i64>,
+    labels: BTreeMap<i64, String>,
+}
+
+// 語の値を符号と 10 桁で書く
+fn format_value(word: &WordImpl, digits: usize) -> String {
+    let sign = match word.sign() {
+        Sign::Positive => '+',
+        Sign::Negative => '-',
+    };
+    format!("{}{:0width$}", sign, word.val().abs(), width = digits)
+}
+
+impl DebugSession {
+    // program を vm に読み込んで開始番地で止める. 装置の入力は vm に用意しておく.
+    pub fn new(mut vm: MixVM, program: &AssembledProgram) -> Self {
+        vm.load(program);
+        let address_source: HashMap<usize, usize> = program
+            .line_address
+            .iter()
+            .map(|(index, address)| (*address, *index))
+            .collect();
+        let mut line_address = BTreeMap::new();
+        for (address, index) in address_source.iter() {
+            let first = line_address
+                .entry(program.source[*index].line)
+                .or_insert(*address);
+            *first = (*first).min(*address);
+        }
+        // EQU 以外の行のラベルは番地を表す
+        let mut labels = BTreeMap::new();
+        for s in program.source.iter() {
+            if s.text.starts_with(|c: char| c.is_whitespace() || c == '*') {
+                continue;
+            }
+            let terms: Vec<&str> = s.text.split_whitespace().collect();
+            match (terms.first(), terms.get(1)) {
+                (Some(label), Some(op)) if *op != "EQU" => {
+                    if let Some(value) = program.symbols.get(*label) {
+                        labels.entry(*value).or_insert_with(|| label.to_string());
+                    }
+                }
+                _ => (),
+            }
+        }
+        let first = program.binary.iter().map(|(a, _)| *a as i64).min();
+        let first = labels.keys().next().copied().into_iter().chain(first).min();
+        let last = program.binary.iter().map(|(a, _)| *a as i64).max();
+        let range = first.unwrap_or(0)..=last.unwrap_or(-1);
+        DebugSession {
+            vm,
+            debugger: Debugger::new(),
+            disassembler: Disassembler::with_symbols(&program.symbols),
+            symbols: program.symbols.clone(),
+            source: program.source.clone(),
+            address_source,
+            line_address,
+            range,
+            labels,
+        }
+    }
+    // debug_assemble と同じく自由形式のソースをアセンブルして読み込む
+    pub fn from_source(code: &str) -> Result<Self, Vec<AsmError>> {
+        Ok(DebugSession::new(MixVM::new(), &asm::assemble(code)?))
+    }
+    pub fn vm(&self) -> &MixVM {
+        &self.vm
+    }
+    pub fn vm_mut(&mut self) -> &mut MixVM {
+        &mut self.vm
+    }
+
+    // コマンドを 1 つ実行して, 表示する文字列を返す
+    pub fn execute(&mut self, command: &str) -> Result<String, String> {
+        let mut terms = command.split_whitespace();
+        let name = terms.next().unwrap_or("");
+        let args: Vec<&str> = terms.collect();
+        // 式は空白を含んでもよい
+        let expression = args.concat();
+        let count = || match args.first() {
+            Some(n) => n
+                .parse::<usize>()
+                .map_err(|_| format!("invalid count {}", n)),
+            None => Ok(1),
+        };
+        match name {
+            "step" | "s" => self.step(count()?, false),
+            "next" | "n" => self.step(count()?, true),
+            "continue" | "c" => {
+                let stop = self
+                    .debugger
+                    .run_until(&mut self.vm)
+                    .map_err(|f| f.to_string())?;
+                Ok(self.report(Some(stop)))
+            }
+            "break" | "b" => {
+                let address = self.location(&expression)?;
+                if !self.debugger.add_breakpoint(address) {
+                    return Err(format!("breakpoint at {:04} already exists", address));
+                }
+                Ok(format!("breakpoint at {}\n", self.describe(address)))
+            }
+            "delete" | "d" if expression.is_empty() => {
+                let all: Vec<i64> = self.debugger.breakpoints().copied().collect();
+                for address in all.iter() {
+                    self.debugger.remove_breakpoint(*address);
+                }
+                Ok(format!("deleted {} breakpoints\n", all.len()))
+            }
+            "delete" | "d" => {
+                let address = self.location(&expression)?;
+                if !self.debugger.remove_breakpoint(address) {
+                    return Err(format!("no breakpoint at {:04}", address));
+                }
+                Ok(format!(
+                    "deleted breakpoint at {}\n",
+                    self.describe(address)
+                ))
+            }
+            "info" => Ok(self
+                .debugger
+                .breakpoints()
+                .map(|address| format!("breakpoint at {}\n", self.describe(*address)))
+                .collect()),
+            "where" | "w" => Ok(self.where_line()),
+            "list" | "l" => self.list(args.first()),
+            "registers" | "r" => Ok(self.registers()),
+            "print" | "p" => {
+                let value = self.evaluate(&expression)?;
+                match self.symbolic(value) {
+                    Some(symbol) if symbol != expression => Ok(format!("{} ({})\n", value, symbol)),
+                    _ => Ok(format!("{}\n", value)),
+                }
+            }
+            "x" => {
+                let begin = self.evaluate(args.first().ok_or("x needs an address")?)?;
+                let n = match args.get(1) {
+                    Some(n) => n
+                        .parse::<i64>()
+                        .ok()
+                        .filter(|n| *n >= 0)
+                        .ok_or_else(|| format!("invalid count {}", n))?,
+                    None => 1,
+                };
+                let end = begin
+                    .checked_add(n)
+                    .ok_or_else(|| format!("count {} is too large", n))?;
+                let mut text = String::new();
+                for address in begin..end {
+                    let word = self
+                        .vm
+                        .memory(address)
+                        .ok_or_else(|| format!("location {} is out of memory", address))?;
+                    text += &format!(
+                        "{:04} {:<10} {}  {}\n",
+                        address,
+                        self.labels.get(&address).map_or("", |s| s.as_str()),
+                        format_value(word, 10),
+                        self.disassembler.word(word)
+                    );
+                }
+                Ok(text)
+            }
+            "output" => Ok(self.vm.print(18)),
+            "help" | "h" => Ok(HELP.to_string()),
+            "" => Ok(String::new()),
+            _ => Err(format!("unknown command {} (try help)", name)),
+        }
+    }
+
+    // 命令を n 個実行する. over なら JMP で呼んだサブルーチンは rJ の戻り番地まで実行する
+    fn step(&mut self, n: usize, over: bool) -> Result<String, String> {
+        for _ in 0..n {
+            let pc = self.vm.pc();
+            let call = over
+                && self
+                    .vm
+                    .memory(pc)
+                    .is_some_and(|w| w.operation() == 39 && w.modification() == 0);
+            let stop = if call {
+                let id = self.debugger.add_condition(move |vm| vm.pc() == pc + 1);
+                let stop = self.debugger.run_until(&mut self.vm);
+                self.debugger.remove_condition(id);
+                stop.map_err(|f| f.to_string())?
+            } else {
+                match self.vm.step().map_err(|f| f.to_string())? {
+                    StepOutcome::Stepped(_, _) => continue,
+                    StepOutcome::Halted(_, _) => {
+                        Stop::Halted(self.vm.halt_info().cloned().unwrap())
+                    }
+                }
+            };
+            if !matches!(stop, Stop::Condition(_)) {
+                return Ok(self.report(Some(stop)));
+            }
+        }
+        Ok(self.report(None))
+    }
+
+    // 止まった理由と今の行
+    fn report(&self, stop: Option<Stop>) -> String {
+        let reason = match stop {
+            Some(Stop::Breakpoint(address)) => {
+                format!("breakpoint at {}\n", self.describe(address))
+            }
+            // 止まった後の PC は HLT の次なので, 行は表示しない
+            Some(Stop::Halted(halt)) => {
+                return format!("halted at {:04} (M = {})\n", halt.pc, halt.address);
+            }
+            _ => String::new(),
+        };
+        reason + &self.where_line()
+    }
+
+    // 番地の語を生成した source の位置
+    fn source_index(&self, address: i64) -> Option<&usize> {
+        if address < 0 {
+            return None;
+        }
+        self.address_source.get(&(address as usize))
+    }
+
+    // 今の番地, 命令, ソースの行 (マクロの展開は呼び出しの行番号)
+    fn where_line(&self) -> String {
+        let pc = self.vm.pc();
+        let inst = self
+            .vm
+            .memory(pc)
+            .map(|w| self.disassembler.word(w))
+            .unwrap_or_default();
+        match self.source_index(pc) {
+            Some(index) => {
+                let s = &self.source[*index];
+                format!(
+                    "{:04}  {:<20} {:>4}| {}\n",
+                    pc,
+                    inst,
+                    s.line + 1,
+                    s.text.trim_end()
+                )
+            }
+            None => format!("{:04}  {}\n", pc, inst),
+        }
+    }
+
+    fn list(&self, line: Option<&&str>) -> Result<String, String> {
+        let center = match line {
+            Some(line) => {
+                let line = line
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid line {}", line))?;
+                self.source
+                    .iter()
+                    .position(|s| s.line + 1 >= line)
+                    .ok_or_else(|| format!("line {} is past the end", line))?
+            }
+            None => *self.source_index(self.vm.pc()).ok_or("no source here")?,
+        };
+        let current = self.source_index(self.vm.pc());
+        let begin = center.saturating_sub(LIST_LINES / 2);
+        let mut text = String::new();
+        for (index, s) in self.source.iter().enumerate().skip(begin).take(LIST_LINES) {
+            let address = self
+                .address_source
+                .iter()
+                .find(|(_, i)| **i == index)
+                .map(|(a, _)| *a as i64);
+            let marker = match address {
+                _ if current == Some(&index) => "=>",
+                Some(a) if self.debugger.breakpoints().any(|b| *b == a) => " B",
+                _ => "  ",
+            };
+            let line = if s.expanded {
+                "+".to_string()
+            } else {
+                (s.line + 1).to_string()
+            };
+            text += &format!("{} {:>4}  {}\n", marker, line, s.text.trim_end());
+        }
+        Ok(text)
+    }
+
+    fn registers(&self) -> String {
+        let vm = &self.vm;
+        let mut text = format!("rA   {}\n", format_value(vm.reg_a(), 10));
+        text += &format!("rX   {}\n", format_value(vm.reg_x(), 10));
+        for k in 1..=6 {
+            let value = format_value(vm.reg_i(k), 4);
+            match self.labels.get(&vm.reg_i(k).val()) {
+                Some(label) => text += &format!("rI{}  {}  {}\n", k, value, label),
+                None => text += &format!("rI{}  {}\n", k, value),
+            }
+        }
+        let j = vm.reg_j().val();
+        text += &format!(
+            "rJ   {}  {}\n",
+            format_value(vm.reg_j(), 4),
+            self.symbolic(j).unwrap_or_default()
+        );
+        let comparison = match vm.comparison() {
+            Ordering::Less => "LESS",
+            Ordering::Equal => "EQUAL",
+            Ordering::Greater => "GREATER",
+        };
+        let overflow = if vm.overflow() { "ON" } else { "OFF" };
+        text += &format!(
+            "OV   {}  CI {}  clock {}u\n",
+            overflow,
+            comparison,
+            vm.clock()
+        );
+        text
+    }
+
+    // プログラムの番地なら, ラベルか直前のラベルからの距離 (LOOP+2)
+    fn symbolic(&self, value: i64) -> Option<String> {
+        if let Some(label) = self.labels.get(&value) {
+            return Some(label.clone());
+        }
+        if !self.range.contains(&value) {
+            return None;
+        }
+        self.labels
+            .range(..value)
+            .next_back()
+            .map(|(address, label)| format!("{}+{}", label, value - address))
+    }
+
+    // 番地とラベル, ソースの行
+    fn describe(&self, address: i64) -> String {
+        let mut text = format!("{:04}", address);
+        if let Some(symbol) = self.symbolic(address) {
+            text += &format!(" ({})", symbol);
+        }
+        if let Some(index) = self.source_index(address) {
+            text += &format!(", line {}", self.source[*index].line + 1);
+        }
+        text
+    }
+
+    fn evaluate(&self, expression: &str) -> Result<i64, String> {
+        asm::evaluate(expression, &self.symbols, self.vm.pc().max(0) as usize)
+    }
+
+    // ブレークポイントの場所. 行番号ならその行か, それより後で最初に語のある行の番地
+    fn location(&self, arg: &str) -> Result<i64, String> {
+        if arg.is_empty() {
+            return Err("missing location".to_string());
+        }
+        if let Some(expression) = arg.strip_prefix('*') {
+            return self.evaluate(expression);
+        }
+        if let Ok(line) = arg.parse::<usize>() {
+            return match self.line_address.range(line.saturating_sub(1)..).next() {
+                Some((_, address)) => Ok(*address as i64),
+                None => Err(format!("no code at or after line {}", line)),
+            };
+        }
+        self.evaluate(arg)
+    }
+}
+
+// コマンドを 1 行ずつ読んで実行する. 空行は直前のコマンドを繰り返す.
+pub fn repl<R: BufRead, W: Write>(
+    session: &mut DebugSession,
+    input: R,
+    mut output: W,
+) -> io::Result<()> {
+    write!(output, "{}(mix) ", session.where_line())?;
+    output.flush()?;
+    let mut last = String::new();
+    for line in input.lines() {
+        let line = line?;
+        let command = match line.trim() {
+            "" => last.clone(),
+            command => command.to_string(),
+        };
+        if command == "quit" || command == "q" {
+            return Ok(());
+        }
+        match session.execute(&command) {
+            Ok(text) => write!(output, "{}", text)?,
+            Err(message) => writeln!(output, "error: {}", message)?,
+        }
+        last = command;
+        write!(output, "(mix) ")?;
+        output.flush()?;
+    }
+    writeln!(output)
+}
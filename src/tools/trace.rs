@@ -0,0 +1,344 @@
+// 実行の記録. 1 命令ごとに番地, 命令, M, 変わったレジスタと表示器, 書いたメモリ, 時刻を残し,
+// 1 行 1 命令のテキストとして保存, 復元する.
+//
+// MIX TRACE 1
+// 3 1002 +15.47.0.0.39 m=1007 clock=3 rJ=+1003 | JMP ADD
+// 9 1005 +15.50.0.5.24 m=1010 clock=15 M[1010]=+6 | STA SUM
+//
+// 番号 (1 始まり), 番地, 語, M, 実行後の時刻, 変わった場所 (rA, rX, rI1..rI6, rJ, OV=0/1,
+// CI=L/E/G, M[番地]) の値, | の後に逆アセンブルした命令.
+use super::RunInfo;
+use crate::disasm::Disassembler;
+use crate::mix_word::{str_to_word, word_to_str};
+use crate::vm::{Location, MixFault, MixVM, Sign, StepOutcome, WordImpl};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+const HEADER: &str = "MIX TRACE 1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    // レジスタまたはメモリと, 書いた後の値
+    Word(Location, WordImpl),
+    Overflow(bool),
+    Comparison(Ordering),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub step: usize,
+    pub pc: i64,
+    pub word: WordImpl,
+    pub instruction: String,
+    pub m: i64,
+    pub clock: i64,
+    pub changes: Vec<Change>,
+}
+
+// 値を符号付きの整数で書く. -0 も区別する
+fn format_value(word: &WordImpl) -> String {
+    let sign = match word.sign() {
+        Sign::Positive => '+',
+        Sign::Negative => '-',
+    };
+    format!("{}{}", sign, word.val().abs())
+}
+
+fn parse_value(s: &str) -> Option<WordImpl> {
+    let sign = match s.chars().next()? {
+        '+' => Sign::Positive,
+        '-' => Sign::Negative,
+        _ => return None,
+    };
+    let val = s[1..].parse::<i64>().ok()?;
+    let mut word = WordImpl::from_val(val);
+    *word.sign_mut() = sign;
+    Some(word)
+}
+
+// レジスタの語も 5 バイトの語にそろえる
+fn normalize(word: &WordImpl) -> WordImpl {
+    let mut normalized = WordImpl::from_val(word.val().abs());
+    *normalized.sign_mut() = *word.sign();
+    normalized
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Word(Location::Memory(address), word) => {
+                write!(f, "M[{}]={}", address, format_value(word))
+            }
+            Change::Word(Location::A, word) => write!(f, "rA={}", format_value(word)),
+            Change::Word(Location::X, word) => write!(f, "rX={}", format_value(word)),
+            Change::Word(Location::I(k), word) => write!(f, "rI{}={}", k, format_value(word)),
+            Change::Word(Location::J, word) => write!(f, "rJ={}", format_value(word)),
+            Change::Overflow(overflow) => write!(f, "OV={}", *overflow as u8),
+            Change::Comparison(comparison) => {
+                let c = match comparison {
+                    Ordering::Less => 'L',
+                    Ordering::Equal => 'E',
+                    Ordering::Greater => 'G',
+                };
+                write!(f, "CI={}", c)
+            }
+        }
+    }
+}
+
+impl Change {
+    fn parse(s: &str) -> Option<Change> {
+        let (name, value) = s.split_at(s.find('=')?);
+        let value = &value[1..];
+        let word = |location| Some(Change::Word(location, parse_value(value)?));
+        match name {
+            "rA" => word(Location::A),
+            "rX" => word(Location::X),
+            "rJ" => word(Location::J),
+            "OV" => match value {
+                "0" => Some(Change::Overflow(false)),
+                "1" => Some(Change::Overflow(true)),
+                _ => None,
+            },
+            "CI" => match value {
+                "L" => Some(Change::Comparison(Ordering::Less)),
+                "E" => Some(Change::Comparison(Ordering::Equal)),
+                "G" => Some(Change::Comparison(Ordering::Greater)),
+                _ => None,
+            },
+            _ => {
+                if let Some(k) = name.strip_prefix("rI") {
+                    match k.parse::<usize>() {
+                        Ok(k) if (1..=6).contains(&k) => word(Location::I(k)),
+                        _ => None,
+                    }
+                } else {
+                    let address = name.strip_prefix("M[")?.strip_suffix(']')?;
+                    word(Location::Memory(address.parse().ok()?))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} m={} clock={}",
+            self.step,
+            self.pc,
+            word_to_str(&self.word),
+            self.m,
+            self.clock
+        )?;
+        for change in self.changes.iter() {
+            write!(f, " {}", change)?;
+        }
+        write!(f, " | {}", self.instruction)
+    }
+}
+
+impl TraceStep {
+    // Display で書いた 1 行を読む
+    pub fn parse(line: &str) -> Result<TraceStep, String> {
+        let (fields, instruction) = match line.find(" | ") {
+            Some(i) => (&line[..i], &line[(i + 3)..]),
+            None => return Err("expected | and an instruction".to_string()),
+        };
+        let terms: Vec<&str> = fields.split_whitespace().collect();
+        if terms.len() < 5 {
+            return Err("expected step, location, word, m and clock".to_string());
+        }
+        let int = |s: &str| {
+            s.parse::<i64>()
+                .map_err(|_| format!("invalid number {}", s))
+        };
+        let field = |s: &str, name: &str| match s.strip_prefix(name) {
+            Some(v) => int(v),
+            None => Err(format!("expected {}", name)),
+        };
+        let step = terms[0]
+            .parse::<usize>()
+            .map_err(|_| format!("invalid step {}", terms[0]))?;
+        let changes = terms[5..]
+            .iter()
+            .map(|t| Change::parse(t).ok_or_else(|| format!("invalid change {}", t)))
+            .collect::<Result<Vec<Change>, String>>()?;
+        Ok(TraceStep {
+            step,
+            pc: int(terms[1])?,
+            word: str_to_word(terms[2]).ok_or_else(|| format!("invalid word {}", terms[2]))?,
+            instruction: instruction.to_string(),
+            m: field(terms[3], "m=")?,
+            clock: field(terms[4], "clock=")?,
+            changes,
+        })
+    }
+}
+
+// 記録する命令. 番地の範囲と命令の名前 (空ならすべて)
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub range: Option<(i64, i64)>,
+    pub operations: Vec<String>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, step: &TraceStep) -> bool {
+        if let Some((begin, end)) = self.range {
+            if step.pc < begin || end < step.pc {
+                return false;
+            }
+        }
+        let name = step.instruction.split_whitespace().next().unwrap_or("");
+        self.operations.is_empty() || self.operations.iter().any(|op| op == name)
+    }
+}
+
+// vm を 1 命令ずつ進めて記録を作る
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    disassembler: Disassembler,
+    step: usize,
+}
+
+impl Tracer {
+    pub fn new(disassembler: Disassembler) -> Self {
+        Tracer {
+            disassembler,
+            step: 0,
+        }
+    }
+
+    pub fn step(&mut self, vm: &mut MixVM) -> Result<(StepOutcome, TraceStep), MixFault> {
+        let pc = vm.pc();
+        let word = vm.memory(pc).cloned().unwrap_or_else(WordImpl::word);
+        // M は実行前の索引レジスタで決まる
+        let m = match word.index() {
+            i @ 1..=6 => word.address() + vm.reg_i(i as usize).val(),
+            _ => word.address(),
+        };
+        let registers = |vm: &MixVM| {
+            let mut registers = vec![
+                (Location::A, normalize(vm.reg_a())),
+                (Location::X, normalize(vm.reg_x())),
+            ];
+            for k in 1..=6 {
+                registers.push((Location::I(k), normalize(vm.reg_i(k))));
+            }
+            registers.push((Location::J, normalize(vm.reg_j())));
+            registers
+        };
+        let before = registers(vm);
+        let (overflow, comparison) = (vm.overflow(), vm.comparison());
+
+        let outcome = vm.step()?;
+        self.step += 1;
+        let mut changes: Vec<Change> = registers(vm)
+            .into_iter()
+            .zip(before)
+            .filter(|(after, before)| after != before)
+            .map(|((location, word), _)| Change::Word(location, word))
+            .collect();
+        if vm.overflow() != overflow {
+            changes.push(Change::Overflow(vm.overflow()));
+        }
+        if vm.comparison() != comparison {
+            changes.push(Change::Comparison(vm.comparison()));
+        }
+        let written: BTreeSet<i64> = vm
+            .last_access()
+            .write
+            .iter()
+            .filter_map(|location| match location {
+                Location::Memory(address) => Some(*address),
+                _ => None,
+            })
+            .collect();
+        for address in written {
+            if let Some(word) = vm.memory(address) {
+                changes.push(Change::Word(Location::Memory(address), normalize(word)));
+            }
+        }
+        let step = TraceStep {
+            step: self.step,
+            pc,
+            instruction: self.disassembler.word(&word),
+            word: normalize(&word),
+            m,
+            clock: vm.clock(),
+            changes,
+        };
+        Ok((outcome, step))
+    }
+}
+
+// run と同じく HLT まで実行し, filter に合う命令の記録も返す
+pub fn trace(
+    vm: &mut MixVM,
+    disassembler: Disassembler,
+    filter: &TraceFilter,
+) -> Result<(Vec<TraceStep>, RunInfo), MixFault> {
+    let mut tracer = Tracer::new(disassembler);
+    let mut info = RunInfo::new();
+    let mut steps = vec![];
+    loop {
+        let clock = vm.clock();
+        let (outcome, step) = tracer.step(vm)?;
+        info.update_pc(step.pc, (step.clock - clock) as usize);
+        if filter.matches(&step) {
+            steps.push(step);
+        }
+        if let StepOutcome::Halted(_, _) = outcome {
+            info.set_halt(vm.halt_info().cloned());
+            return Ok((steps, info));
+        }
+    }
+}
+
+pub fn write_trace<W: Write>(steps: &[TraceStep], mut w: W) -> io::Result<()> {
+    writeln!(w, "{}", HEADER)?;
+    for step in steps.iter() {
+        writeln!(w, "{}", step)?;
+    }
+    Ok(())
+}
+
+// write_trace で書いた記録を読む. エラーは行番号 (1 始まり) と理由
+pub fn read_trace<R: BufRead>(r: R) -> Result<Vec<TraceStep>, (usize, String)> {
+    let mut steps = vec![];
+    for (n, line) in r.lines().enumerate() {
+        let line = line.map_err(|e| (n + 1, e.to_string()))?;
+        if n == 0 {
+            if line.trim() != HEADER {
+                return Err((1, "not a MIX trace".to_string()));
+            }
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        steps.push(TraceStep::parse(&line).map_err(|message| (n + 1, message))?);
+    }
+    Ok(steps)
+}
+
+// 2 つの記録で最初に食い違う位置. 番号と逆アセンブルした命令 (シンボルが異なりうる) は比べない.
+// 片方が先に終わったときはその長さ. 同じなら None
+pub fn trace_diff(a: &[TraceStep], b: &[TraceStep]) -> Option<usize> {
+    let same = |x: &TraceStep, y: &TraceStep| {
+        x.pc == y.pc
+            && x.word == y.word
+            && x.m == y.m
+            && x.clock == y.clock
+            && x.changes == y.changes
+    };
+    match a.iter().zip(b.iter()).position(|(x, y)| !same(x, y)) {
+        Some(k) => Some(k),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
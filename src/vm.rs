@@ -1,12 +1,209 @@
 use crate::instruction;
 use crate::iounit;
-use crate::iounit::IOUnit;
-use crate::mix_word::{Memory, Register, Sign, WordImpl};
+pub use crate::iounit::Latency;
+use crate::iounit::{IOUnit, UnitState};
+use crate::mix_word::{Byte, Memory, Register};
+pub use crate::mix_word::{Sign, WordImpl};
 use std::cmp::Ordering;
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
 
+mod snapshot;
+pub use snapshot::SnapshotError;
+
+// MixVM::load で読み込めるもの. 番地と語の並びと, あれば開始番地.
+pub trait Loadable {
+    fn binary(&self) -> Vec<(usize, WordImpl)>;
+    fn entry_point(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl Loadable for Vec<(usize, WordImpl)> {
+    fn binary(&self) -> Vec<(usize, WordImpl)> {
+        self.clone()
+    }
+}
+
+impl Loadable for [(usize, WordImpl)] {
+    fn binary(&self) -> Vec<(usize, WordImpl)> {
+        self.to_vec()
+    }
+}
+
+const MEMORY_SIZE: usize = 4000;
+// 制御状態でのみ使える -1..=-3999 番地 (TAOCP 1.4.4 演習問題 18)
+const CONTROL_MEMORY_SIZE: usize = 3999;
+// 割り込みで退避したレジスタの格納先 (-9..=-1 番地)
+const SAVE_AREA: i64 = -9;
+// インターバルタイマ
+const TIMER: i64 = -10;
+// 割り込み先の番地
+const TIMER_INTERRUPT: i64 = -11;
+const INT_INTERRUPT: i64 = -12;
+const IO_INTERRUPT: i64 = -20;
+
+// 1 命令を実行した結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepOutcome {
+    // 命令を実行した (実行した命令の番地と命令語)
+    Stepped(i64, WordImpl),
+    // HLT に到達した
+    Halted(i64, WordImpl),
+}
+
+// HLT で停止したときの情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HaltInfo {
+    // HLT 命令の番地
+    pub pc: i64,
+    // HLT 命令の M (インデックス修飾後のアドレス部). エラーコードとして使われることがある.
+    pub address: i64,
+}
+
+// 命令が読み書きする場所
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Location {
+    Memory(i64),
+    A,
+    X,
+    // rI1..=rI6
+    I(usize),
+    J,
+}
+
+// 直前の step で読み書きした場所. 転送の完了や割り込みによるものも含む.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Access {
+    pub read: Vec<Location>,
+    pub write: Vec<Location>,
+}
+
+impl Access {
+    fn memory(addresses: std::ops::Range<i64>) -> Vec<Location> {
+        addresses.map(Location::Memory).collect()
+    }
+}
+
+// ある時点の機械の状態 (レジスタ, 桁あふれ, 比較, 0..=3999 番地のメモリ)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineState {
+    pub pc: i64,
+    pub clock: i64,
+    pub reg_a: WordImpl,
+    pub reg_x: WordImpl,
+    // rI1..=rI6
+    pub reg_i: Vec<WordImpl>,
+    pub reg_j: WordImpl,
+    pub overflow: bool,
+    pub comp: Ordering,
+    pub memory: Vec<WordImpl>,
+}
+
+// 実行できなかった理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FaultKind {
+    // 定義されていない (C, F) の組
+    InvalidOpcode(i64, usize),
+    // 部分フィールド (L:R) が不正
+    InvalidFieldSpec(usize),
+    // インデックス I が 0..=6 でない
+    InvalidIndex(i64),
+    // 番地 M がメモリの範囲外
+    AddressOutOfRange(i64),
+    // 装置番号が存在しない
+    InvalidDevice(usize),
+    // 装置がその入出力操作に対応していない
+    UnsupportedIoOperation(usize),
+    // 入力装置に読み込むデータが残っていない
+    EndOfInput(usize),
+    // インデックスレジスタ rIi の値が 2 バイトに収まらない
+    IndexRegisterOverflow(usize),
+    // シフト量が負
+    InvalidShiftCount(i64),
+    // ディスク (装置番号, rX) のブロック番号が容量を超えている
+    InvalidBlock(usize, i64),
+}
+
+// 命令実行時の異常. 異常を起こした命令の番地と命令語を持つ.
+// 異常が起きた場合, 機械の状態はその命令を実行する前のまま変わらない.
+// ただし命令の取り出しの前に済ませる, その時刻までの入出力の完了と割り込みの受け付けは残る.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MixFault {
+    pub pc: i64,
+    pub inst: WordImpl,
+    pub kind: FaultKind,
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use FaultKind::*;
+        match self {
+            InvalidOpcode(c, field) => write!(f, "invalid opcode C={} F={}", c, field),
+            InvalidFieldSpec(field) => write!(f, "invalid field specification {}", field),
+            InvalidIndex(i) => write!(f, "invalid index {}", i),
+            AddressOutOfRange(m) => write!(f, "address {} out of range", m),
+            InvalidDevice(unit) => write!(f, "invalid device {}", unit),
+            UnsupportedIoOperation(unit) => {
+                write!(f, "unsupported I/O operation on device {}", unit)
+            }
+            EndOfInput(unit) => write!(f, "no more input on device {}", unit),
+            IndexRegisterOverflow(i) => write!(f, "rI{} overflow", i),
+            InvalidShiftCount(m) => write!(f, "invalid shift count {}", m),
+            InvalidBlock(unit, block) => write!(f, "invalid block {} on device {}", block, unit),
+        }
+    }
+}
+impl fmt::Display for MixFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:04}: {}", self.kind, self.pc, self.inst)
+    }
+}
+
+// 転送中の入出力. 完了時刻になったら memory や装置に反映する
+#[derive(Debug, Clone)]
+enum Transfer {
+    // IN: 書き込み先の memory の添字と読み込んだ語
+    Input(usize, Vec<WordImpl>),
+    // OUT: 書き出す memory の先頭の添字. 語は転送が完了した時点の memory から取る
+    Output(usize),
+    // IOC
+    Control,
+}
+
+#[derive(Debug, Clone)]
+struct PendingIo {
+    device: usize,
+    done: i64,
+    transfer: Transfer,
+}
+
+// step_back で 1 命令分戻すための記録. レジスタ類はそのまま, メモリと装置は
+// 書き換える前の値を持つ.
+#[derive(Debug, Clone)]
+struct Undo {
+    pc: i64,
+    clock: i64,
+    reg_a: Register,
+    reg_x: Register,
+    reg_i: Vec<Register>,
+    reg_j: Register,
+    overflow: bool,
+    comp: Ordering,
+    control: bool,
+    pending_interrupts: BTreeSet<i64>,
+    pending_io: Vec<PendingIo>,
+    halt: Option<HaltInfo>,
+    // memory の添字と元の語
+    memory: Vec<(usize, WordImpl)>,
+    // 装置番号と元の状態 (ラインプリンタは改ページの位置も)
+    units: Vec<(usize, UnitState, Vec<usize>)>,
+}
+
+#[derive(Debug, Clone)]
 pub struct MixVM {
     // utility
-    pc: usize,
+    pc: i64,
     clock: i64,
     // onboard
     reg_a: Register,
@@ -15,8 +212,12 @@ pub struct MixVM {
     reg_j: Register,
     overflow: bool,
     comp: Ordering,
-    // memory
+    // memory (先頭 CONTROL_MEMORY_SIZE 語は負の番地)
     memory: Memory,
+    // interrupt
+    interrupt_facility: bool,
+    control: bool,
+    pending_interrupts: BTreeSet<i64>,
     // IOUnit
     tape: Vec<iounit::Tape>,
     disk: Vec<iounit::Disk>,
@@ -25,6 +226,14 @@ pub struct MixVM {
     line_printer: iounit::LinePrinter,
     type_writer_terminal: iounit::TypeWriterTerminal,
     paper_tape: iounit::PaperTape,
+    pending_io: Vec<PendingIo>,
+    // halt
+    halt: Option<HaltInfo>,
+    // debug
+    access: Access,
+    undo: Option<Undo>,
+    history: VecDeque<Undo>,
+    history_limit: usize,
 }
 
 impl MixVM {
@@ -36,7 +245,10 @@ impl MixVM {
             reg_x: Register::from_val(0),
             reg_i: vec![Register::from_val(0); 6],
             reg_j: Register::from_val(0),
-            memory: vec![Register::from_val(0); 4000],
+            memory: vec![Register::from_val(0); CONTROL_MEMORY_SIZE + MEMORY_SIZE],
+            interrupt_facility: false,
+            control: false,
+            pending_interrupts: BTreeSet::new(),
             overflow: false,
             comp: Ordering::Equal,
             tape: vec![iounit::Tape::default(); 8],
@@ -46,19 +258,171 @@ impl MixVM {
             line_printer: iounit::LinePrinter::default(),
             type_writer_terminal: iounit::TypeWriterTerminal::default(),
             paper_tape: iounit::PaperTape::default(),
+            pending_io: vec![],
+            halt: None,
+            access: Access::default(),
+            undo: None,
+            history: VecDeque::new(),
+            history_limit: 0,
+        }
+    }
+    // 語をメモリに置く. 開始番地を持つもの (オブジェクトファイルなど) は PC もそこに合わせる.
+    pub fn load<P: Loadable + ?Sized>(&mut self, program: &P) {
+        for (address, word) in program.binary() {
+            self.memory[CONTROL_MEMORY_SIZE + address] = word;
+        }
+        if let Some(entry_point) = program.entry_point() {
+            self.set_pc(entry_point);
         }
     }
-    pub fn load(&mut self, code: &Vec<(usize, WordImpl)>) {
+    // 負の番地 (-3999..=-1) を含めて書き込む. 割り込み先やタイマの初期値を置くのに使う.
+    pub fn load_control(&mut self, code: &Vec<(i64, WordImpl)>) {
         for (address, word) in code {
-            self.memory[*address] = word.clone();
+            *self.control_word_mut(*address) = word.clone();
         }
     }
+    // GO ボタン (TAOCP 1.3.1 演習問題 26). カード読取装置から 1 枚を 0..=15 番地に読み込み,
+    // 読み終わるのを待って rJ を 0 にし, 0 番地から実行を始める. 続きは step で進める.
+    pub fn press_go(&mut self) -> Result<(), FaultKind> {
+        if !self.can_read(16) {
+            return Err(FaultKind::EndOfInput(16));
+        }
+        self.wait_io(16);
+        let card: Vec<WordImpl> = self
+            .card_reader
+            .read()
+            .unwrap()
+            .into_iter()
+            .cloned()
+            .collect();
+        let latency = self.latency(16);
+        self.start_io(
+            16,
+            latency.transfer,
+            Transfer::Input(CONTROL_MEMORY_SIZE, card),
+        );
+        self.wait_io(16);
+        self.reg_j = WordImpl::from_val(0);
+        self.pc = 0;
+        self.halt = None;
+        Ok(())
+    }
+    pub fn pc(&self) -> i64 {
+        self.pc
+    }
     pub fn set_pc(&mut self, pc: usize) {
-        self.pc = pc;
+        self.pc = pc as i64;
+    }
+    // 割り込み機構 (制御状態, 負の番地, INT, タイマ, 入出力完了割り込み) を使うかどうか.
+    // 使わない場合は TAOCP 1.3 の素の MIX として動く.
+    pub fn set_interrupt_facility(&mut self, enabled: bool) {
+        self.interrupt_facility = enabled;
+        if !enabled {
+            self.control = false;
+            self.pending_interrupts.clear();
+        }
+    }
+    pub fn is_control_state(&self) -> bool {
+        self.control
     }
     pub fn clock(&self) -> i64 {
         self.clock
     }
+    pub fn reg_a(&self) -> &WordImpl {
+        &self.reg_a
+    }
+    pub fn set_reg_a(&mut self, word: WordImpl) {
+        self.reg_a = word;
+    }
+    pub fn reg_x(&self) -> &WordImpl {
+        &self.reg_x
+    }
+    pub fn set_reg_x(&mut self, word: WordImpl) {
+        self.reg_x = word;
+    }
+    // k は 1..=6
+    pub fn reg_i(&self, k: usize) -> &WordImpl {
+        &self.reg_i[k - 1]
+    }
+    pub fn set_reg_i(&mut self, k: usize, word: WordImpl) {
+        self.reg_i[k - 1] = word;
+    }
+    pub fn reg_j(&self) -> &WordImpl {
+        &self.reg_j
+    }
+    pub fn set_reg_j(&mut self, word: WordImpl) {
+        self.reg_j = word;
+    }
+    pub fn overflow(&self) -> bool {
+        self.overflow
+    }
+    pub fn set_overflow(&mut self, overflow: bool) {
+        self.overflow = overflow;
+    }
+    pub fn comparison(&self) -> Ordering {
+        self.comp
+    }
+    pub fn set_comparison(&mut self, comp: Ordering) {
+        self.comp = comp;
+    }
+    // 負の番地 (-3999..=-1) も読み書きできる
+    pub fn memory(&self, address: i64) -> Option<&WordImpl> {
+        MixVM::cell_index(true, address).map(|index| &self.memory[index])
+    }
+    // begin..end 番地
+    pub fn memory_range(&self, begin: i64, end: i64) -> Option<&[WordImpl]> {
+        let first = MixVM::cell_index(true, begin)?;
+        if begin == end {
+            return Some(&[]);
+        }
+        let last = MixVM::cell_index(true, end - 1)?;
+        self.memory.get(first..=last)
+    }
+    pub fn set_memory(&mut self, address: i64, word: WordImpl) -> Result<(), FaultKind> {
+        match MixVM::cell_index(true, address) {
+            Some(index) => {
+                self.memory[index] = word;
+                Ok(())
+            }
+            None => Err(FaultKind::AddressOutOfRange(address)),
+        }
+    }
+    // begin 番地から順に words を書き込む
+    pub fn set_memory_range(&mut self, begin: i64, words: &[WordImpl]) -> Result<(), FaultKind> {
+        let end = begin + words.len() as i64;
+        if !words.is_empty() {
+            for &address in &[begin, end - 1] {
+                if MixVM::cell_index(true, address).is_none() {
+                    return Err(FaultKind::AddressOutOfRange(address));
+                }
+            }
+        }
+        for (i, word) in words.iter().enumerate() {
+            self.set_memory(begin + i as i64, word.clone())?;
+        }
+        Ok(())
+    }
+    pub fn state(&self) -> MachineState {
+        MachineState {
+            pc: self.pc,
+            clock: self.clock,
+            reg_a: self.reg_a.clone(),
+            reg_x: self.reg_x.clone(),
+            reg_i: self.reg_i.clone(),
+            reg_j: self.reg_j.clone(),
+            overflow: self.overflow,
+            comp: self.comp,
+            memory: self.memory[CONTROL_MEMORY_SIZE..].to_vec(),
+        }
+    }
+    // 直前の step で読み書きした場所
+    pub fn last_access(&self) -> &Access {
+        &self.access
+    }
+    // 直前の step で HLT により停止していれば, その情報を返す
+    pub fn halt_info(&self) -> Option<&HaltInfo> {
+        self.halt.as_ref()
+    }
     pub fn print(&self, f: usize) -> String {
         // print out
         match f {
@@ -109,43 +473,561 @@ impl MixVM {
             _ => unreachable!(),
         }
     }
-    pub fn step(&mut self) -> Result<(usize, WordImpl), ()> {
+    // 番地から memory の添字を求める. 負の番地は制御状態でのみ使える.
+    fn cell_index(control: bool, address: i64) -> Option<usize> {
+        let lowest = if control {
+            -(CONTROL_MEMORY_SIZE as i64)
+        } else {
+            0
+        };
+        if lowest <= address && address < MEMORY_SIZE as i64 {
+            Some((address + CONTROL_MEMORY_SIZE as i64) as usize)
+        } else {
+            None
+        }
+    }
+    fn control_word(&self, address: i64) -> &WordImpl {
+        &self.memory[(address + CONTROL_MEMORY_SIZE as i64) as usize]
+    }
+    fn control_word_mut(&mut self, address: i64) -> &mut WordImpl {
+        let index = (address + CONTROL_MEMORY_SIZE as i64) as usize;
+        self.journal_memory(index);
+        &mut self.memory[index]
+    }
+    // step_back のために書き換える前の語を記録する
+    fn journal_memory(&mut self, index: usize) {
+        if let Some(undo) = self.undo.as_mut() {
+            undo.memory.push((index, self.memory[index].clone()));
+        }
+    }
+    // step_back のために装置 f の状態を記録する. 1 命令につき最初の 1 回だけ.
+    fn journal_unit(&mut self, f: usize) {
+        let recorded = match self.undo.as_ref() {
+            Some(undo) => undo.units.iter().any(|(unit, _, _)| *unit == f),
+            None => true,
+        };
+        if recorded {
+            return;
+        }
+        let state = match f {
+            0..=7 => self.tape[f].save_state(),
+            8..=15 => self.disk[f - 8].save_state(),
+            16 => self.card_reader.save_state(),
+            17 => self.card_punch.save_state(),
+            18 => self.line_printer.save_state(),
+            19 => self.type_writer_terminal.save_state(),
+            20 => self.paper_tape.save_state(),
+            _ => unreachable!(),
+        };
+        let pages = self.line_printer.pages().clone();
+        self.undo.as_mut().unwrap().units.push((f, state, pages));
+    }
+    // 直前に戻せる命令の数の上限. 0 なら記録しない.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+    // 最後に実行した命令の前の状態に戻す. 戻せる記録がなければ false.
+    pub fn step_back(&mut self) -> bool {
+        let undo = match self.history.pop_back() {
+            Some(undo) => undo,
+            None => return false,
+        };
+        self.pc = undo.pc;
+        self.clock = undo.clock;
+        self.reg_a = undo.reg_a;
+        self.reg_x = undo.reg_x;
+        self.reg_i = undo.reg_i;
+        self.reg_j = undo.reg_j;
+        self.overflow = undo.overflow;
+        self.comp = undo.comp;
+        self.control = undo.control;
+        self.pending_interrupts = undo.pending_interrupts;
+        self.pending_io = undo.pending_io;
+        self.halt = undo.halt;
+        for (index, word) in undo.memory.into_iter().rev() {
+            self.memory[index] = word;
+        }
+        for (f, state, pages) in undo.units {
+            match f {
+                0..=7 => self.tape[f].restore_state(state),
+                8..=15 => self.disk[f - 8].restore_state(state),
+                16 => self.card_reader.restore_state(state),
+                17 => self.card_punch.restore_state(state),
+                18 => self.line_printer.restore_state(state),
+                19 => self.type_writer_terminal.restore_state(state),
+                20 => self.paper_tape.restore_state(state),
+                _ => unreachable!(),
+            }
+            if f == 18 {
+                self.line_printer.set_pages(pages);
+            }
+        }
+        self.access = Access::default();
+        true
+    }
+    // 割り込みを保留する. 制御状態の間は通常状態に戻るまで待たされる.
+    fn request_interrupt(&mut self, location: i64) {
+        if self.interrupt_facility {
+            self.pending_interrupts.insert(location);
+        }
+    }
+    // 保留中の割り込みがあれば受け付ける. 番地が 0 に近いものほど優先度が高い
+    // (タイマ -11, INT -12, 装置 u の入出力完了 -(20 + u)).
+    fn accept_interrupt(&mut self) {
+        if !self.interrupt_facility || self.control {
+            return;
+        }
+        let location = match self.pending_interrupts.iter().next_back() {
+            Some(location) => *location,
+            None => return,
+        };
+        self.pending_interrupts.remove(&location);
+
+        // rA, rI1..rI6, rX を -9..=-2 番地に, 残りを -1 番地に退避する.
+        // -1 番地: (1:1) 8 * 桁あふれ + 比較 (0: 等しい, 1: 小さい, 2: 大きい),
+        //          (2:3) rJ, (4:5) 次に実行する命令の番地
+        let mut saved = vec![self.reg_a.clone()];
+        saved.extend(self.reg_i.iter().cloned());
+        saved.push(self.reg_x.clone());
+        for (i, word) in saved.into_iter().enumerate() {
+            *self.control_word_mut(SAVE_AREA + i as i64) = word;
+        }
+        let comp = match self.comp {
+            Ordering::Equal => 0,
+            Ordering::Less => 1,
+            Ordering::Greater => 2,
+        };
+        let b = Byte::max() as i64;
+        let j = self.reg_j.val().abs();
+        let state = vec![
+            (8 * self.overflow as i64 + comp) as u32,
+            (j / b) as u32,
+            (j % b) as u32,
+            (self.pc / b) as u32,
+            (self.pc % b) as u32,
+        ];
+        *self.control_word_mut(-1) = WordImpl::from_seq(Sign::Positive, &state);
+        self.access.write.extend(Access::memory(SAVE_AREA..0));
+
+        self.control = true;
+        self.pc = location;
+    }
+    // 制御状態の INT: 退避しておいた状態に戻り通常状態に移る
+    fn return_from_interrupt(&mut self) {
+        self.reg_a = self.control_word(SAVE_AREA).clone();
+        for k in 0..6 {
+            self.reg_i[k] = self.control_word(SAVE_AREA + 1 + k as i64).clone();
+        }
+        self.reg_x = self.control_word(-2).clone();
+        let state = self.control_word(-1).clone();
+        let flags = state.subword(WordImpl::pair(1, 1)).unwrap().val();
+        self.overflow = flags / 8 != 0;
+        self.comp = match flags % 8 {
+            1 => Ordering::Less,
+            2 => Ordering::Greater,
+            _ => Ordering::Equal,
+        };
+        self.reg_j = state.subword(WordImpl::pair(2, 3)).unwrap();
+        self.pc = state.subword(WordImpl::pair(4, 5)).unwrap().val();
+        self.control = false;
+    }
+    // -10 番地のタイマを経過時間だけ減らし, 0 になったら割り込みを起こす
+    fn tick_timer(&mut self, elapsed: i64) {
+        if !self.interrupt_facility || elapsed <= 0 {
+            return;
+        }
+        let timer = self.control_word(TIMER).val();
+        if timer <= 0 {
+            return;
+        }
+        self.access.write.push(Location::Memory(TIMER));
+        if timer <= elapsed {
+            *self.control_word_mut(TIMER) = WordImpl::from_val(0);
+            self.request_interrupt(TIMER_INTERRUPT);
+        } else {
+            *self.control_word_mut(TIMER) = WordImpl::from_val(timer - elapsed);
+        }
+    }
+    fn block_size(f: usize) -> Option<usize> {
+        match f {
+            0..=7 => Some(iounit::Tape::block_size()),
+            8..=15 => Some(iounit::Disk::block_size()),
+            16 => Some(iounit::CardReader::block_size()),
+            17 => Some(iounit::CardPunch::block_size()),
+            18 => Some(iounit::LinePrinter::block_size()),
+            19 => Some(iounit::TypeWriterTerminal::block_size()),
+            20 => Some(iounit::PaperTape::block_size()),
+            _ => None,
+        }
+    }
+    fn busy(&self, f: usize) -> Option<bool> {
+        let clock = self.clock;
+        match f {
+            0..=7 => Some(self.tape[f].busy(clock)),
+            8..=15 => Some(self.disk[f - 8].busy(clock)),
+            16 => Some(self.card_reader.busy(clock)),
+            17 => Some(self.card_punch.busy(clock)),
+            18 => Some(self.line_printer.busy(clock)),
+            19 => Some(self.type_writer_terminal.busy(clock)),
+            20 => Some(self.paper_tape.busy(clock)),
+            _ => None,
+        }
+    }
+    fn ready(&self, f: usize) -> Option<bool> {
+        let clock = self.clock;
+        match f {
+            0..=7 => Some(self.tape[f].ready(clock)),
+            8..=15 => Some(self.disk[f - 8].ready(clock)),
+            16 => Some(self.card_reader.ready(clock)),
+            17 => Some(self.card_punch.ready(clock)),
+            18 => Some(self.line_printer.ready(clock)),
+            19 => Some(self.type_writer_terminal.ready(clock)),
+            20 => Some(self.paper_tape.ready(clock)),
+            _ => None,
+        }
+    }
+    // ディスク f (8..=15) の容量をブロック数で設定する
+    pub fn set_disk_capacity(&mut self, f: usize, blocks: usize) {
+        self.disk[f - 8].set_capacity(blocks);
+    }
+    pub fn latency(&self, f: usize) -> Latency {
+        match f {
+            0..=7 => self.tape[f].latency(),
+            8..=15 => self.disk[f - 8].latency(),
+            16 => self.card_reader.latency(),
+            17 => self.card_punch.latency(),
+            18 => self.line_printer.latency(),
+            19 => self.type_writer_terminal.latency(),
+            20 => self.paper_tape.latency(),
+            _ => unreachable!(),
+        }
+    }
+    pub fn set_latency(&mut self, f: usize, latency: Latency) {
+        match f {
+            0..=7 => self.tape[f].set_latency(latency),
+            8..=15 => self.disk[f - 8].set_latency(latency),
+            16 => self.card_reader.set_latency(latency),
+            17 => self.card_punch.set_latency(latency),
+            18 => self.line_printer.set_latency(latency),
+            19 => self.type_writer_terminal.set_latency(latency),
+            20 => self.paper_tape.set_latency(latency),
+            _ => unreachable!(),
+        }
+    }
+    // 装置 f の転送を time 単位時間後に完了するものとして始める
+    fn start_io(&mut self, f: usize, time: i64, transfer: Transfer) {
+        let done = self.clock + time;
+        match f {
+            0..=7 => self.tape[f].start(done),
+            8..=15 => self.disk[f - 8].start(done),
+            16 => self.card_reader.start(done),
+            17 => self.card_punch.start(done),
+            18 => self.line_printer.start(done),
+            19 => self.type_writer_terminal.start(done),
+            20 => self.paper_tape.start(done),
+            _ => unreachable!(),
+        }
+        self.pending_io.push(PendingIo {
+            device: f,
+            done,
+            transfer,
+        });
+    }
+    // 完了時刻を過ぎた転送を memory や装置に反映し, 入出力完了割り込みを起こす
+    fn complete_io(&mut self) {
+        let clock = self.clock;
+        let (done, pending): (Vec<_>, Vec<_>) = self
+            .pending_io
+            .drain(..)
+            .partition(|pending| pending.done <= clock);
+        self.pending_io = pending;
+        for pending in done {
+            self.finish_io(pending);
+        }
+    }
+    fn finish_io(&mut self, pending: PendingIo) {
+        let f = pending.device;
+        self.journal_unit(f);
+        match pending.transfer {
+            Transfer::Input(begin, words) => {
+                let address = begin as i64 - CONTROL_MEMORY_SIZE as i64;
+                self.access
+                    .write
+                    .extend(Access::memory(address..(address + words.len() as i64)));
+                for (i, word) in words.into_iter().enumerate() {
+                    self.journal_memory(begin + i);
+                    self.memory[begin + i] = word;
+                }
+            }
+            Transfer::Output(begin) => {
+                let block_size = MixVM::block_size(f).unwrap();
+                let address = begin as i64 - CONTROL_MEMORY_SIZE as i64;
+                self.access
+                    .read
+                    .extend(Access::memory(address..(address + block_size as i64)));
+                let words = self.memory[begin..(begin + block_size)].to_vec();
+                let v = words.iter().collect();
+                match f {
+                    0..=7 => self.tape[f].write(v),
+                    8..=15 => self.disk[f - 8].write(v),
+                    17 => self.card_punch.write(v),
+                    18 => self.line_printer.write(v),
+                    19 => self.type_writer_terminal.write(v),
+                    20 => self.paper_tape.write(v),
+                    _ => unreachable!(),
+                }
+            }
+            Transfer::Control => {}
+        }
+        self.request_interrupt(IO_INTERRUPT - f as i64);
+    }
+    // 装置 f が空くのを待ってから 1 ブロック読めるか. 転送中の出力は書いた後に読む.
+    // ディスクは読む前にブロックを位置決めするので, いつでも読める.
+    fn can_read(&self, f: usize) -> bool {
+        let writes = self
+            .pending_io
+            .iter()
+            .filter(|pending| pending.device == f)
+            .filter(|pending| matches!(pending.transfer, Transfer::Output(_)))
+            .count();
+        match f {
+            0..=7 => self.tape[f].can_read(writes),
+            8..=15 => true,
+            16 => self.card_reader.can_read(writes),
+            19 => self.type_writer_terminal.can_read(writes),
+            20 => self.paper_tape.can_read(writes),
+            _ => unreachable!(),
+        }
+    }
+    // 装置 f が空くまで待つ
+    fn wait_io(&mut self, f: usize) {
+        let done = self
+            .pending_io
+            .iter()
+            .filter(|pending| pending.device == f)
+            .map(|pending| pending.done)
+            .max();
+        if let Some(done) = done {
+            self.clock = self.clock.max(done);
+            self.complete_io();
+        }
+    }
+    // 停止している間に装置は動き続けるので, 転送中のものはすべて完了させる
+    fn flush_io(&mut self) {
+        let pending: Vec<_> = self.pending_io.drain(..).collect();
+        let clock = self.clock;
+        for pending in pending {
+            let f = pending.device;
+            self.finish_io(pending);
+            match f {
+                0..=7 => self.tape[f].start(clock),
+                8..=15 => self.disk[f - 8].start(clock),
+                16 => self.card_reader.start(clock),
+                17 => self.card_punch.start(clock),
+                18 => self.line_printer.start(clock),
+                19 => self.type_writer_terminal.start(clock),
+                20 => self.paper_tape.start(clock),
+                _ => unreachable!(),
+            }
+        }
+    }
+    // 命令 (C, F) が番地 M と共に読み書きする場所. rJ への書き込みは
+    // 実際に飛んだかどうかで決まるので jump! で記録する.
+    fn operand_access(&self, c: i64, f: usize, m: i64) -> Access {
+        use Location::*;
+        let reg = |k: i64| match k {
+            0 => A,
+            7 => X,
+            k => I(k as usize),
+        };
+        let block = |f: usize| MixVM::block_size(f).unwrap_or(0) as i64;
+        let (read, write) = match c {
+            1..=4 if f == 6 => (vec![A, Memory(m)], vec![A]),
+            1 | 2 => (vec![A, Memory(m)], vec![A]),
+            3 => (vec![A, Memory(m)], vec![A, X]),
+            4 => (vec![A, X, Memory(m)], vec![A, X]),
+            5 => match f {
+                0 => (vec![A, X], vec![A]),
+                1 => (vec![A], vec![A, X]),
+                6 | 7 => (vec![A], vec![A]),
+                9 if self.control => (
+                    Access::memory(SAVE_AREA..0),
+                    vec![A, I(1), I(2), I(3), I(4), I(5), I(6), X, J],
+                ),
+                _ => (vec![], vec![]),
+            },
+            6 => match f {
+                0 | 1 => (vec![A], vec![A]),
+                _ => (vec![A, X], vec![A, X]),
+            },
+            7 => {
+                let to = self.reg_i[0].val();
+                let mut read = Access::memory(m..(m + f as i64));
+                read.push(I(1));
+                let mut write = Access::memory(to..(to + f as i64));
+                write.push(I(1));
+                (read, write)
+            }
+            8..=15 => (vec![Memory(m)], vec![reg(c - 8)]),
+            16..=23 => (vec![Memory(m)], vec![reg(c - 16)]),
+            24..=31 => (vec![reg(c - 24)], vec![Memory(m)]),
+            32 => (vec![J], vec![Memory(m)]),
+            33 => (vec![], vec![Memory(m)]),
+            35 if (8..=15).contains(&f) => (vec![X], vec![]),
+            36 => {
+                let mut read = vec![];
+                if (8..=15).contains(&f) {
+                    read.push(X);
+                }
+                (read, vec![])
+            }
+            37 => {
+                let mut read = Access::memory(m..(m + block(f)));
+                if (8..=15).contains(&f) {
+                    read.push(X);
+                }
+                (read, vec![])
+            }
+            40..=47 => (vec![reg(c - 40)], vec![]),
+            48..=55 => match f {
+                0 | 1 => (vec![reg(c - 48)], vec![reg(c - 48)]),
+                _ => (vec![], vec![reg(c - 48)]),
+            },
+            56 if f == 6 => (vec![A, Memory(m), Memory(0)], vec![]),
+            56..=63 => (vec![reg(c - 56), Memory(m)], vec![]),
+            _ => (vec![], vec![]),
+        };
+        Access { read, write }
+    }
+    pub fn step(&mut self) -> Result<StepOutcome, MixFault> {
+        if self.history_limit > 0 {
+            self.undo = Some(Undo {
+                pc: self.pc,
+                clock: self.clock,
+                reg_a: self.reg_a.clone(),
+                reg_x: self.reg_x.clone(),
+                reg_i: self.reg_i.clone(),
+                reg_j: self.reg_j.clone(),
+                overflow: self.overflow,
+                comp: self.comp,
+                control: self.control,
+                pending_interrupts: self.pending_interrupts.clone(),
+                pending_io: self.pending_io.clone(),
+                halt: self.halt.clone(),
+                memory: vec![],
+                units: vec![],
+            });
+        }
+        let result = self.execute();
+        if let Some(undo) = self.undo.take() {
+            if result.is_ok() {
+                self.history.push_back(undo);
+                if self.history.len() > self.history_limit {
+                    self.history.pop_front();
+                }
+            }
+        }
+        result
+    }
+    fn execute(&mut self) -> Result<StepOutcome, MixFault> {
+        // 0. I/O, interrupt
+        self.access = Access::default();
+        self.complete_io();
+        self.accept_interrupt();
+        let start_clock = self.clock;
+
         // 1. fetch
-        let inst = self.memory[self.pc].clone();
         let current_pc = self.pc;
+        let inst = match MixVM::cell_index(self.control, current_pc) {
+            Some(index) => self.memory[index].clone(),
+            None => {
+                return Err(MixFault {
+                    pc: current_pc,
+                    inst: WordImpl::word(),
+                    kind: FaultKind::AddressOutOfRange(current_pc),
+                });
+            }
+        };
         let a = inst.address(); // address
         let i = inst.index(); // index
         let f = inst.modification() as usize; // modification
         let c = inst.operation(); // operation
+
+        // 2. define macros
+        macro_rules! fault {
+            ($kind: expr) => {
+                return Err(MixFault {
+                    pc: current_pc,
+                    inst: inst.clone(),
+                    kind: $kind,
+                });
+            };
+        }
+        if i > 6 {
+            fault!(FaultKind::InvalidIndex(i));
+        }
         let m = if i == 0 {
             a
         } else {
-            a + &self.reg_i[(i - 1) as usize].val()
+            a + self.reg_i[(i - 1) as usize].val()
         }; // modified address
+        let operand = self.operand_access(c, f, m);
+        for location in operand.write.iter() {
+            if let Location::Memory(address) = location {
+                if let Some(index) = MixVM::cell_index(self.control, *address) {
+                    self.journal_memory(index);
+                }
+            }
+        }
+        self.access.read.extend(operand.read);
+        self.access.write.extend(operand.write);
 
-        // println!(
-        //     "line: {}, a: {:4}, i: {:2}, f: {:2}, c: {:2}, m: {:4}",
-        //     current_pc - 2417,
-        //     a,
-        //     i,
-        //     f,
-        //     c,
-        //     m
-        // );
-        // println!("\t rA: {}\n\t rX: {}\n\trI1: {}\n\trI2: {}\n\trI3: {}\n\trI4: {}\n\trI5: {}\n\trI6: {}\n\t rJ: {}", self.reg_a, self.reg_x, self.reg_i[0], self.reg_i[1], self.reg_i[2], self.reg_i[3], self.reg_i[4], self.reg_i[5], self.reg_j);
-        // println!("\toverflow: {}, comp: {:?}", self.overflow, self.comp);
-
-        // 2. define macros
         macro_rules! forward {
             ($pc: expr, $clock: expr) => {
                 self.pc += $pc;
                 self.clock += $clock as i64;
             };
         }
+        macro_rules! cell {
+            ($address: expr) => {{
+                let address = $address;
+                match MixVM::cell_index(self.control, address) {
+                    Some(index) => index,
+                    None => {
+                        fault!(FaultKind::AddressOutOfRange(address));
+                    }
+                }
+            }};
+        }
+        macro_rules! field {
+            () => {
+                let (l, r) = Register::unpair(f);
+                if l > r || r > 5 {
+                    fault!(FaultKind::InvalidFieldSpec(f));
+                }
+            };
+        }
+        macro_rules! index_register {
+            ($k: expr, $reg: expr) => {
+                let reg = $reg;
+                if reg.val().abs() >= (Byte::max() as i64).pow(2) {
+                    fault!(FaultKind::IndexRegisterOverflow($k + 1));
+                }
+                self.reg_i[$k] = reg;
+            };
+        }
         macro_rules! jump {
             () => {
-                self.reg_j = Register::from_val((self.pc + 1) as i64);
-                self.pc = m as usize;
+                cell!(m);
+                self.reg_j = Register::from_val(self.pc + 1);
+                self.pc = m;
+                self.access.write.push(Location::J);
             };
         }
         macro_rules! macro40_47 {
@@ -200,7 +1082,7 @@ impl MixVM {
                         }
                     }
                     _ => {
-                        unreachable!();
+                        fault!(FaultKind::InvalidOpcode(c, f));
                     }
                 }
             };
@@ -209,10 +1091,10 @@ impl MixVM {
             ($reg: expr) => {
                 match f {
                     0 => {
-                        instruction::inc(m as i64, $reg, &mut self.overflow);
+                        instruction::inc(m, $reg, &mut self.overflow);
                     }
                     1 => {
-                        instruction::dec(m as i64, $reg, &mut self.overflow);
+                        instruction::dec(m, $reg, &mut self.overflow);
                     }
                     2 => {
                         let sign = if m == 0 {
@@ -222,7 +1104,7 @@ impl MixVM {
                         } else {
                             Sign::Negative
                         };
-                        instruction::ent(m as i64, &sign, $reg);
+                        instruction::ent(m, &sign, $reg);
                     }
                     3 => {
                         let sign = if m == 0 {
@@ -232,10 +1114,10 @@ impl MixVM {
                         } else {
                             Sign::Negative
                         };
-                        instruction::entn(m as i64, &sign, $reg);
+                        instruction::entn(m, &sign, $reg);
                     }
                     _ => {
-                        unreachable!();
+                        fault!(FaultKind::InvalidOpcode(c, f));
                     }
                 }
             };
@@ -248,582 +1130,536 @@ impl MixVM {
                 forward!(1, 1);
             }
             1 => {
-                // ADD
-                instruction::add(
-                    &self.memory[m as usize],
-                    &mut self.reg_a,
-                    f,
-                    &mut self.overflow,
-                );
-                forward!(1, 2);
+                match f {
+                    6 => {
+                        // FADD
+                        instruction::fadd(
+                            &self.memory[cell!(m)],
+                            &mut self.reg_a,
+                            &mut self.overflow,
+                        );
+                        forward!(1, 4);
+                    }
+                    _ => {
+                        // ADD
+                        field!();
+                        instruction::add(
+                            &self.memory[cell!(m)],
+                            &mut self.reg_a,
+                            f,
+                            &mut self.overflow,
+                        );
+                        forward!(1, 2);
+                    }
+                }
             }
             2 => {
-                // SUB
-                instruction::sub(
-                    &self.memory[m as usize],
-                    &mut self.reg_a,
-                    f,
-                    &mut self.overflow,
-                );
-                forward!(1, 2);
+                match f {
+                    6 => {
+                        // FSUB
+                        instruction::fsub(
+                            &self.memory[cell!(m)],
+                            &mut self.reg_a,
+                            &mut self.overflow,
+                        );
+                        forward!(1, 4);
+                    }
+                    _ => {
+                        // SUB
+                        field!();
+                        instruction::sub(
+                            &self.memory[cell!(m)],
+                            &mut self.reg_a,
+                            f,
+                            &mut self.overflow,
+                        );
+                        forward!(1, 2);
+                    }
+                }
             }
             3 => {
-                // MUL
-                instruction::mul(
-                    &self.memory[m as usize],
-                    &mut self.reg_a,
-                    &mut self.reg_x,
-                    f,
-                );
-                forward!(1, 10);
+                match f {
+                    6 => {
+                        // FMUL
+                        instruction::fmul(
+                            &self.memory[cell!(m)],
+                            &mut self.reg_a,
+                            &mut self.overflow,
+                        );
+                        forward!(1, 9);
+                    }
+                    _ => {
+                        // MUL
+                        field!();
+                        instruction::mul(
+                            &self.memory[cell!(m)],
+                            &mut self.reg_a,
+                            &mut self.reg_x,
+                            f,
+                        );
+                        forward!(1, 10);
+                    }
+                }
             }
             4 => {
-                // DIV
-                instruction::div(
-                    &self.memory[m as usize],
-                    &mut self.reg_a,
-                    &mut self.reg_x,
-                    f,
-                    &mut self.overflow,
-                );
-                forward!(1, 12);
+                match f {
+                    6 => {
+                        // FDIV
+                        instruction::fdiv(
+                            &self.memory[cell!(m)],
+                            &mut self.reg_a,
+                            &mut self.overflow,
+                        );
+                        forward!(1, 11);
+                    }
+                    _ => {
+                        // DIV
+                        field!();
+                        instruction::div(
+                            &self.memory[cell!(m)],
+                            &mut self.reg_a,
+                            &mut self.reg_x,
+                            f,
+                            &mut self.overflow,
+                        );
+                        forward!(1, 12);
+                    }
+                }
             }
             5 => {
-                // NUM/CHAR/HLT
+                // NUM/CHAR/HLT/FLOT/FIX
                 match f {
                     0 => {
                         // NUM
                         instruction::to_num(&mut self.reg_a, &self.reg_x, &mut self.overflow);
+                        forward!(1, 10);
                     }
                     1 => {
                         // CHAR
                         instruction::to_char(&mut self.reg_a, &mut self.reg_x);
+                        forward!(1, 10);
                     }
                     2 => {
                         // HLT
-                        // TODO: 何をやる？
-                        return Err(());
+                        // 次の step は HLT の次の命令から再開する
+                        self.halt = Some(HaltInfo {
+                            pc: current_pc,
+                            address: m,
+                        });
+                        forward!(1, 10);
+                        self.flush_io();
+                        self.tick_timer(self.clock - start_clock);
+                        return Ok(StepOutcome::Halted(current_pc, inst));
+                    }
+                    9 if self.interrupt_facility => {
+                        // INT
+                        if self.control {
+                            self.return_from_interrupt();
+                        } else {
+                            self.request_interrupt(INT_INTERRUPT);
+                            self.pc += 1;
+                        }
+                        forward!(0, 2);
+                    }
+                    6 => {
+                        // FLOT
+                        instruction::flot(&mut self.reg_a, &mut self.overflow);
+                        forward!(1, 3);
+                    }
+                    7 => {
+                        // FIX
+                        instruction::fix(&mut self.reg_a, &mut self.overflow);
+                        forward!(1, 3);
                     }
                     _ => {
-                        unreachable!();
+                        fault!(FaultKind::InvalidOpcode(c, f));
                     }
                 }
-                forward!(1, 10);
             }
             6 => {
                 // SLA/SRA/SLAX/SRAX/SLC/SRC
+                if m < 0 {
+                    fault!(FaultKind::InvalidShiftCount(m));
+                }
                 match f {
                     0 => {
-                        instruction::shift_left(m as i64, &mut self.reg_a);
+                        instruction::shift_left(m, &mut self.reg_a);
                     }
                     1 => {
-                        instruction::shift_right(m as i64, &mut self.reg_a);
+                        instruction::shift_right(m, &mut self.reg_a);
                     }
                     2 => {
-                        instruction::shift_left_pair(m as i64, &mut self.reg_a, &mut self.reg_x);
+                        instruction::shift_left_pair(m, &mut self.reg_a, &mut self.reg_x);
                     }
                     3 => {
-                        instruction::shift_right_pair(m as i64, &mut self.reg_a, &mut self.reg_x);
+                        instruction::shift_right_pair(m, &mut self.reg_a, &mut self.reg_x);
                     }
                     4 => {
-                        instruction::rotate_left_pair(m as i64, &mut self.reg_a, &mut self.reg_x);
+                        instruction::rotate_left_pair(m, &mut self.reg_a, &mut self.reg_x);
                     }
                     5 => {
-                        instruction::rotate_right_pair(m as i64, &mut self.reg_a, &mut self.reg_x);
+                        instruction::rotate_right_pair(m, &mut self.reg_a, &mut self.reg_x);
                     }
                     _ => {
-                        unreachable!();
+                        fault!(FaultKind::InvalidOpcode(c, f));
                     }
                 }
                 forward!(1, 2);
             }
             7 => {
                 // MOVE
-                instruction::mov(m as i64, &mut self.reg_i[0], f as i64, &mut self.memory);
+                let to = self.reg_i[0].val();
+                if f > 0 {
+                    let from = cell!(m);
+                    cell!(m + f as i64 - 1);
+                    let to = cell!(to);
+                    cell!(self.reg_i[0].val() + f as i64 - 1);
+                    instruction::mov(from, to, f, &mut self.memory);
+                }
+                self.reg_i[0] = Register::from_val(to + f as i64);
                 forward!(1, 1 + 2 * f);
             }
             8 => {
                 // LDA
-                instruction::load(&self.memory[m as usize], &mut self.reg_a, f);
+                field!();
+                instruction::load(&self.memory[cell!(m)], &mut self.reg_a, f);
                 forward!(1, 2);
             }
             9 => {
                 // LD1
-                instruction::load(&self.memory[m as usize], &mut self.reg_i[0], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::load(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(0, reg);
                 forward!(1, 2);
             }
             10 => {
                 // LD2
-                instruction::load(&self.memory[m as usize], &mut self.reg_i[1], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::load(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(1, reg);
                 forward!(1, 2);
             }
             11 => {
                 // LD3
-                instruction::load(&self.memory[m as usize], &mut self.reg_i[2], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::load(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(2, reg);
                 forward!(1, 2);
             }
             12 => {
                 // LD4
-                instruction::load(&self.memory[m as usize], &mut self.reg_i[3], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::load(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(3, reg);
                 forward!(1, 2);
             }
             13 => {
                 // LD5
-                instruction::load(&self.memory[m as usize], &mut self.reg_i[4], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::load(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(4, reg);
                 forward!(1, 2);
             }
             14 => {
                 // LD6
-                instruction::load(&self.memory[m as usize], &mut self.reg_i[5], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::load(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(5, reg);
                 forward!(1, 2);
             }
             15 => {
                 // LDX
-                instruction::load(&self.memory[m as usize], &mut self.reg_x, f);
+                field!();
+                instruction::load(&self.memory[cell!(m)], &mut self.reg_x, f);
                 forward!(1, 2);
             }
             16 => {
                 // LDAN
-                instruction::loadn(&self.memory[m as usize], &mut self.reg_a, f);
+                field!();
+                instruction::loadn(&self.memory[cell!(m)], &mut self.reg_a, f);
                 forward!(1, 2);
             }
             17 => {
                 // LD1N
-                instruction::loadn(&self.memory[m as usize], &mut self.reg_i[0], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::loadn(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(0, reg);
                 forward!(1, 2);
             }
             18 => {
                 // LD2N
-                instruction::loadn(&self.memory[m as usize], &mut self.reg_i[1], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::loadn(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(1, reg);
                 forward!(1, 2);
             }
             19 => {
                 // LD3N
-                instruction::loadn(&self.memory[m as usize], &mut self.reg_i[2], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::loadn(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(2, reg);
                 forward!(1, 2);
             }
             20 => {
                 // LD4N
-                instruction::loadn(&self.memory[m as usize], &mut self.reg_i[3], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::loadn(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(3, reg);
                 forward!(1, 2);
             }
             21 => {
                 // LD5N
-                instruction::loadn(&self.memory[m as usize], &mut self.reg_i[4], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::loadn(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(4, reg);
                 forward!(1, 2);
             }
             22 => {
                 // LD6N
-                instruction::loadn(&self.memory[m as usize], &mut self.reg_i[5], f);
+                field!();
+                let mut reg = Register::from_val(0);
+                instruction::loadn(&self.memory[cell!(m)], &mut reg, f);
+                index_register!(5, reg);
                 forward!(1, 2);
             }
             23 => {
                 // LDXN
-                instruction::loadn(&self.memory[m as usize], &mut self.reg_x, f);
+                field!();
+                instruction::loadn(&self.memory[cell!(m)], &mut self.reg_x, f);
                 forward!(1, 2);
             }
             24 => {
                 // STA
-                instruction::store(&self.reg_a, &mut self.memory[m as usize], f);
+                field!();
+                instruction::store(&self.reg_a, &mut self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             25 => {
                 // ST1
-                instruction::store(&self.reg_i[0], &mut self.memory[m as usize], f);
+                field!();
+                instruction::store(&self.reg_i[0], &mut self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             26 => {
                 // ST2
-                instruction::store(&self.reg_i[1], &mut self.memory[m as usize], f);
+                field!();
+                instruction::store(&self.reg_i[1], &mut self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             27 => {
                 // ST3
-                instruction::store(&self.reg_i[2], &mut self.memory[m as usize], f);
+                field!();
+                instruction::store(&self.reg_i[2], &mut self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             28 => {
                 // ST4
-                instruction::store(&self.reg_i[3], &mut self.memory[m as usize], f);
+                field!();
+                instruction::store(&self.reg_i[3], &mut self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             29 => {
                 // ST5
-                instruction::store(&self.reg_i[4], &mut self.memory[m as usize], f);
+                field!();
+                instruction::store(&self.reg_i[4], &mut self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             30 => {
                 // ST6
-                instruction::store(&self.reg_i[5], &mut self.memory[m as usize], f);
+                field!();
+                instruction::store(&self.reg_i[5], &mut self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             31 => {
                 // STX
-                instruction::store(&self.reg_x, &mut self.memory[m as usize], f);
+                field!();
+                instruction::store(&self.reg_x, &mut self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             32 => {
                 // STJ
-                instruction::store(&self.reg_j, &mut self.memory[m as usize], f);
-                forward!(0, 2);
+                field!();
+                instruction::store(&self.reg_j, &mut self.memory[cell!(m)], f);
+                forward!(1, 2);
             }
             33 => {
                 // STZ
-                instruction::store_zero(&mut self.memory[m as usize]);
+                instruction::store_zero(&mut self.memory[cell!(m)]);
                 forward!(1, 2);
             }
             34 => {
                 // JBUS
-                match f {
-                    0..=7 => {
-                        if self.tape[f].busy() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    8..=15 => {
-                        if self.disk[f].busy() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    16 => {
-                        if self.card_reader.busy() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    17 => {
-                        if self.card_punch.busy() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    18 => {
-                        if self.line_printer.busy() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    19 => {
-                        if self.type_writer_terminal.busy() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    20 => {
-                        if self.paper_tape.busy() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    _ => {
-                        unreachable!();
-                    }
+                let busy = match self.busy(f) {
+                    Some(busy) => busy,
+                    None => {
+                        fault!(FaultKind::InvalidDevice(f));
+                    }
+                };
+                if busy {
+                    jump!();
+                } else {
+                    self.pc += 1;
                 }
                 forward!(0, 1);
             }
             35 => {
                 // IOC
                 match f {
+                    0..=20 => {}
+                    _ => {
+                        fault!(FaultKind::InvalidDevice(f));
+                    }
+                }
+                if m != 0 && ((8..=15).contains(&f) || f == 18 || f == 20) {
+                    fault!(FaultKind::UnsupportedIoOperation(f));
+                }
+                if (8..=15).contains(&f) && !self.disk[f - 8].contains_block(self.reg_x.val()) {
+                    fault!(FaultKind::InvalidBlock(f, self.reg_x.val()));
+                }
+                // 装置が空くまで待ってから位置決めを始める
+                self.journal_unit(f);
+                self.wait_io(f);
+                let latency = self.latency(f);
+                let moved = match f {
                     0..=7 => {
-                        while self.tape[f].busy() {
-                            forward!(0, 1);
-                        }
-                        // tape is ready
+                        // tape
                         if m == 0 {
-                            self.tape[f].seek(-100);
+                            self.tape[f].seek(-100)
                         } else {
-                            self.tape[f].seek(m as i64);
+                            self.tape[f].seek(m)
                         }
                     }
                     8..=15 => {
-                        while self.disk[f].busy() {
-                            forward!(0, 1);
-                        }
-                        // disk is ready
-                        if m != 0 {
-                            panic!();
-                        }
-                        // TODO: rX に位置決めするとは？
+                        // disk: rX 番目のブロックに位置決めする
+                        self.disk[f - 8].seek_block(self.reg_x.val())
                     }
                     16 => {
-                        while self.card_reader.busy() {
-                            forward!(0, 1);
-                        }
-                        // card reader is ready
+                        // card reader
                         // TODO: card reader に IOC はない？
+                        0
                     }
                     17 => {
-                        while self.card_punch.busy() {
-                            forward!(0, 1);
-                        }
-                        // card punch is ready
+                        // card punch
                         // TODO: card punch に IOC はない？
+                        0
                     }
                     18 => {
-                        while self.line_printer.busy() {
-                            forward!(0, 1);
-                        }
-                        // line printer is ready
-                        if m != 0 {
-                            panic!();
-                        }
+                        // line printer
                         self.line_printer.next_page();
+                        1
                     }
                     19 => {
-                        while self.type_writer_terminal.busy() {
-                            forward!(0, 1);
-                        }
-                        // type writer terminal is ready
+                        // type writer terminal
                         // TODO: type writer terminal に IOC はない？
+                        0
                     }
                     20 => {
-                        while self.paper_tape.busy() {
-                            forward!(0, 1);
-                        }
-                        // paper tape is ready
-                        if m != 0 {
-                            panic!();
-                        }
-                        self.paper_tape.seek(-100);
+                        // paper tape
+                        self.paper_tape.seek(-100)
                     }
-                    _ => {
-                        unreachable!();
-                    }
-                }
-                // TODO: clock実装
+                    _ => unreachable!(),
+                };
+                self.start_io(f, latency.seek * moved as i64, Transfer::Control);
                 forward!(1, 1);
             }
             36 => {
                 // IN
+                let block_size = match MixVM::block_size(f) {
+                    Some(block_size) => block_size,
+                    None => {
+                        fault!(FaultKind::InvalidDevice(f));
+                    }
+                };
+                let begin = cell!(m);
+                cell!(m + block_size as i64 - 1);
                 match f {
-                    0..=7 => {
-                        while self.tape[f].busy() {
-                            forward!(0, 1);
-                        }
-                        // tape is ready
-                        let v = self.tape[f].read();
-                        for (i, x) in v.into_iter().enumerate() {
-                            self.memory[(m + i as i64) as usize] = x.clone();
-                        }
-                    }
-                    8..=15 => {
-                        while self.disk[f].busy() {
-                            forward!(0, 1);
-                        }
-                        // disk is ready
-                        let v = self.disk[f].read();
-                        for (i, x) in v.into_iter().enumerate() {
-                            self.memory[(m + i as i64) as usize] = x.clone();
-                        }
-                    }
-                    16 => {
-                        while self.card_reader.busy() {
-                            forward!(0, 1);
-                        }
-                        // card reader is ready
-                        let v = self.card_reader.read();
-                        for (i, x) in v.into_iter().enumerate() {
-                            self.memory[(m + i as i64) as usize] = x.clone();
-                        }
-                    }
-                    17 => {
-                        while self.card_punch.busy() {
-                            forward!(0, 1);
-                        }
-                        // card punch is ready
-                        let v = self.card_punch.read();
-                        for (i, x) in v.into_iter().enumerate() {
-                            self.memory[(m + i as i64) as usize] = x.clone();
-                        }
-                    }
-                    18 => {
-                        while self.line_printer.busy() {
-                            forward!(0, 1);
-                        }
-                        // line printer is ready
-                        let v = self.line_printer.read();
-                        for (i, x) in v.into_iter().enumerate() {
-                            self.memory[(m + i as i64) as usize] = x.clone();
-                        }
-                    }
-                    19 => {
-                        while self.type_writer_terminal.busy() {
-                            forward!(0, 1);
-                        }
-                        // type writer terminal is ready
-                        let v = self.type_writer_terminal.read();
-                        for (i, x) in v.into_iter().enumerate() {
-                            self.memory[(m + i as i64) as usize] = x.clone();
-                        }
-                    }
-                    20 => {
-                        while self.paper_tape.busy() {
-                            forward!(0, 1);
-                        }
-                        // paper tape is ready
-                        let v = self.paper_tape.read();
-                        for (i, x) in v.into_iter().enumerate() {
-                            self.memory[(m + i as i64) as usize] = x.clone();
-                        }
-                    }
-                    _ => {
-                        unreachable!();
+                    17 | 18 => {
+                        fault!(FaultKind::UnsupportedIoOperation(f));
                     }
+                    _ => {}
                 }
-                // TODO: clock実装
+                if (8..=15).contains(&f) && !self.disk[f - 8].contains_block(self.reg_x.val()) {
+                    fault!(FaultKind::InvalidBlock(f, self.reg_x.val()));
+                }
+                // 時刻や装置を変える前に, 読むデータが残っているか確かめる
+                if !self.can_read(f) {
+                    fault!(FaultKind::EndOfInput(f));
+                }
+                // 装置が空くまで待ってから転送を始める.
+                // memory への書き込みは転送が完了した時点で行う.
+                self.journal_unit(f);
+                self.wait_io(f);
+                let moved = match f {
+                    8..=15 => self.disk[f - 8].seek_block(self.reg_x.val()),
+                    _ => 0,
+                };
+                let v = match f {
+                    0..=7 => self.tape[f].read(),
+                    8..=15 => self.disk[f - 8].read(),
+                    16 => self.card_reader.read(),
+                    19 => self.type_writer_terminal.read(),
+                    20 => self.paper_tape.read(),
+                    _ => unreachable!(),
+                };
+                let v: Vec<WordImpl> = v.unwrap().into_iter().cloned().collect();
+                let latency = self.latency(f);
+                let time = latency.seek * moved as i64 + latency.transfer;
+                self.start_io(f, time, Transfer::Input(begin, v));
                 forward!(1, 1);
             }
             37 => {
                 // OUT
-                match f {
-                    0..=7 => {
-                        while self.tape[f].busy() {
-                            forward!(0, 1);
-                        }
-                        // tape is ready
-                        let mut v = vec![];
-                        for x in 0..iounit::Tape::block_size() {
-                            v.push(&self.memory[(m + x as i64) as usize]);
-                        }
-                        self.tape[f].write(v);
-                    }
-                    8..=15 => {
-                        while self.disk[f].busy() {
-                            forward!(0, 1);
-                        }
-                        // disk is ready
-                        let mut v = vec![];
-                        for x in 0..iounit::Disk::block_size() {
-                            v.push(&self.memory[(m + x as i64) as usize]);
-                        }
-                        self.disk[f].write(v);
-                    }
-                    16 => {
-                        while self.card_reader.busy() {
-                            forward!(0, 1);
-                        }
-                        // card reader is ready
-                        let mut v = vec![];
-                        for x in 0..iounit::CardReader::block_size() {
-                            v.push(&self.memory[(m + x as i64) as usize]);
-                        }
-                        self.card_reader.write(v);
-                    }
-                    17 => {
-                        while self.card_punch.busy() {
-                            forward!(0, 1);
-                        }
-                        // card punch is ready
-                        let mut v = vec![];
-                        for x in 0..iounit::CardPunch::block_size() {
-                            v.push(&self.memory[(m + x as i64) as usize]);
-                        }
-                        self.card_punch.write(v);
-                    }
-                    18 => {
-                        while self.line_printer.busy() {
-                            forward!(0, 1);
-                        }
-                        // line printer is ready
-                        let mut v = vec![];
-                        for x in 0..iounit::LinePrinter::block_size() {
-                            v.push(&self.memory[(m + x as i64) as usize]);
-                        }
-                        self.line_printer.write(v);
-                    }
-                    19 => {
-                        while self.type_writer_terminal.busy() {
-                            forward!(0, 1);
-                        }
-                        // type writer terminal is ready
-                        let mut v = vec![];
-                        for x in 0..iounit::TypeWriterTerminal::block_size() {
-                            v.push(&self.memory[(m + x as i64) as usize]);
-                        }
-                        self.type_writer_terminal.write(v);
-                    }
-                    20 => {
-                        while self.paper_tape.busy() {
-                            forward!(0, 1);
-                        }
-                        // paper tape is ready
-                        let mut v = vec![];
-                        for x in 0..iounit::PaperTape::block_size() {
-                            v.push(&self.memory[(m + x as i64) as usize]);
-                        }
-                        self.paper_tape.write(v);
-                    }
-                    _ => {
-                        unreachable!();
-                    }
+                let block_size = match MixVM::block_size(f) {
+                    Some(block_size) => block_size,
+                    None => {
+                        fault!(FaultKind::InvalidDevice(f));
+                    }
+                };
+                let begin = cell!(m);
+                cell!(m + block_size as i64 - 1);
+                if f == 16 {
+                    fault!(FaultKind::UnsupportedIoOperation(f));
+                }
+                if (8..=15).contains(&f) && !self.disk[f - 8].contains_block(self.reg_x.val()) {
+                    fault!(FaultKind::InvalidBlock(f, self.reg_x.val()));
                 }
-                // TODO: clock実装
+                // 装置が空くまで待ってから転送を始める.
+                // memory からの読み出しと装置への書き込みは転送が完了した時点で行う
+                // (TAOCP 1.4.4). それまでに書き換えた語が出力される.
+                self.journal_unit(f);
+                self.wait_io(f);
+                let moved = match f {
+                    8..=15 => self.disk[f - 8].seek_block(self.reg_x.val()),
+                    _ => 0,
+                };
+                let latency = self.latency(f);
+                let time = latency.seek * moved as i64 + latency.transfer;
+                self.start_io(f, time, Transfer::Output(begin));
                 forward!(1, 1);
             }
             38 => {
                 // JRED
-                match f {
-                    0..=7 => {
-                        if self.tape[f].ready() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    8..=15 => {
-                        if self.disk[f].ready() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    16 => {
-                        if self.card_reader.ready() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    17 => {
-                        if self.card_punch.ready() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    18 => {
-                        if self.line_printer.ready() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    19 => {
-                        if self.type_writer_terminal.ready() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    20 => {
-                        if self.paper_tape.ready() {
-                            jump!();
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                    _ => {
-                        unreachable!();
-                    }
+                let ready = match self.ready(f) {
+                    Some(ready) => ready,
+                    None => {
+                        fault!(FaultKind::InvalidDevice(f));
+                    }
+                };
+                if ready {
+                    jump!();
+                } else {
+                    self.pc += 1;
                 }
                 forward!(0, 1);
             }
@@ -836,13 +1672,14 @@ impl MixVM {
                     }
                     1 => {
                         // JSJ
-                        self.pc = m as usize;
+                        cell!(m);
+                        self.pc = m;
                     }
                     2 => {
                         // JOV
                         if self.overflow {
-                            self.overflow = false;
                             jump!();
+                            self.overflow = false;
                         } else {
                             self.pc += 1;
                         }
@@ -905,7 +1742,7 @@ impl MixVM {
                         }
                     }
                     _ => {
-                        unreachable!();
+                        fault!(FaultKind::InvalidOpcode(c, f));
                     }
                 }
                 forward!(0, 1);
@@ -956,32 +1793,44 @@ impl MixVM {
             }
             49 => {
                 // INC1/DEC1/ENT1/ENN1
-                macro48_55!(&mut self.reg_i[0]);
+                let mut reg = self.reg_i[0].clone();
+                macro48_55!(&mut reg);
+                index_register!(0, reg);
                 forward!(1, 1);
             }
             50 => {
                 // INC2/DEC2/ENT2/ENN2
-                macro48_55!(&mut self.reg_i[1]);
+                let mut reg = self.reg_i[1].clone();
+                macro48_55!(&mut reg);
+                index_register!(1, reg);
                 forward!(1, 1);
             }
             51 => {
                 // INC3/DEC3/ENT3/ENN3
-                macro48_55!(&mut self.reg_i[2]);
+                let mut reg = self.reg_i[2].clone();
+                macro48_55!(&mut reg);
+                index_register!(2, reg);
                 forward!(1, 1);
             }
             52 => {
                 // INC4/DEC4/ENT4/ENN4
-                macro48_55!(&mut self.reg_i[3]);
+                let mut reg = self.reg_i[3].clone();
+                macro48_55!(&mut reg);
+                index_register!(3, reg);
                 forward!(1, 1);
             }
             53 => {
                 // INC5/DEC5/ENT5/ENN5
-                macro48_55!(&mut self.reg_i[4]);
+                let mut reg = self.reg_i[4].clone();
+                macro48_55!(&mut reg);
+                index_register!(4, reg);
                 forward!(1, 1);
             }
             54 => {
                 // INC6/DEC6/ENT6/ENN6
-                macro48_55!(&mut self.reg_i[5]);
+                let mut reg = self.reg_i[5].clone();
+                macro48_55!(&mut reg);
+                index_register!(5, reg);
                 forward!(1, 1);
             }
             55 => {
@@ -990,50 +1839,73 @@ impl MixVM {
                 forward!(1, 1);
             }
             56 => {
-                // CMPA
-                self.comp = instruction::comp(&self.reg_a, &self.memory[m as usize], f);
-                forward!(1, 2);
+                match f {
+                    6 => {
+                        // FCMP
+                        self.comp = instruction::fcmp(
+                            &self.reg_a,
+                            &self.memory[cell!(m)],
+                            self.control_word(0),
+                        );
+                        forward!(1, 4);
+                    }
+                    _ => {
+                        // CMPA
+                        field!();
+                        self.comp = instruction::comp(&self.reg_a, &self.memory[cell!(m)], f);
+                        forward!(1, 2);
+                    }
+                }
             }
             57 => {
                 // CMP1
-                self.comp = instruction::comp(&self.reg_i[0], &self.memory[m as usize], f);
+                field!();
+                self.comp = instruction::comp(&self.reg_i[0], &self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             58 => {
                 // CMP2
-                self.comp = instruction::comp(&self.reg_i[1], &self.memory[m as usize], f);
+                field!();
+                self.comp = instruction::comp(&self.reg_i[1], &self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             59 => {
                 // CMP3
-                self.comp = instruction::comp(&self.reg_i[2], &self.memory[m as usize], f);
+                field!();
+                self.comp = instruction::comp(&self.reg_i[2], &self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             60 => {
                 // CMP4
-                self.comp = instruction::comp(&self.reg_i[3], &self.memory[m as usize], f);
+                field!();
+                self.comp = instruction::comp(&self.reg_i[3], &self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             61 => {
                 // CMP5
-                self.comp = instruction::comp(&self.reg_i[4], &self.memory[m as usize], f);
+                field!();
+                self.comp = instruction::comp(&self.reg_i[4], &self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             62 => {
                 // CMP6
-                self.comp = instruction::comp(&self.reg_i[5], &self.memory[m as usize], f);
+                field!();
+                self.comp = instruction::comp(&self.reg_i[5], &self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             63 => {
                 // CMPX
-                self.comp = instruction::comp(&self.reg_x, &self.memory[m as usize], f);
+                field!();
+                self.comp = instruction::comp(&self.reg_x, &self.memory[cell!(m)], f);
                 forward!(1, 2);
             }
             _ => {
-                unreachable!();
+                fault!(FaultKind::InvalidOpcode(c, f));
             }
         }
 
-        Ok((current_pc, inst))
+        self.halt = None;
+        self.tick_timer(self.clock - start_clock);
+        Ok(StepOutcome::Stepped(current_pc, inst))
     }
 }
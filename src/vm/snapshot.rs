@@ -0,0 +1,362 @@
+// MixVM の状態を行単位のテキストとして保存, 復元する.
+//
+// MIX SNAPSHOT 1
+// byte 64
+// pc 3000
+// clock 1234
+// rA +0.0.0.0.1                   (符号と 5 バイト)
+// mem -1 +0.0.0.0.1               (0 でない語だけ)
+// unit 18 24 7500 -               (装置, 位置, 転送が終わる時刻, 所要時間)
+// data 18 +0.0.0.0.0 ...          (1 ブロックずつ)
+// io 18 7500 out 2000            (転送中の入出力)
+// end
+use super::{MixVM, PendingIo, Transfer, CONTROL_MEMORY_SIZE};
+use crate::iounit::{IOUnit, Latency, UnitState};
+use crate::mix_word::{str_to_word, word_to_str, Byte, Sign, WordImpl};
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+const HEADER: &str = "MIX SNAPSHOT 1";
+const UNITS: usize = 21;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    // 読めなかった行 (1 始まり) と理由
+    Parse(usize, String),
+    // 保存したときと Byte::max() が異なる
+    ByteSize(u32),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::Parse(line, message) => write!(f, "line {}: {}", line, message),
+            SnapshotError::ByteSize(size) => write!(
+                f,
+                "snapshot uses byte size {} but the machine uses {}",
+                size,
+                Byte::max()
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl MixVM {
+    fn unit_state(&self, f: usize) -> UnitState {
+        match f {
+            0..=7 => self.tape[f].save_state(),
+            8..=15 => self.disk[f - 8].save_state(),
+            16 => self.card_reader.save_state(),
+            17 => self.card_punch.save_state(),
+            18 => self.line_printer.save_state(),
+            19 => self.type_writer_terminal.save_state(),
+            20 => self.paper_tape.save_state(),
+            _ => unreachable!(),
+        }
+    }
+    fn set_unit_state(&mut self, f: usize, state: UnitState) {
+        match f {
+            0..=7 => self.tape[f].restore_state(state),
+            8..=15 => self.disk[f - 8].restore_state(state),
+            16 => self.card_reader.restore_state(state),
+            17 => self.card_punch.restore_state(state),
+            18 => self.line_printer.restore_state(state),
+            19 => self.type_writer_terminal.restore_state(state),
+            20 => self.paper_tape.restore_state(state),
+            _ => unreachable!(),
+        }
+    }
+
+    // 機械の状態をすべて書き出す. 直前の step の Access は保存しない.
+    pub fn write_snapshot<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let words = |words: &[WordImpl]| {
+            words
+                .iter()
+                .map(word_to_str)
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        writeln!(w, "{}", HEADER)?;
+        writeln!(w, "byte {}", Byte::max())?;
+        writeln!(w, "pc {}", self.pc)?;
+        writeln!(w, "clock {}", self.clock)?;
+        writeln!(w, "rA {}", word_to_str(&self.reg_a))?;
+        writeln!(w, "rX {}", word_to_str(&self.reg_x))?;
+        for (k, reg) in self.reg_i.iter().enumerate() {
+            writeln!(w, "rI{} {}", k + 1, word_to_str(reg))?;
+        }
+        writeln!(w, "rJ {}", word_to_str(&self.reg_j))?;
+        writeln!(w, "overflow {}", self.overflow as i64)?;
+        let comp = match self.comp {
+            Ordering::Less => "<",
+            Ordering::Equal => "=",
+            Ordering::Greater => ">",
+        };
+        writeln!(w, "comp {}", comp)?;
+        writeln!(
+            w,
+            "interrupt {} {}",
+            self.interrupt_facility as i64, self.control as i64
+        )?;
+        for location in self.pending_interrupts.iter() {
+            writeln!(w, "pending {}", location)?;
+        }
+        if let Some(halt) = &self.halt {
+            writeln!(w, "halt {} {}", halt.pc, halt.address)?;
+        }
+        for (index, word) in self.memory.iter().enumerate() {
+            if word.val() != 0 || *word.sign() == Sign::Negative {
+                let address = index as i64 - CONTROL_MEMORY_SIZE as i64;
+                writeln!(w, "mem {} {}", address, word_to_str(word))?;
+            }
+        }
+        for f in 0..UNITS {
+            let state = self.unit_state(f);
+            let latency = match state.latency {
+                Some(latency) => format!("{} {}", latency.transfer, latency.seek),
+                None => "-".to_string(),
+            };
+            writeln!(
+                w,
+                "unit {} {} {} {}",
+                f, state.pos, state.busy_until, latency
+            )?;
+            let block_size = MixVM::block_size(f).unwrap();
+            for block in state.data.chunks(block_size) {
+                writeln!(w, "data {} {}", f, words(block))?;
+            }
+            if (8..=15).contains(&f) {
+                writeln!(w, "capacity {} {}", f, self.disk[f - 8].capacity())?;
+            }
+        }
+        for page in self.line_printer.pages() {
+            writeln!(w, "page {}", page)?;
+        }
+        for pending in self.pending_io.iter() {
+            let transfer = match &pending.transfer {
+                Transfer::Input(begin, v) => format!(
+                    "in {} {}",
+                    *begin as i64 - CONTROL_MEMORY_SIZE as i64,
+                    words(v)
+                ),
+                Transfer::Output(begin) => {
+                    format!("out {}", *begin as i64 - CONTROL_MEMORY_SIZE as i64)
+                }
+                Transfer::Control => "control".to_string(),
+            };
+            writeln!(w, "io {} {} {}", pending.device, pending.done, transfer)?;
+        }
+        writeln!(w, "end")
+    }
+
+    // write_snapshot で書き出した状態を読み込む
+    pub fn read_snapshot<R: BufRead>(r: R) -> Result<MixVM, SnapshotError> {
+        let mut vm = MixVM::new();
+        let mut units: Vec<UnitState> = (0..UNITS)
+            .map(|_| UnitState {
+                data: vec![],
+                pos: 0,
+                busy_until: 0,
+                latency: None,
+            })
+            .collect();
+        let mut pages = vec![];
+        let mut header = false;
+        let mut end = false;
+
+        for (n, line) in r.lines().enumerate() {
+            let line = line?;
+            let n = n + 1;
+            let error = |message: &str| SnapshotError::Parse(n, message.to_string());
+            if !header {
+                if line.trim() != HEADER {
+                    return Err(error("not a MIX snapshot"));
+                }
+                header = true;
+                continue;
+            }
+            let terms: Vec<&str> = line.split_whitespace().collect();
+            if terms.is_empty() {
+                continue;
+            }
+            if end {
+                return Err(error("unexpected line after end"));
+            }
+            let int = |i: usize| -> Result<i64, SnapshotError> {
+                terms
+                    .get(i)
+                    .and_then(|t| t.parse::<i64>().ok())
+                    .ok_or_else(|| error("expected an integer"))
+            };
+            // 位置, 時刻, 所要時間などは負にならない
+            let count = |i: usize| -> Result<usize, SnapshotError> {
+                match int(i)? {
+                    v if v >= 0 => Ok(v as usize),
+                    _ => Err(error("expected a non-negative integer")),
+                }
+            };
+            let word = |i: usize| -> Result<WordImpl, SnapshotError> {
+                terms
+                    .get(i)
+                    .and_then(|t| str_to_word(t))
+                    .ok_or_else(|| error("expected a word"))
+            };
+            let words = |from: usize| -> Result<Vec<WordImpl>, SnapshotError> {
+                (from..terms.len()).map(word).collect()
+            };
+            let unit = |i: usize| -> Result<usize, SnapshotError> {
+                let f = int(i)?;
+                if 0 <= f && f < UNITS as i64 {
+                    Ok(f as usize)
+                } else {
+                    Err(error("invalid unit"))
+                }
+            };
+            let flag = |i: usize| -> Result<bool, SnapshotError> {
+                match int(i)? {
+                    0 => Ok(false),
+                    1 => Ok(true),
+                    _ => Err(error("expected 0 or 1")),
+                }
+            };
+
+            match terms[0] {
+                "byte" => {
+                    let size = count(1)? as u32;
+                    if size != Byte::max() {
+                        return Err(SnapshotError::ByteSize(size));
+                    }
+                }
+                "pc" => vm.pc = int(1)?,
+                "clock" => vm.clock = count(1)? as i64,
+                "rA" => vm.reg_a = word(1)?,
+                "rX" => vm.reg_x = word(1)?,
+                "rI1" | "rI2" | "rI3" | "rI4" | "rI5" | "rI6" => {
+                    let k = terms[0][2..].parse::<usize>().unwrap();
+                    vm.reg_i[k - 1] = word(1)?;
+                }
+                "rJ" => vm.reg_j = word(1)?,
+                "overflow" => vm.overflow = flag(1)?,
+                "comp" => {
+                    vm.comp = match terms.get(1) {
+                        Some(&"<") => Ordering::Less,
+                        Some(&"=") => Ordering::Equal,
+                        Some(&">") => Ordering::Greater,
+                        _ => return Err(error("expected <, = or >")),
+                    }
+                }
+                "interrupt" => {
+                    vm.interrupt_facility = flag(1)?;
+                    vm.control = flag(2)?;
+                }
+                "pending" => {
+                    vm.pending_interrupts.insert(int(1)?);
+                }
+                "halt" => {
+                    vm.halt = Some(super::HaltInfo {
+                        pc: int(1)?,
+                        address: int(2)?,
+                    })
+                }
+                "mem" => {
+                    let address = int(1)?;
+                    match MixVM::cell_index(true, address) {
+                        Some(index) => vm.memory[index] = word(2)?,
+                        None => return Err(error("address out of range")),
+                    }
+                }
+                "unit" => {
+                    let f = unit(1)?;
+                    units[f].pos = count(2)?;
+                    units[f].busy_until = count(3)? as i64;
+                    units[f].latency = match terms.get(4) {
+                        Some(&"-") => None,
+                        _ => Some(Latency {
+                            transfer: count(4)? as i64,
+                            seek: count(5)? as i64,
+                        }),
+                    };
+                }
+                "data" => {
+                    let f = unit(1)?;
+                    units[f].data.extend(words(2)?);
+                }
+                "capacity" => {
+                    let f = unit(1)?;
+                    if !(8..=15).contains(&f) {
+                        return Err(error("capacity is only for disks"));
+                    }
+                    vm.disk[f - 8].set_capacity(count(2)?);
+                }
+                "page" => pages.push(count(1)?),
+                "io" => {
+                    let device = unit(1)?;
+                    let done = count(2)? as i64;
+                    let block_size = MixVM::block_size(device).unwrap();
+                    // IN と OUT の命令と同じく, 装置が対応している向きだけ
+                    let transfer = match terms.get(3) {
+                        Some(&"in") if device == 17 || device == 18 => {
+                            return Err(error("device does not support input"))
+                        }
+                        Some(&"out") if device == 16 => {
+                            return Err(error("device does not support output"))
+                        }
+                        Some(&"in") => {
+                            let address = int(4)?;
+                            let begin = match MixVM::cell_index(true, address) {
+                                Some(index) => index,
+                                None => return Err(error("address out of range")),
+                            };
+                            let v = words(5)?;
+                            if v.len() != block_size {
+                                return Err(error("expected a block of words"));
+                            }
+                            if begin + v.len() > vm.memory.len() {
+                                return Err(error("address out of range"));
+                            }
+                            Transfer::Input(begin, v)
+                        }
+                        Some(&"out") => {
+                            let address = int(4)?;
+                            let begin = match MixVM::cell_index(true, address) {
+                                Some(index) => index,
+                                None => return Err(error("address out of range")),
+                            };
+                            if begin + block_size > vm.memory.len() {
+                                return Err(error("address out of range"));
+                            }
+                            Transfer::Output(begin)
+                        }
+                        Some(&"control") => Transfer::Control,
+                        _ => return Err(error("expected in, out or control")),
+                    };
+                    vm.pending_io.push(PendingIo {
+                        device,
+                        done,
+                        transfer,
+                    });
+                }
+                "end" => end = true,
+                _ => return Err(error("unknown item")),
+            }
+        }
+        if !end {
+            return Err(SnapshotError::Parse(0, "missing end".to_string()));
+        }
+
+        for (f, state) in units.into_iter().enumerate() {
+            vm.set_unit_state(f, state);
+        }
+        vm.line_printer.set_pages(pages);
+        Ok(vm)
+    }
+}
@@ -0,0 +1,98 @@
+use mix_emulator::asm::assemble;
+use mix_emulator::tools::profile_calls;
+use mix_emulator::vm::MixVM;
+
+const CODE: &str = " ORIG 1000
+MAIN JMP SQ
+ JMP SQ
+ JMP TWICE
+ HLT
+* rA に rA の 2 乗を加える
+SQ STJ 1F
+ STA TEMP
+ MUL TEMP
+ STX TEMP
+ LDA TEMP
+1H JMP *
+* SQ を 2 回呼ぶ
+TWICE STJ 9F
+ JMP SQ
+ JMP SQ
+9H JMP *
+TEMP CON 0
+ END MAIN";
+
+#[test]
+fn test_call_graph() {
+    let program = assemble(CODE).unwrap();
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    let (info, graph) = profile_calls(&mut vm, &program.symbols).unwrap();
+    assert_eq!(info.halt().unwrap().pc, 1003);
+
+    // SQ は 1 回 19u. 主プログラムは JMP 3 回と HLT
+    let routines: Vec<(String, usize, usize, usize)> = graph
+        .routines()
+        .iter()
+        .map(|(entry, r)| (graph.name(*entry), r.calls, r.exclusive, r.inclusive))
+        .collect();
+    assert_eq!(
+        routines,
+        [
+            ("MAIN".to_string(), 1, 13, 94),
+            ("SQ".to_string(), 4, 76, 76),
+            ("TWICE".to_string(), 1, 5, 43)
+        ]
+    );
+    assert_eq!(
+        info.count_clocks(0, 3999).unwrap(),
+        graph.routines()[&1000].inclusive
+    );
+    assert_eq!(graph.edges()[&(1000, 1004)], (2, 38));
+    assert_eq!(graph.edges()[&(1010, 1004)], (2, 38));
+
+    let expected = "\
+ROUTINE    ENTRY  CALLS     SELF    TOTAL      %
+MAIN       1000      1       13       94 100.0%
+SQ         1004      4       76       76  80.9%
+TWICE      1010      1        5       43  45.7%
+
+CALLER     CALLEE      CALLS     TIME
+MAIN       SQ              2       38
+MAIN       TWICE           1       43
+TWICE      SQ              2       38
+";
+    assert_eq!(expected, graph.report());
+    let expected = "\
+MAIN 13
+MAIN;SQ 38
+MAIN;TWICE 5
+MAIN;TWICE;SQ 38
+";
+    assert_eq!(expected, graph.folded());
+    let dot = graph.dot();
+    assert!(dot.starts_with("digraph calls {\n"));
+    assert!(dot.contains("    \"SQ\" [label=\"SQ\\n76u (80.9%)\\nself 76u\\n4 calls\"];\n"));
+    assert!(dot.contains("    \"MAIN\" -> \"TWICE\" [label=\"1 calls\\n43u\"];\n"));
+    assert!(dot.ends_with("}\n"));
+}
+
+#[test]
+fn test_jumps_without_stj() {
+    // STJ で始まらない番地への飛び越しは呼び出しではない
+    let code = " ORIG 100
+START ENT1 3
+LOOP DEC1 1
+ J1P LOOP
+ JMP DONE
+ NOP
+DONE HLT
+ END START";
+    let program = assemble(code).unwrap();
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    let (_, graph) = profile_calls(&mut vm, &program.symbols).unwrap();
+    assert_eq!(graph.routines().len(), 1);
+    assert!(graph.edges().is_empty());
+    assert_eq!(graph.folded(), "START 18\n");
+}
@@ -0,0 +1,362 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+const ECHO: &str = "         ORIG 1000
+BUF      ORIG *+24
+START    IN   BUF(16)
+         JBUS *(16)
+         OUT  BUF(18)
+         HLT  5
+         END  START
+";
+
+fn temp_file(name: &str, content: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("mix-cli-{}-{}", std::process::id(), name));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn mix(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_mix"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_run() {
+    let source = temp_file("echo.mixal", ECHO);
+    let cards = temp_file("cards.txt", "HELLO WORLD\n");
+    let output = mix(&[
+        "run",
+        source.to_str().unwrap(),
+        "--card",
+        cards.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!("HELLO WORLD", stdout.trim_end());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("halted at 1027 (M = 5)"));
+
+    // 印字をファイルに書き出す
+    let printer = std::env::temp_dir().join(format!("mix-cli-{}-printer.txt", std::process::id()));
+    let output = mix(&[
+        "run",
+        source.to_str().unwrap(),
+        "--card",
+        cards.to_str().unwrap(),
+        "--printer",
+        printer.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        "HELLO WORLD",
+        fs::read_to_string(&printer).unwrap().trim_end()
+    );
+}
+
+#[test]
+fn test_fault_and_usage() {
+    // カードがないので IN が失敗する
+    let source = temp_file("nocard.mixal", ECHO);
+    let output = mix(&["run", source.to_str().unwrap()]);
+    assert_eq!(Some(2), output.status.code());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("no more input on device 16"));
+
+    let output = mix(&["run", source.to_str().unwrap(), "--bogus"]);
+    assert_eq!(Some(1), output.status.code());
+    let output = mix(&[]);
+    assert_eq!(Some(1), output.status.code());
+}
+
+#[test]
+fn test_assemble_errors() {
+    let source = temp_file("errors.mixal", " LDA X\n FOO 1\nX NOP\nX NOP\n END 0\n");
+    let output = mix(&["assemble", source.to_str().unwrap()]);
+    assert_eq!(Some(1), output.status.code());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let path = source.to_str().unwrap();
+    assert!(stderr.contains(&format!("{}:2:2: unknown operation FOO", path)));
+    assert!(stderr.contains(&format!("{}:4:1: symbol X is already defined", path)));
+}
+
+#[test]
+fn test_assemble_listing() {
+    let source = temp_file("listing.mixal", ECHO);
+    let output = mix(&["assemble", source.to_str().unwrap(), "--listing"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("1026 + 1000 00 18 37     5           OUT  BUF(18)"));
+    assert!(stdout.contains("START       1024"));
+}
+
+#[test]
+fn test_object_file() {
+    let source = temp_file("object.mixal", ECHO);
+    let object = temp_file("echo.mixo", "");
+    let output = mix(&[
+        "assemble",
+        source.to_str().unwrap(),
+        "--object",
+        object.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let text = fs::read_to_string(&object).unwrap();
+    assert!(text.starts_with("MIX OBJECT 1\n"));
+    assert!(text.contains("\nsym START 1024\n"));
+
+    let cards = temp_file("object-cards.txt", "HELLO WORLD\n");
+    let output = mix(&[
+        "run",
+        object.to_str().unwrap(),
+        "--card",
+        cards.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("HELLO WORLD"));
+}
+
+#[test]
+fn test_go() {
+    let source = temp_file("go.mixal", ECHO);
+    let deck = temp_file("go.deck", "");
+    let output = mix(&[
+        "assemble",
+        source.to_str().unwrap(),
+        "--deck",
+        deck.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let text = fs::read_to_string(&deck).unwrap();
+    assert!(text.ends_with("TRANS01024\n"));
+
+    let cards = temp_file("go-cards.txt", "HELLO WORLD\n");
+    let output = mix(&[
+        "run",
+        source.to_str().unwrap(),
+        "--go",
+        "--card",
+        cards.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("HELLO WORLD"));
+}
+
+#[test]
+fn test_link() {
+    let library = temp_file(
+        "print.mixal",
+        " ENTRY PRINT
+ EXTERN MSG
+PRINT STJ EXIT
+ OUT MSG(18)
+ JBUS *(18)
+EXIT JMP *
+ END
+",
+    );
+    let main = temp_file(
+        "main.mixal",
+        " ENTRY MSG
+ EXTERN PRINT
+START JMP PRINT
+ HLT
+MSG ALF HELLO
+ ORIG MSG+24
+ END START
+",
+    );
+    let object = temp_file("print.mixo", "");
+    let output = mix(&[
+        "assemble",
+        library.to_str().unwrap(),
+        "--module",
+        "--object",
+        object.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+
+    // ソースとモジュールのオブジェクトファイルを混ぜてリンクできる
+    let linked = temp_file("linked.mixo", "");
+    let output = mix(&[
+        "link",
+        main.to_str().unwrap(),
+        object.to_str().unwrap(),
+        "--origin",
+        "1000",
+        "--object",
+        linked.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let text = fs::read_to_string(&linked).unwrap();
+    assert!(text.contains("\nentry 1000\n"));
+    assert!(text.contains("\nlinkage PRINT 1029\n"));
+
+    let output = mix(&["run", linked.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("HELLO"));
+
+    let output = mix(&["link", object.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("external symbol MSG is not defined"));
+}
+
+#[test]
+fn test_include() {
+    // INCLUDE のファイルはソースのディレクトリから探す
+    let defs = temp_file(
+        "defs.mixal",
+        "PRINT MACRO MSG\n OUT MSG(18)\n JBUS *(18)\n ENDM\n",
+    );
+    let name = defs.file_name().unwrap().to_str().unwrap();
+    let source = temp_file(
+        "include.mixal",
+        &format!(
+            " INCLUDE {}\n ORIG 100\nSTART PRINT TEXT\n HLT\nTEXT ALF HELLO\n END START\n",
+            name
+        ),
+    );
+    let output = mix(&["run", source.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("HELLO"));
+
+    let output = mix(&["assemble", source.to_str().unwrap(), "--listing"]);
+    let listing = String::from_utf8(output.stdout).unwrap();
+    assert!(listing.contains("0100 + 0103 00 18 37     +   OUT TEXT(18)\n"));
+}
+
+#[test]
+fn test_debug() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let source = temp_file("debug.mixal", ECHO);
+    let cards = temp_file("debug-cards.txt", "HELLO\n");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mix"))
+        .args([
+            "debug",
+            source.to_str().unwrap(),
+            "--card",
+            cards.to_str().unwrap(),
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"break 5\ncontinue\nprint 1001\nc\noutput\nquit\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("1024  IN BUF(16)"));
+    assert!(stdout.contains("(mix) breakpoint at 1026 (START+2), line 5\n"));
+    assert!(stdout.contains("(mix) 1001 (BUF+1)\n"));
+    assert!(stdout.contains("(mix) halted at 1027 (M = 5)\n(mix) HELLO"));
+}
+
+#[test]
+fn test_profile() {
+    let source = temp_file("profile.mixal", ECHO);
+    let cards = temp_file("profile-cards.txt", "HELLO\n");
+    let profile = std::env::temp_dir().join(format!("mix-cli-{}-profile.csv", std::process::id()));
+    let output = mix(&[
+        "run",
+        source.to_str().unwrap(),
+        "--card",
+        cards.to_str().unwrap(),
+        "--profile",
+        profile.to_str().unwrap(),
+        "--csv",
+    ]);
+    assert!(output.status.success());
+    let csv = fs::read_to_string(&profile).unwrap();
+    assert!(csv.starts_with("line,expanded,address,count,time,source\n3,false,1024,1,"));
+    assert!(csv.ends_with("6,false,1027,1,10,\"HLT  5\"\n"));
+}
+
+#[test]
+fn test_calls() {
+    let source = temp_file(
+        "calls.mixal",
+        " ORIG 1000\nMAIN JMP SUB\n JMP SUB\n HLT\nSUB STJ 1F\n1H JMP *\n END MAIN\n",
+    );
+    let output = mix(&["run", source.to_str().unwrap(), "--calls", "-", "--folded"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "MAIN 12\nMAIN;SUB 6\n");
+    let output = mix(&["run", source.to_str().unwrap(), "--calls", "-", "--dot"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("    \"MAIN\" -> \"SUB\" [label=\"2 calls\\n6u\"];\n"));
+}
+
+#[test]
+fn test_trace_diff() {
+    let reference = temp_file(
+        "reference.mixal",
+        " ORIG 100\nSTART ENTA 2\n INCA 3\n HLT\n END START\n",
+    );
+    let student = temp_file(
+        "student.mixal",
+        " ORIG 100\nSTART ENTA 2\n INCA 2\n HLT\n END START\n",
+    );
+    let a = std::env::temp_dir().join(format!("mix-cli-{}-a.trace", std::process::id()));
+    let b = std::env::temp_dir().join(format!("mix-cli-{}-b.trace", std::process::id()));
+    for (source, trace) in [(&reference, &a), (&student, &b)] {
+        let output = mix(&[
+            "run",
+            source.to_str().unwrap(),
+            "--record",
+            trace.to_str().unwrap(),
+        ]);
+        assert!(output.status.success());
+    }
+    assert!(fs::read_to_string(&a)
+        .unwrap()
+        .starts_with("MIX TRACE 1\n1 100 +0.2.0.2.48 m=2 clock=1 rA=+2 | ENTA 2\n"));
+
+    let output = mix(&["trace-diff", a.to_str().unwrap(), b.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "traces differ at entry 2
+< 2 101 +0.3.0.0.48 m=3 clock=2 rA=+5 | INCA 3
+> 2 101 +0.2.0.0.48 m=2 clock=2 rA=+4 | INCA 2
+"
+    );
+    let output = mix(&["trace-diff", a.to_str().unwrap(), a.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "traces match (3 steps)\n"
+    );
+
+    let output = mix(&[
+        "run",
+        reference.to_str().unwrap(),
+        "--record",
+        "-",
+        "--op",
+        "hlt",
+    ]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "MIX TRACE 1\n3 102 +0.0.0.2.5 m=0 clock=12 | HLT\n");
+}
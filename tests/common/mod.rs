@@ -0,0 +1,11 @@
+use mix_emulator::asm::debug_assemble;
+use mix_emulator::vm::MixVM;
+
+// code をアセンブルして読み込み, 入口から始める vm
+pub fn setup(code: &str) -> MixVM {
+    let (entry_point, binary, _table) = debug_assemble(code);
+    let mut vm = MixVM::new();
+    vm.load(&binary);
+    vm.set_pc(entry_point);
+    vm
+}
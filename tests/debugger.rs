@@ -0,0 +1,87 @@
+mod common;
+
+use mix_emulator::tools::{Debugger, Stop, WatchKind};
+use mix_emulator::vm::Location;
+
+const CODE: &str = "ORIG 3000
+                    START ENT1 0
+                    LOOP INC1 1
+                    ST1 1000
+                    CMP1 =10=
+                    JL LOOP
+                    LDA 1000
+                    HLT
+                    END START";
+
+#[test]
+fn test_breakpoint() {
+    let mut vm = common::setup(CODE);
+    let mut debugger = Debugger::new();
+    assert!(debugger.add_breakpoint(3001));
+    assert!(!debugger.add_breakpoint(3001));
+
+    // 止まった番地の命令はまだ実行していない
+    assert_eq!(Stop::Breakpoint(3001), debugger.run_until(&mut vm).unwrap());
+    assert_eq!(1, vm.clock());
+    assert_eq!(Stop::Breakpoint(3001), debugger.run_until(&mut vm).unwrap());
+
+    assert!(debugger.remove_breakpoint(3001));
+    assert!(matches!(
+        debugger.run_until(&mut vm).unwrap(),
+        Stop::Halted(halt) if halt.pc == 3006
+    ));
+
+    // 条件付きブレークポイント
+    let mut vm = common::setup(CODE);
+    let mut debugger = Debugger::new();
+    debugger.add_conditional_breakpoint(3001, |vm| vm.clock() > 30);
+    assert_eq!(Stop::Breakpoint(3001), debugger.run_until(&mut vm).unwrap());
+    assert!(vm.clock() > 30);
+}
+
+#[test]
+fn test_watchpoint() {
+    let mut vm = common::setup(CODE);
+    let mut debugger = Debugger::new();
+    let write = debugger.add_watchpoint(Location::Memory(1000), WatchKind::Write);
+    let read = debugger.add_watchpoint(Location::Memory(1000), WatchKind::Read);
+    assert_eq!(
+        Stop::Watchpoint(write, WatchKind::Write, Location::Memory(1000), 3002),
+        debugger.run_until(&mut vm).unwrap()
+    );
+    assert!(debugger.remove_watchpoint(write));
+    assert!(!debugger.remove_watchpoint(write));
+    assert_eq!(
+        Stop::Watchpoint(read, WatchKind::Read, Location::Memory(1000), 3005),
+        debugger.run_until(&mut vm).unwrap()
+    );
+
+    // レジスタ. rJ は実際に飛んだときだけ書き込まれる
+    let mut vm = common::setup(CODE);
+    let mut debugger = Debugger::new();
+    let i1 = debugger.add_watchpoint(Location::I(1), WatchKind::ReadWrite);
+    let j = debugger.add_watchpoint(Location::J, WatchKind::Write);
+    assert_eq!(
+        Stop::Watchpoint(i1, WatchKind::Write, Location::I(1), 3000),
+        debugger.run_until(&mut vm).unwrap()
+    );
+    debugger.remove_watchpoint(i1);
+    assert_eq!(
+        Stop::Watchpoint(j, WatchKind::Write, Location::J, 3004),
+        debugger.run_until(&mut vm).unwrap()
+    );
+}
+
+#[test]
+fn test_condition() {
+    let mut vm = common::setup(CODE);
+    let mut debugger = Debugger::new();
+    let id = debugger.add_condition(|vm| vm.clock() >= 20);
+    assert_eq!(Stop::Condition(id), debugger.run_until(&mut vm).unwrap());
+    assert_eq!(20, vm.clock());
+    assert!(debugger.remove_condition(id));
+    assert!(matches!(
+        debugger.run_until(&mut vm).unwrap(),
+        Stop::Halted(_)
+    ));
+}
@@ -0,0 +1,96 @@
+use mix_emulator::asm::{assemble, loading_deck};
+use mix_emulator::tools::{boot, run, text_to_block};
+use mix_emulator::vm::{FaultKind, MixVM, Sign};
+
+// データカードを 1 枚読んで印字し, 負の数や -0 を含む表を足し合わせる
+const CODE: &str = "ORIG 100
+                    START IN BUF(16)
+                    JBUS *(16)
+                    OUT BUF(18)
+                    ENT1 0
+                    ENTA 0
+                    LOOP ADD TABLE,1
+                    INC1 1
+                    CMP1 =10=
+                    JL LOOP
+                    STA SUM
+                    HLT
+                    TABLE CON 1
+                    CON -2
+                    CON 3
+                    CON -4
+                    CON 5
+                    CON -6
+                    CON 7
+                    CON -8
+                    CON 1000000000
+                    CON -0
+                    SUM CON 0
+                    BUF ORIG *+24
+                    END START";
+
+fn insert_cards(vm: &mut MixVM, cards: &[String]) {
+    for card in cards {
+        vm.read(16, text_to_block(card, 16).unwrap());
+    }
+}
+
+#[test]
+fn test_boot() {
+    let program = assemble(CODE).unwrap();
+    let mut deck = loading_deck(program.entry_point, &program.binary).unwrap();
+    // 100..=106, 107..=113, 114..=120, 121 (SUM), 146 (=10=)
+    assert_eq!(deck.len(), 2 + 5 + 1);
+    assert!(deck[2].starts_with("     70100"));
+    assert_eq!(
+        deck[4],
+        "     70114000000000M0000000005000000000O0000000007000000000Q1000000000000000000Δ"
+    );
+    assert_eq!(deck[6], "     101460000000010");
+    assert_eq!(deck.last().unwrap(), "TRANS00100");
+
+    // プログラムの入力はデッキの後ろに置く
+    deck.push("HELLO FROM THE DECK".to_string());
+    let mut vm = MixVM::new();
+    insert_cards(&mut vm, &deck);
+    let info = boot(&mut vm).unwrap();
+    assert_eq!(info.halt().unwrap().pc, 110);
+    assert_eq!(vm.print(18).trim_end(), "HELLO FROM THE DECK");
+
+    let mut direct = MixVM::new();
+    direct.load(&program);
+    insert_cards(&mut direct, &deck[deck.len() - 1..]);
+    run(&mut direct).unwrap();
+    assert_eq!(vm.reg_a(), direct.reg_a());
+    assert_eq!(vm.reg_a().val(), 1000000000 - 4);
+    assert_eq!(
+        vm.memory_range(100, 4000).unwrap(),
+        direct.memory_range(100, 4000).unwrap()
+    );
+    // 表の最後の語は -0
+    assert_eq!(vm.memory(120).unwrap().val(), 0);
+    assert_eq!(*vm.memory(120).unwrap().sign(), Sign::Negative);
+}
+
+#[test]
+fn test_press_go() {
+    let program = assemble(CODE).unwrap();
+    let deck = loading_deck(program.entry_point, &program.binary).unwrap();
+    let mut vm = MixVM::new();
+    assert_eq!(vm.press_go(), Err(FaultKind::EndOfInput(16)));
+
+    insert_cards(&mut vm, &deck);
+    vm.press_go().unwrap();
+    assert_eq!(vm.pc(), 0);
+    assert_eq!(vm.reg_j().val(), 0);
+    assert!(vm.clock() > 0);
+    // 1 枚目のカードだけが読み込まれている. 0 番地は IN 16(16)
+    assert_eq!(vm.memory(0).unwrap().operation(), 36);
+    assert_eq!(vm.memory(16).unwrap().val(), 0);
+}
+
+#[test]
+fn test_loader_overlap() {
+    let program = assemble(" ORIG 40\nSTART HLT\n END START").unwrap();
+    assert!(loading_deck(program.entry_point, &program.binary).is_err());
+}
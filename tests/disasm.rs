@@ -0,0 +1,112 @@
+use mix_emulator::asm::assemble;
+use mix_emulator::disasm::Disassembler;
+use mix_emulator::vm::{Sign, WordImpl};
+
+const CODE: &str = "ORIG 3000
+START LDA 2000,1(1:3)
+ LDA 2000
+ STJ 2000
+ STJ 2000(0:5)
+ FADD 1000
+ ADD 1000(0:6)
+ JMP START
+ JOV 1
+ JGE -1
+ ENTA 0
+ INCA 5
+ ENN1 -5
+ IN 1000(16)
+ IOC 0(18)
+ MOVE 100(3)
+ HLT
+ SLAX 2
+ NUM
+ CHAR
+ FCMP 3000,2
+ CMPA 3000(7)
+ END START";
+
+#[test]
+fn test_disassemble() {
+    let program = assemble(CODE).unwrap();
+    let disasm = Disassembler::new();
+    let text: Vec<String> = program
+        .binary
+        .iter()
+        .map(|(_, word)| disasm.word(word))
+        .collect();
+    assert_eq!(
+        text,
+        vec![
+            "LDA 2000,1(1:3)",
+            "LDA 2000",
+            "STJ 2000",
+            "STJ 2000(0:5)",
+            "FADD 1000",
+            "FADD 1000",
+            "JMP 3000",
+            "JOV 1",
+            "JGE -1",
+            "ENTA",
+            "INCA 5",
+            "ENN1 -5",
+            "IN 1000(16)",
+            "IOC 0(18)",
+            "MOVE 100(3)",
+            "HLT",
+            "SLAX 2",
+            "NUM",
+            "CHAR",
+            "FCMP 3000,2",
+            "CMPA 3000(7)",
+        ]
+    );
+
+    // NUM の F の既定値は 0
+    assert_eq!(
+        program.binary[17].1,
+        WordImpl::from_seq(Sign::Positive, &vec![0, 0, 0, 0, 5])
+    );
+
+    // 逆アセンブルしたものをアセンブルすると元に戻る
+    let source: Vec<String> = text.iter().map(|line| format!(" {}", line)).collect();
+    let again = assemble(&format!(" ORIG 3000\n{}\n END 3000", source.join("\n"))).unwrap();
+    assert_eq!(program.binary, again.binary);
+}
+
+#[test]
+fn test_data_and_labels() {
+    let program = assemble(
+        "ORIG 100
+        START JMP LOOP
+        LOOP LDA TEXT,1
+        TEXT ALF \"HI  5\"
+        CON -7
+        CON -0
+        CON 0
+        CON 10
+        JMP 0(10)
+        END START",
+    )
+    .unwrap();
+    let mut disasm = Disassembler::with_symbols(&program.symbols);
+    disasm.set_data(102);
+    assert_eq!(disasm.label(101), Some("LOOP"));
+    assert_eq!(
+        disasm.dump(&program.binary),
+        "\
+0100 START      JMP LOOP
+0101 LOOP       LDA TEXT,1
+0102 TEXT       ALF \"HI  5\"
+0103            CON -7
+0104            CON -0
+0105            CON 0
+0106            CON 10
+0107            ALF \"   Δ9\"
+"
+    );
+    // 命令として読めるかどうかに関わらずデータとして読める
+    let word = WordImpl::from_seq(Sign::Positive, &vec![8, 9, 0, 0, 0]);
+    assert_eq!(disasm.data(&word), "ALF \"HI   \"");
+    assert_eq!(disasm.instruction(&word), Some("NOP 521".to_string()));
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use mix_emulator::tools::run;
+use mix_emulator::vm::FaultKind;
+
+#[test]
+fn test_random_access() {
+    let code = "ORIG 3000
+                START ENTA 7
+                STA 1000
+                ENTX 5
+                OUT 1000(8)
+                ENTX 2
+                IN 2000(8)
+                ENTX 5
+                IN 2100(8)
+                WAIT JBUS WAIT(8)
+                LD1 2000
+                LD2 2100
+                HLT 0,1
+                HLT 0,2
+                END START";
+    let mut vm = common::setup(code);
+
+    // 書き込んでいないブロックは 0, rX = 5 に書いたブロックは 5 から読める
+    let runinfo = run(&mut vm).unwrap();
+    assert_eq!(0, runinfo.halt().unwrap().address);
+    let runinfo = run(&mut vm).unwrap();
+    assert_eq!(7, runinfo.halt().unwrap().address);
+    assert_eq!(600, vm.print_binary(8).len());
+}
+
+#[test]
+fn test_seek_time() {
+    let code = "ORIG 3000
+                START ENTX 10
+                IOC 0(8)
+                WAIT JBUS WAIT(8)
+                ENTX 4
+                IN 1000(8)
+                HLT
+                END START";
+    let mut vm = common::setup(code);
+
+    // 10 ブロック分の位置決め (100u * 10) を JBUS で待つ.
+    // 続く IN の位置決めと転送は HLT の後で完了する.
+    run(&mut vm).unwrap();
+    assert_eq!(1 + 1000 + 1 + 1 + 1 + 10, vm.clock());
+}
+
+#[test]
+fn test_invalid_block() {
+    let code = "ORIG 3000
+                START ENTX 4095
+                INCX 1
+                IN 1000(8)
+                HLT
+                END START";
+    let mut vm = common::setup(code);
+    let fault = run(&mut vm).unwrap_err();
+    assert_eq!(FaultKind::InvalidBlock(8, 4096), fault.kind);
+
+    let code = "ORIG 3000
+                START ENTX 10
+                OUT 1000(9)
+                HLT
+                END START";
+    let mut vm = common::setup(code);
+    vm.set_disk_capacity(9, 10);
+    let fault = run(&mut vm).unwrap_err();
+    assert_eq!(FaultKind::InvalidBlock(9, 10), fault.kind);
+}
@@ -0,0 +1,162 @@
+use mix_emulator::asm::debug_assemble;
+use mix_emulator::tools::run;
+use mix_emulator::vm::{FaultKind, MixVM, StepOutcome};
+
+fn fault_of(code: &str) -> (i64, FaultKind) {
+    let (entry_point, binary, _table) = debug_assemble(code);
+    let mut vm = MixVM::new();
+    vm.load(&binary);
+    vm.set_pc(entry_point);
+    let fault = run(&mut vm).unwrap_err();
+    (fault.pc, fault.kind)
+}
+
+#[test]
+fn test_fault() {
+    let code = "ORIG 3000
+                START ENTA 1
+                LDA 4000
+                HLT
+                END START";
+    assert_eq!((3001, FaultKind::AddressOutOfRange(4000)), fault_of(code));
+
+    let code = "ORIG 3000
+                START ENT1 -1
+                STA 0,1
+                HLT
+                END START";
+    assert_eq!((3001, FaultKind::AddressOutOfRange(-1)), fault_of(code));
+
+    let code = "ORIG 3000
+                START LDA 0(5:3)
+                HLT
+                END START";
+    assert_eq!((3000, FaultKind::InvalidFieldSpec(43)), fault_of(code));
+
+    let code = "ORIG 3000
+                START LDA 0,7
+                HLT
+                END START";
+    assert_eq!((3000, FaultKind::InvalidIndex(7)), fault_of(code));
+
+    let code = "ORIG 3000
+                START NUM 0(3)
+                HLT
+                END START";
+    assert_eq!((3000, FaultKind::InvalidOpcode(5, 3)), fault_of(code));
+
+    let code = "ORIG 3000
+                START OUT 0(21)
+                HLT
+                END START";
+    assert_eq!((3000, FaultKind::InvalidDevice(21)), fault_of(code));
+
+    let code = "ORIG 3000
+                START IN 0(18)
+                HLT
+                END START";
+    assert_eq!(
+        (3000, FaultKind::UnsupportedIoOperation(18)),
+        fault_of(code)
+    );
+
+    let code = "ORIG 3000
+                START IN 0(16)
+                HLT
+                END START";
+    assert_eq!((3000, FaultKind::EndOfInput(16)), fault_of(code));
+
+    let code = "ORIG 3000
+                START ENT1 4000
+                INC1 100
+                HLT
+                END START";
+    assert_eq!((3001, FaultKind::IndexRegisterOverflow(1)), fault_of(code));
+
+    let code = "ORIG 3000
+                START LD2 BIG
+                HLT
+                BIG CON 100000
+                END START";
+    assert_eq!((3000, FaultKind::IndexRegisterOverflow(2)), fault_of(code));
+}
+
+#[test]
+fn test_fault_keeps_state() {
+    let code = "ORIG 3000
+                START ENT1 4000
+                INC1 100
+                HLT
+                END START";
+    let (entry_point, binary, _table) = debug_assemble(code);
+    let mut vm = MixVM::new();
+    vm.load(&binary);
+    vm.set_pc(entry_point);
+    assert!(matches!(vm.step(), Ok(StepOutcome::Stepped(3000, _))));
+    let clock = vm.clock();
+    let fault = vm.step().unwrap_err();
+    assert_eq!(3001, fault.pc);
+    assert_eq!(clock, vm.clock());
+    // the same fault is reported again because nothing has changed
+    assert_eq!(fault, vm.step().unwrap_err());
+}
+
+#[test]
+fn test_jump_fault_keeps_overflow() {
+    let code = "ORIG 3000
+                START LDA BIG
+                ADD BIG
+                JOV 4000
+                HLT
+                BIG CON 1000000000
+                END START";
+    let (entry_point, binary, _table) = debug_assemble(code);
+    let mut vm = MixVM::new();
+    vm.load(&binary);
+    vm.set_pc(entry_point);
+    vm.step().unwrap();
+    vm.step().unwrap();
+    assert!(vm.overflow());
+    let fault = vm.step().unwrap_err();
+    assert_eq!(
+        (3002, FaultKind::AddressOutOfRange(4000)),
+        (fault.pc, fault.kind)
+    );
+    assert!(vm.overflow());
+}
+
+#[test]
+fn test_io_fault_keeps_state() {
+    // 2 回目の IN で読むカードがない. 1 枚目の転送を待たずに異常になる
+    let code = "ORIG 3000
+                START IN 200(16)
+                IN 200(16)
+                HLT
+                END START";
+    let (entry_point, binary, _table) = debug_assemble(code);
+    let mut vm = MixVM::new();
+    vm.load(&binary);
+    vm.set_pc(entry_point);
+    vm.read(16, vec!["ABCDE".to_string(); 16]);
+    assert!(matches!(vm.step(), Ok(StepOutcome::Stepped(3000, _))));
+    let fault = vm.step().unwrap_err();
+    assert_eq!((3001, FaultKind::EndOfInput(16)), (fault.pc, fault.kind));
+    assert_eq!(1, vm.clock());
+    assert_eq!(0, vm.memory(200).unwrap().val());
+
+    // 転送中の出力を書いた後には, テープに読むブロックがない
+    let code = "ORIG 3000
+                START OUT 1000(0)
+                IN 1000(0)
+                HLT
+                END START";
+    let (entry_point, binary, _table) = debug_assemble(code);
+    let mut vm = MixVM::new();
+    vm.load(&binary);
+    vm.set_pc(entry_point);
+    assert!(matches!(vm.step(), Ok(StepOutcome::Stepped(3000, _))));
+    let fault = vm.step().unwrap_err();
+    assert_eq!((3001, FaultKind::EndOfInput(0)), (fault.pc, fault.kind));
+    assert_eq!(1, vm.clock());
+    assert_eq!("", vm.print(0));
+}
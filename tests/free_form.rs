@@ -0,0 +1,59 @@
+use mix_emulator::asm::assemble;
+use mix_emulator::tools::run;
+use mix_emulator::vm::{MixVM, WordImpl};
+
+// 命令と同じ名前のラベル, 桁で読む ALF, 引用符の ALF, 注釈
+const CODE: &str = "* FREE FORM
+ ORIG 1000
+START JMP SUB          CALL THE ROUTINE
+ JMP MOVE ; AGAIN
+ LDA TEXT
+ LDX TEXT+1
+ HLT
+SUB STJ EXIT
+ INCA 1
+EXIT JMP *
+MOVE STJ 1F
+ INCA 10
+1H JMP *
+TEXT ALF  FIVE
+ ALF \"A B  \" ; TRAILING BLANKS
+ END START";
+
+#[test]
+fn test_labels_named_like_operations() {
+    let program = assemble(CODE).unwrap();
+    assert_eq!(program.symbols["SUB"], 1005);
+    assert_eq!(program.symbols["MOVE"], 1008);
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    run(&mut vm).unwrap();
+    let bytes =
+        |word: &WordImpl| -> Vec<u32> { (0..5).map(|k| word.byte(k).unwrap().val()).collect() };
+    // " FIVE" と "A B  "
+    assert_eq!(bytes(vm.reg_a()), [0, 6, 9, 25, 5]);
+    assert_eq!(bytes(vm.reg_x()), [1, 0, 2, 0, 0]);
+}
+
+#[test]
+fn test_lines_of_two_operations() {
+    // 2 つだけなら ADD が命令で, NOP は END で確保されるシンボル
+    let program = assemble(" ORIG 1000\nADD NOP\n HLT\n END 1000").unwrap();
+    assert!(!program.symbols.contains_key("ADD"));
+    assert_eq!(program.binary[0].1.address(), 1002);
+
+    // アドレスを続ければラベルになる
+    let program = assemble(" ORIG 1000\nADD NOP 0\n JMP ADD\n END 1000").unwrap();
+    assert_eq!(program.symbols["ADD"], 1000);
+
+    // 桁で読む ALF は 5 文字ちょうど. 長いものと短いものはエラー
+    let errors = assemble(" ALF TOOLONG\n ALF AB\n ALF A_B_C\n END 0").unwrap_err();
+    assert_eq!(
+        errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+        [
+            "1:6: ALF operand TOOLONG should have 5 characters",
+            "2:6: ALF operand AB should have 5 characters",
+            "3:6: '_' is not a MIX character"
+        ]
+    );
+}
@@ -0,0 +1,54 @@
+use mix_emulator::asm::debug_assemble;
+use mix_emulator::tools::run;
+use mix_emulator::vm::{MixVM, StepOutcome};
+
+#[test]
+fn test_resume_after_halt() {
+    // 1. make input
+    let code = "ORIG 3000
+                START ENT1 3
+                1H DEC1 1
+                HLT 100,1
+                J1P 1B
+                HLT 666
+                END START";
+
+    // 2. run VM
+    let (entry_point, binary, table) = debug_assemble(code);
+    let mut vm = MixVM::new();
+    vm.load(&binary);
+    vm.set_pc(entry_point);
+
+    // 3. every run stops at HLT and the next run continues after it
+    for expected in [102, 101, 100].iter() {
+        let runinfo = run(&mut vm).unwrap();
+        let halt = runinfo.halt().unwrap();
+        assert_eq!(table[&(4 - 1)] as i64, halt.pc);
+        assert_eq!(*expected, halt.address);
+    }
+    let runinfo = run(&mut vm).unwrap();
+    assert_eq!(table[&(6 - 1)] as i64, runinfo.halt().unwrap().pc);
+    assert_eq!(666, runinfo.halt().unwrap().address);
+    assert_eq!(1, runinfo.count_exec(table[&(6 - 1)]));
+    assert_eq!(10, runinfo.count_clock(table[&(6 - 1)]));
+
+    // 1 + 3 * (1 + 10 + 1) + 10
+    assert_eq!(47, vm.clock());
+}
+
+#[test]
+fn test_halt_info() {
+    let code = "ORIG 3000
+                START HLT 5
+                NOP
+                END START";
+    let (entry_point, binary, _table) = debug_assemble(code);
+    let mut vm = MixVM::new();
+    vm.load(&binary);
+    vm.set_pc(entry_point);
+    assert!(matches!(vm.step(), Ok(StepOutcome::Halted(3000, _))));
+    assert_eq!(5, vm.halt_info().unwrap().address);
+    assert_eq!(10, vm.clock());
+    assert!(matches!(vm.step(), Ok(StepOutcome::Stepped(3001, _))));
+    assert!(vm.halt_info().is_none());
+}
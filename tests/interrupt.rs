@@ -0,0 +1,118 @@
+use mix_emulator::asm::debug_assemble;
+use mix_emulator::tools::run;
+use mix_emulator::vm::{FaultKind, MixVM};
+
+// code を読み込み, control を負の番地に読み込む.
+// control は 4000 - k 番地に書いた語が -k 番地に置かれる.
+fn setup(code: &str, control: &str) -> MixVM {
+    let (entry_point, binary, _table) = debug_assemble(code);
+    let (_, control, _table) = debug_assemble(control);
+    let control = control
+        .into_iter()
+        .map(|(address, word)| (address as i64 - 4000, word))
+        .collect();
+    let mut vm = MixVM::new();
+    vm.set_interrupt_facility(true);
+    vm.load(&binary);
+    vm.load_control(&control);
+    vm.set_pc(entry_point);
+    vm
+}
+
+#[test]
+fn test_int() {
+    let code = "ORIG 3500
+                HANDLER ENT1 77
+                ST1 3900
+                INT
+                ORIG 3000
+                START ENT1 5
+                INT
+                LD2 3900
+                HLT 0,1
+                HLT 0,2
+                END START";
+    // -12: INT による割り込み
+    let control = "ORIG 3988
+                   JMP 3500
+                   END 3988";
+    let mut vm = setup(code, control);
+
+    // 割り込みから戻ると rI1 は復元される
+    let runinfo = run(&mut vm).unwrap();
+    assert_eq!(5, runinfo.halt().unwrap().address);
+    assert_eq!(1, runinfo.count_exec(3500));
+    assert!(!vm.is_control_state());
+    let runinfo = run(&mut vm).unwrap();
+    assert_eq!(77, runinfo.halt().unwrap().address);
+}
+
+#[test]
+fn test_timer() {
+    let code = "ORIG 3600
+                HANDLER HLT 0,2
+                ORIG 3000
+                START ENT2 0
+                LOOP INC2 1
+                JMP LOOP
+                END START";
+    // -11: タイマ割り込み, -10: タイマ
+    let control = "ORIG 3989
+                   JMP 3600
+                   CON 20
+                   END 3989";
+    let mut vm = setup(code, control);
+
+    // ENT2 (1) の後 INC2, JMP (各 1) を 19 単位時間分繰り返したところで割り込む
+    let runinfo = run(&mut vm).unwrap();
+    assert_eq!(10, runinfo.halt().unwrap().address);
+    assert_eq!(3600, runinfo.halt().unwrap().pc);
+    assert!(vm.is_control_state());
+}
+
+#[test]
+fn test_io_interrupt() {
+    let code = "ORIG 3700
+                HANDLER ENT3 1
+                ST3 3900
+                INT
+                ORIG 3000
+                START ENT3 0
+                OUT 1000(18)
+                WAIT JBUS WAIT(18)
+                LD3 3900
+                HLT 0,3
+                END START";
+    // -38: ラインプリンタ (18) の入出力完了割り込み
+    let control = "ORIG 3962
+                   JMP 3700
+                   END 3962";
+    let mut vm = setup(code, control);
+
+    let runinfo = run(&mut vm).unwrap();
+    assert_eq!(1, runinfo.halt().unwrap().address);
+    assert_eq!(1, runinfo.count_exec(3700));
+}
+
+#[test]
+fn test_interrupt_facility_disabled() {
+    let code = "ORIG 3000
+                START INT
+                HLT
+                END START";
+    let (entry_point, binary, _table) = debug_assemble(code);
+    let mut vm = MixVM::new();
+    vm.load(&binary);
+    vm.set_pc(entry_point);
+    let fault = run(&mut vm).unwrap_err();
+    assert_eq!(FaultKind::InvalidOpcode(5, 9), fault.kind);
+
+    // 通常状態では負の番地は使えない
+    let code = "ORIG 3000
+                START LDA -1
+                HLT
+                END START";
+    let mut vm = setup(code, "ORIG 3999\nCON 0\nEND 3999");
+    let fault = run(&mut vm).unwrap_err();
+    assert_eq!(FaultKind::AddressOutOfRange(-1), fault.kind);
+}
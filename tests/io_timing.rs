@@ -0,0 +1,83 @@
+mod common;
+
+use mix_emulator::tools::run;
+use mix_emulator::vm::Latency;
+
+#[test]
+fn test_input_completes_later() {
+    let code = "ORIG 3000
+                START IN 1000(16)
+                LD1 1000(4:5)
+                WAIT JBUS WAIT(16)
+                LD2 1000(4:5)
+                HLT 0,1
+                HLT 0,2
+                END START";
+    let mut vm = common::setup(code);
+    let mut card = vec!["   AB".to_string()];
+    card.extend(vec!["     ".to_string(); 15]);
+    vm.read(16, card);
+
+    // 転送が終わるまで memory は書き換わらない
+    let runinfo = run(&mut vm).unwrap();
+    assert_eq!(0, runinfo.halt().unwrap().address);
+    // JBUS で転送の完了 (10000u) を待つ
+    assert_eq!(10000 + 1 + 2 + 10, vm.clock());
+    let runinfo = run(&mut vm).unwrap();
+    assert_eq!(66, runinfo.halt().unwrap().address);
+}
+
+#[test]
+fn test_output_waits_for_device() {
+    let code = "ORIG 3000
+                START OUT 1000(18)
+                OUT 1000(18)
+                HLT
+                END START";
+
+    // 2 回目の OUT はラインプリンタの 1 行目の印字 (7500u) が終わるまで待つ
+    let mut vm = common::setup(code);
+    run(&mut vm).unwrap();
+    assert_eq!(7500 + 1 + 10, vm.clock());
+    assert_eq!(2, vm.print(18).lines().count());
+
+    let mut vm = common::setup(code);
+    vm.set_latency(
+        18,
+        Latency {
+            transfer: 100,
+            seek: 0,
+        },
+    );
+    assert_eq!(100, vm.latency(18).transfer);
+    run(&mut vm).unwrap();
+    assert_eq!(100 + 1 + 10, vm.clock());
+}
+
+#[test]
+fn test_jred() {
+    let code = "ORIG 3000
+                START OUT 1000(18)
+                JRED READY(18)
+                ENT1 1
+                READY HLT 0,1
+                END START";
+    let mut vm = common::setup(code);
+    let runinfo = run(&mut vm).unwrap();
+    assert_eq!(1, runinfo.halt().unwrap().address);
+}
+
+#[test]
+fn test_output_reads_memory_on_completion() {
+    // 転送が終わる前に書き換えた語が出力される (TAOCP 1.4.4)
+    let code = "ORIG 3000
+                START OUT 1000(18)
+                LDA WORD
+                STA 1000
+                HLT
+                WORD ALF HELLO
+                END START";
+    let mut vm = common::setup(code);
+    run(&mut vm).unwrap();
+    assert!(vm.print(18).starts_with("HELLO"));
+}
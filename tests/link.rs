@@ -0,0 +1,164 @@
+use mix_emulator::asm::{assemble_module, AsmErrorKind, Relocation, SourceFormat};
+use mix_emulator::link::{link, LinkError};
+use mix_emulator::object::ObjectFile;
+use mix_emulator::tools::run;
+use mix_emulator::vm::MixVM;
+
+// X[1..n] の最大値を rA に, その添字を rI2 に求める (TAOCP 1.3.2 プログラム M)
+const MAXIMUM: &str = " ENTRY MAXN
+                       EXTERN X
+                       MAXN STJ EXIT
+                       INIT ENT3 0,1
+                       JMP CHANGEM
+                       LOOP CMPA X,3
+                       JGE *+3
+                       CHANGEM ENT2 0,3
+                       LDA X,3
+                       DEC3 1
+                       J3P LOOP
+                       EXIT JMP *
+                       END";
+
+const MAIN: &str = " ENTRY X
+                    EXTERN MAXN
+                    START ENT1 5
+                    JMP MAXN
+                    LDX PTR
+                    LD3 =X+1=
+                    HLT
+                    X CON 0
+                    CON 3
+                    CON 10
+                    CON -2
+                    CON 7
+                    CON 1
+                    PTR CON X+2
+                    END START";
+
+fn module(code: &str) -> ObjectFile {
+    ObjectFile::new(&assemble_module(code, SourceFormat::FreeForm).unwrap())
+}
+
+#[test]
+fn test_module() {
+    let program = assemble_module(MAXIMUM, SourceFormat::FreeForm).unwrap();
+    assert!(program.relocatable);
+    assert_eq!(program.size, 10);
+    assert_eq!(program.exports["MAXN"], (0, true));
+    // STJ EXIT, JMP CHANGEM, CMPA X,3, JGE *+3, ...
+    assert_eq!(program.relocations[&0], Relocation::Address);
+    assert!(!program.relocations.contains_key(&1));
+    assert_eq!(
+        program.relocations[&3],
+        Relocation::External("X".to_string())
+    );
+    assert_eq!(program.relocations[&4], Relocation::Address);
+
+    let main = assemble_module(MAIN, SourceFormat::FreeForm).unwrap();
+    assert_eq!(main.relocations[&11], Relocation::Value);
+    assert_eq!(main.exports["X"], (5, true));
+
+    // モジュールの情報もオブジェクトファイルに書ける
+    let object = ObjectFile::new(&program);
+    let mut text = vec![];
+    object.write(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.contains("\nmodule 10\n"));
+    assert!(text.contains("\nreloc 3 extern X\n"));
+    assert!(text.contains("\nexport MAXN 0 rel\n"));
+    assert_eq!(object, ObjectFile::read(text.as_bytes()).unwrap());
+}
+
+#[test]
+fn test_link() {
+    let modules = vec![
+        ("main".to_string(), module(MAIN)),
+        ("maximum".to_string(), module(MAXIMUM)),
+    ];
+    let linked = link(&modules, 100).unwrap();
+    // main は 100..=112 (リテラルを含む), maximum は 113 から
+    assert_eq!(linked.entry_point, 100);
+    assert_eq!(linked.symbols["X"], 105);
+    assert_eq!(linked.symbols["MAXN"], 113);
+    assert_eq!(linked.linkage["MAXN"], 122);
+    assert!(linked.module.is_none());
+
+    let mut vm = MixVM::new();
+    vm.load(&linked);
+    run(&mut vm).unwrap();
+    assert_eq!(vm.reg_a().val(), 10);
+    assert_eq!(vm.reg_i(2).val(), 2);
+    assert_eq!(vm.reg_x().val(), 107);
+    assert_eq!(vm.reg_i(3).val(), 106);
+    // 戻り番地は JMP MAXN の次
+    assert_eq!(vm.memory(122).unwrap().address(), 102);
+}
+
+#[test]
+fn test_link_errors() {
+    let modules = vec![
+        ("main".to_string(), module(MAIN)),
+        ("main2".to_string(), module(MAIN)),
+    ];
+    let errors = link(&modules, 100).unwrap_err();
+    assert!(errors.contains(&LinkError::DuplicateSymbol(
+        "X".to_string(),
+        "main".to_string(),
+        "main2".to_string()
+    )));
+    assert!(errors.contains(&LinkError::UndefinedSymbol(
+        "MAXN".to_string(),
+        "main".to_string()
+    )));
+    assert_eq!(
+        errors[0].to_string(),
+        "symbol X is exported by both main and main2"
+    );
+
+    let modules = vec![("maximum".to_string(), module(MAXIMUM))];
+    assert!(matches!(
+        link(&modules, 3995).unwrap_err()[0],
+        LinkError::OutOfRange(_, 4004)
+    ));
+}
+
+#[test]
+fn test_module_errors() {
+    let errors = |code: &str| {
+        assemble_module(code, SourceFormat::FreeForm)
+            .unwrap_err()
+            .iter()
+            .map(|e| (e.line, e.kind))
+            .collect::<Vec<_>>()
+    };
+    // 番地の積や, 索引部の番地は配置によって意味が変わる
+    assert_eq!(
+        errors("A LDA A*2\n LD1 0,A\n END"),
+        vec![
+            (1, AsmErrorKind::InvalidExpression),
+            (2, AsmErrorKind::InvalidExpression)
+        ]
+    );
+    // 外部シンボルはアドレス部でしか使えない
+    assert_eq!(
+        errors(" EXTERN E\n CON E\n LDA 2*E\n END"),
+        vec![
+            (2, AsmErrorKind::InvalidExpression),
+            (3, AsmErrorKind::InvalidExpression)
+        ]
+    );
+    assert_eq!(
+        errors(" EXTERN E\nE NOP\n ENTRY F\n END"),
+        vec![
+            (2, AsmErrorKind::DuplicateSymbol),
+            (3, AsmErrorKind::UndefinedSymbol)
+        ]
+    );
+    // 最初の不正なシンボルで行ごと飛ばす
+    assert_eq!(
+        errors(" ENTRY 2+,F
+ EXTERN E,1+,G
+ END"),
+        vec![(1, AsmErrorKind::Syntax), (2, AsmErrorKind::Syntax)]
+    );
+}
@@ -0,0 +1,55 @@
+use mix_emulator::asm::{assemble, listing};
+
+#[test]
+fn test_listing() {
+    let code = "* LISTING
+TEN EQU 10
+ ORIG 2000
+START LDA =TEN+1=
+ JMP -X
+ CON -5
+ END START";
+    let program = assemble(code).unwrap();
+    let text = listing(code, &program);
+    let expected = "\
+LOC  WORD             LINE  SOURCE
+                         1  * LISTING
+     = 10                2  TEN EQU 10
+     = 2000              3   ORIG 2000
+2000 + 2003 00 05 08     4  START LDA =TEN+1=
+2001 - 2004 00 00 39     5   JMP -X
+2002 - 0000 00 00 05     6   CON -5
+     = 2000              7   END START
+2003 + 0000 00 00 11        =TEN+1=
+2004 + 0000 00 00 00        X CON 0
+
+SYMBOL     VALUE
+START       2000
+TEN           10
+X           2004
+";
+    assert_eq!(expected, text);
+}
+
+#[test]
+fn test_listing_overlapped_lines() {
+    // ORIG で戻って同じ番地に書いても, 各行にはその行の語を並べる
+    let code = " ORIG 100
+ CON 1
+ ORIG 100
+ CON 2
+ END 100";
+    let program = assemble(code).unwrap();
+    let text = listing(code, &program);
+    let expected = "\
+LOC  WORD             LINE  SOURCE
+     = 100               1   ORIG 100
+0100 + 0000 00 00 01     2   CON 1
+     = 100               3   ORIG 100
+0100 + 0000 00 00 02     4   CON 2
+     = 100               5   END 100
+
+SYMBOL     VALUE
+";
+    assert_eq!(expected, text);
+}
@@ -0,0 +1,313 @@
+use mix_emulator::asm::{
+    assemble, assemble_at, assemble_with, listing, AsmErrorKind, SourceFormat,
+};
+use mix_emulator::tools::run;
+use mix_emulator::vm::MixVM;
+use std::fs;
+use std::path::Path;
+
+const CODE: &str = "* MACROS
+SWAP MACRO X,Y
+ LDA X
+ LDX Y
+ STX X
+ STA Y
+ ENDM
+WAIT MACRO U
+1H NOP
+ JBUS 1B(U)
+ ENDM
+COUNT MACRO N
+ ENT1 N
+LOOP DEC1 1
+ J1P LOOP
+ ENDM
+ ORIG 1000
+START SWAP A,B
+1H ENTA 1
+ WAIT 18
+ COUNT 3
+ COUNT 2
+ ENT2 1B
+ JMP 1F
+ HLT
+1H HLT
+A CON 5
+B CON 7
+ END START";
+
+#[test]
+fn test_expansion() {
+    let program = assemble(CODE).unwrap();
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    let info = run(&mut vm).unwrap();
+    assert_eq!(info.halt().unwrap().pc, 1016);
+    assert_eq!(vm.memory(1017).unwrap().val(), 7);
+    assert_eq!(vm.memory(1018).unwrap().val(), 5);
+    // マクロの 1H は呼び出した側の 1B, 1F と混ざらない
+    assert_eq!(vm.reg_i(2).val(), 1004);
+    assert_eq!(vm.memory(1006).unwrap().address(), 1005);
+    // ラベルは呼び出しごとに別のシンボルになり, シンボルテーブルには出ない
+    assert_eq!(vm.memory(1009).unwrap().address(), 1008);
+    assert_eq!(vm.memory(1012).unwrap().address(), 1011);
+    assert_eq!(
+        program.symbols.keys().collect::<Vec<_>>(),
+        ["A", "B", "START"]
+    );
+    let texts: Vec<&str> = program.source.iter().map(|s| s.text.as_str()).collect();
+    assert!(texts.contains(&"4MLOOP DEC1 1"));
+    assert!(texts.contains(&"2M0 NOP"));
+    assert!(texts.contains(&" JBUS 2M0(18)"));
+}
+
+#[test]
+fn test_symbols_like_macro_labels() {
+    // 2 回目の呼び出しの LOOP は 2MLOOP にならず, ソースの 2MLOOP と重ならない
+    let code = "COUNT MACRO N
+ ENT1 N
+LOOP DEC1 1
+ J1P LOOP
+ ENDM
+ ORIG 1000
+START COUNT 3
+ COUNT 2
+ LDA 2MLOOP
+ HLT
+2MLOOP CON 9
+ END START";
+    let program = assemble(code).unwrap();
+    assert_eq!(
+        program.symbols.keys().collect::<Vec<_>>(),
+        ["2MLOOP", "START"]
+    );
+    let texts: Vec<&str> = program.source.iter().map(|s| s.text.as_str()).collect();
+    assert!(texts.contains(&"2M1 DEC1 1"));
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    run(&mut vm).unwrap();
+    assert_eq!(vm.reg_a().val(), 9);
+}
+
+#[test]
+fn test_alf_in_macro() {
+    // ALF のオペランドは仮引数と同じ名前の文字があっても置き換えない
+    let code = "PUT MACRO C
+ LDA =C=
+ ALF C ABC
+ ENDM
+ ORIG 1000
+START PUT 5
+ HLT
+ END START";
+    let program = assemble(code).unwrap();
+    let texts: Vec<&str> = program.source.iter().map(|s| s.text.as_str()).collect();
+    assert!(texts.contains(&" LDA =5="));
+    assert!(texts.contains(&" ALF C ABC"));
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    // C, 空白, A, B, C
+    let word = vm.memory(1001).unwrap();
+    let bytes: Vec<u32> = (0..5).map(|k| word.byte(k).unwrap().val()).collect();
+    assert_eq!(bytes, [3, 0, 1, 2, 3]);
+}
+
+#[test]
+fn test_listing() {
+    let code = "PRINT MACRO MSG
+ OUT MSG(18)
+ JBUS *(18)
+ ENDM
+ ORIG 100
+START PRINT TEXT
+ HLT
+TEXT ALF HELLO
+ END START";
+    let program = assemble(code).unwrap();
+    let expected = "\
+LOC  WORD             LINE  SOURCE
+                         1  PRINT MACRO MSG
+                         2   OUT MSG(18)
+                         3   JBUS *(18)
+                         4   ENDM
+     = 100               5   ORIG 100
+                         6  START PRINT TEXT
+     = 100               +  START ORIG *
+0100 + 0103 00 18 37     +   OUT TEXT(18)
+0101 + 0101 00 18 34     +   JBUS *(18)
+0102 + 0000 00 02 05     7   HLT
+0103 + 0517 13 13 16     8  TEXT ALF HELLO
+     = 100               9   END START
+
+SYMBOL     VALUE
+START        100
+TEXT         103
+";
+    assert_eq!(expected, listing(code, &program));
+}
+
+#[test]
+fn test_conditional() {
+    let code = "DEBUG EQU 1
+SIZE EQU DEBUG*10+5
+ ORIG 100
+START IF DEBUG
+ ENTA SIZE
+ IF SIZE-15
+ ENTX 1
+ ELSE
+ ENTX 2
+ ENDIF
+ ELSE
+ ENTA 0
+ IF UNKNOWN
+ ENDIF
+ ENDIF
+ HLT
+ END START";
+    let program = assemble(code).unwrap();
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    run(&mut vm).unwrap();
+    assert_eq!(vm.reg_a().val(), 15);
+    assert_eq!(vm.reg_x().val(), 2);
+    assert_eq!(program.binary.len(), 3);
+
+    // 値が番地のシンボルは IF に使えない
+    let errors = assemble(" ORIG 100\nX EQU *\n IF X\n ENDIF\n END 100").unwrap_err();
+    assert_eq!(errors[0].line, 3);
+    assert_eq!(errors[0].kind, AsmErrorKind::UndefinedSymbol);
+    let errors = assemble(" IF 1\n ELSE\n ELSE\n ENDIF\n ENDIF\n IF 0").unwrap_err();
+    let messages: Vec<(usize, &str)> = errors
+        .iter()
+        .map(|e| (e.line, e.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            (3, "ELSE appears twice"),
+            (5, "ENDIF without IF"),
+            (6, "IF without ENDIF")
+        ]
+    );
+}
+
+#[test]
+fn test_include() {
+    let dir = std::env::temp_dir().join(format!("mix-include-{}", std::process::id()));
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    fs::write(
+        dir.join("lib").join("io.mixal"),
+        "PRINTER EQU 18\n INCLUDE wait.mixal\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("lib").join("wait.mixal"),
+        "WAIT MACRO U\n JBUS *(U)\n ENDM\n",
+    )
+    .unwrap();
+    let code = " INCLUDE lib/io.mixal
+ ORIG 100
+START OUT TEXT(PRINTER)
+ WAIT PRINTER
+ HLT
+TEXT ALF HELLO
+ END START";
+    let program = assemble_at(code, SourceFormat::FreeForm, false, &dir).unwrap();
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    run(&mut vm).unwrap();
+    assert_eq!(vm.print(18).trim_end(), "HELLO");
+    assert_eq!(program.source[0].text, "PRINTER EQU 18");
+    assert_eq!(program.source[0].line, 0);
+    assert!(program.source[0].expanded);
+
+    let errors = assemble_at(
+        " INCLUDE missing.mixal",
+        SourceFormat::FreeForm,
+        false,
+        &dir,
+    )
+    .unwrap_err();
+    assert_eq!(errors[0].kind, AsmErrorKind::Include);
+    assert_eq!(errors[0].columns, 10..23);
+    let errors = assemble_at(
+        " INCLUDE self.mixal",
+        SourceFormat::FreeForm,
+        false,
+        Path::new(""),
+    );
+    assert!(errors.is_err());
+    fs::write(dir.join("self.mixal"), " INCLUDE self.mixal\n").unwrap();
+    let errors =
+        assemble_at(" INCLUDE self.mixal", SourceFormat::FreeForm, false, &dir).unwrap_err();
+    assert!(errors[0].message.contains("nested too deeply"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_card_format() {
+    let code = "\
+SWAP       MACRO X,Y
+           LDA  X
+           LDX  Y
+           STX  X
+           STA  Y
+           ENDM
+           ORIG 1000
+START      SWAP ALPHA,BETA
+           HLT
+ALPHA      CON  5
+BETA       CON  7
+           END  START";
+    let program = assemble_with(code, SourceFormat::Card).unwrap();
+    assert_eq!(program.source[2].text, "           LDA ALPHA");
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    run(&mut vm).unwrap();
+    assert_eq!(vm.memory(1005).unwrap().val(), 7);
+    assert_eq!(vm.memory(1006).unwrap().val(), 5);
+}
+
+#[test]
+fn test_macro_errors() {
+    let code = "SWAP MACRO X,Y
+ LDA X
+ STA Z
+ ENDM
+ SWAP A,B,C
+ SWAP A
+A CON 0
+ ENDM
+LDA MACRO
+ ENDM
+LOOP MACRO
+ END 0";
+    let errors = assemble(code).unwrap_err();
+    let messages: Vec<(usize, &str)> = errors
+        .iter()
+        .map(|e| (e.line, e.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            (5, "macro SWAP takes 2 arguments but 3 were given"),
+            (8, "ENDM without MACRO"),
+            (9, "invalid macro name LDA"),
+            (11, "macro LOOP has no ENDM")
+        ]
+    );
+
+    // 展開した行のエラーは呼び出しの行で報告する
+    let code = "SWAP MACRO X,Y
+ LDA X
+ STA Z
+ ENDM
+ SWAP A
+A CON 0";
+    let errors = assemble(code).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].line, 5);
+    assert_eq!(errors[0].columns, 1..8);
+    assert_eq!(errors[0].message, "symbol Z is not defined in `STA Z`");
+}
@@ -0,0 +1,91 @@
+use mix_emulator::asm::assemble;
+use mix_emulator::object::{ObjectError, ObjectFile};
+use mix_emulator::tools::run;
+use mix_emulator::vm::MixVM;
+
+// 1..=10 の和を rA に求める
+const CODE: &str = "ORIG 3000
+                    START ENTA 0
+                    ENT1 10
+                    LOOP INCA 0,1
+                    DEC1 1
+                    J1P LOOP
+                    STA SUM
+                    HLT
+                    ORIG 3500
+                    SUM CON 0
+                    END START";
+
+#[test]
+fn test_round_trip() {
+    let program = assemble(CODE).unwrap();
+    let object = ObjectFile::new(&program);
+    assert_eq!(object.entry_point, 3000);
+    assert_eq!(object.segments.len(), 2);
+    assert_eq!(object.segments[1].0, 3500);
+    assert_eq!(object.symbols["LOOP"], 3002);
+    assert_eq!(object.line_address[&3], 3002);
+
+    let mut text = vec![];
+    object.write(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.starts_with("MIX OBJECT 1\nbyte 64\nentry 3000\nseg 3000 +0.0.0.2.48 "));
+    assert!(text.contains("\nsym LOOP 3002\n"));
+    assert!(text.contains("\nline 4 3002\n"));
+    assert!(text.ends_with("end\n"));
+
+    let read = ObjectFile::read(text.as_bytes()).unwrap();
+    assert_eq!(object, read);
+}
+
+#[test]
+fn test_load() {
+    let program = assemble(CODE).unwrap();
+    let mut object = ObjectFile::new(&program);
+    object.symbols.clear();
+    object.line_address.clear();
+    let mut text = vec![];
+    object.write(&mut text).unwrap();
+
+    let object = ObjectFile::read(&text[..]).unwrap();
+    let mut vm = MixVM::new();
+    vm.load(&object);
+    assert_eq!(vm.pc(), 3000);
+    run(&mut vm).unwrap();
+    assert_eq!(vm.reg_a().val(), 55);
+
+    // アセンブル結果から直接読み込んでも同じ
+    let mut direct = MixVM::new();
+    direct.load(&program);
+    run(&mut direct).unwrap();
+    assert_eq!(vm.state(), direct.state());
+}
+
+#[test]
+fn test_read_errors() {
+    let error = |text: &str| ObjectFile::read(text.as_bytes()).unwrap_err();
+    assert!(matches!(
+        error("MIX SNAPSHOT 1\n"),
+        ObjectError::Parse(1, _)
+    ));
+    assert!(matches!(
+        error("MIX OBJECT 1\nbyte 100\nend\n"),
+        ObjectError::ByteSize(100)
+    ));
+    assert!(matches!(
+        error("MIX OBJECT 1\nentry 0\nseg 3999 +0.0.0.0.0 +0.0.0.0.0\nend\n"),
+        ObjectError::Parse(3, _)
+    ));
+    assert!(matches!(
+        error("MIX OBJECT 1\nentry 0\nseg 0 +0.0.0.0\nend\n"),
+        ObjectError::Parse(3, _)
+    ));
+    assert!(matches!(
+        error("MIX OBJECT 1\nseg 0 +0.0.0.0.0\nend\n"),
+        ObjectError::Parse(0, _)
+    ));
+    assert!(matches!(
+        error("MIX OBJECT 1\nentry 0\n"),
+        ObjectError::Parse(0, _)
+    ));
+}
@@ -49,9 +49,9 @@ fn test_prime500() {
                 CON 2
                 ORIG BUF0-5
                 TITLE ALF FISRT
-                ALF _FIVE
-                ALF _HUND
-                ALF RED_P
+                ALF  FIVE
+                ALF  HUND
+                ALF RED P
                 ALF RIMES
                 ORIG BUF0+24
                 CON BUF1+WIDTH
@@ -0,0 +1,90 @@
+use mix_emulator::asm::assemble;
+use mix_emulator::tools::{profile, profile_csv, run};
+use mix_emulator::vm::MixVM;
+
+const CODE: &str = "* SUM OF 1..N
+N EQU 3
+ADDX MACRO R
+ INCA 0,R
+ ENDM
+ ORIG 1000
+START ENT1 N
+ ENTA 0
+LOOP JMP ADD
+ DEC1 1
+ J1P LOOP
+ STA SUM
+ HLT
+ JMP START
+ADD STJ EXIT
+ ADDX 1
+EXIT JMP *
+SUM CON 0
+ END START";
+
+#[test]
+fn test_profile() {
+    let program = assemble(CODE).unwrap();
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    let info = run(&mut vm).unwrap();
+    let expected = "\
+LOC     COUNT     TIME  LINE  SOURCE
+                           1  * SUM OF 1..N
+                           2  N EQU 3
+                           3  ADDX MACRO R
+                           4   INCA 0,R
+                           5   ENDM
+                           6   ORIG 1000
+1000        1        1     7  START ENT1 N
+1001        1        1     8   ENTA 0
+1002        3        3     9  LOOP JMP ADD
+1003        3        3    10   DEC1 1
+1004        3        3    11   J1P LOOP
+1005        1        2    12   STA SUM
+1006        1       10    13   HLT
+1007        0        0    14   JMP START
+1008        3        6    15  ADD STJ EXIT
+                          16   ADDX 1
+1009        3        3     +   INCA 0,1
+1010        3        3    17  EXIT JMP *
+1011                      18  SUM CON 0
+                          19   END START
+
+total 35u, 22 instructions executed
+
+hottest lines:
+ LINE    COUNT     TIME      %  SOURCE
+   13        1       10  28.6%  HLT
+   15        3        6  17.1%  ADD STJ EXIT
+    9        3        3   8.6%  LOOP JMP ADD
+   10        3        3   8.6%  DEC1 1
+   11        3        3   8.6%  J1P LOOP
+
+never executed:
+ LINE  LOC  SOURCE
+   14 1007  JMP START
+";
+    assert_eq!(expected, profile(CODE, &program, &info));
+}
+
+#[test]
+fn test_profile_csv() {
+    let code = " ORIG 100
+START ALF \"HI YO\"
+ LDA START
+ HLT
+ END START";
+    let program = assemble(code).unwrap();
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    vm.set_pc(101);
+    let info = run(&mut vm).unwrap();
+    let expected = "\
+line,expanded,address,count,time,source
+2,false,100,0,0,\"START ALF \"\"HI YO\"\"\"
+3,false,101,1,2,\"LDA START\"
+4,false,102,1,10,\"HLT\"
+";
+    assert_eq!(expected, profile_csv(&program, &info));
+}
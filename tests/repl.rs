@@ -0,0 +1,200 @@
+use mix_emulator::asm::evaluate;
+use mix_emulator::tools::{repl, DebugSession};
+use std::collections::BTreeMap;
+
+const CODE: &str = "* SUM OF 1..N
+N EQU 3
+ ORIG 1000
+START ENT1 N
+ ENTA 0
+LOOP JMP ADD
+ DEC1 1
+ J1P LOOP
+ STA SUM
+ HLT
+ADD STJ EXIT
+ INCA 0,1
+EXIT JMP *
+SUM CON 0
+ END START";
+
+#[test]
+fn test_step_and_next() {
+    let mut session = DebugSession::from_source(CODE).unwrap();
+    assert_eq!(
+        session.execute("where").unwrap(),
+        "1000  ENT1 N                  4| START ENT1 N\n"
+    );
+    assert_eq!(
+        session.execute("step 2").unwrap(),
+        "1002  JMP ADD                 6| LOOP JMP ADD\n"
+    );
+    // step は JMP の先に入る
+    assert_eq!(
+        session.execute("s").unwrap(),
+        "1007  STJ EXIT               11| ADD STJ EXIT\n"
+    );
+    session.execute("s 3").unwrap();
+    assert_eq!(session.vm().pc(), 1003);
+
+    // next は rJ の戻り番地まで実行する
+    session.execute("s 2").unwrap();
+    assert_eq!(session.vm().pc(), 1002);
+    assert_eq!(
+        session.execute("next").unwrap(),
+        "1003  DEC1 1                  7|  DEC1 1\n"
+    );
+    assert_eq!(session.vm().reg_a().val(), 5);
+
+    // サブルーチンの中のブレークポイントでは止まる
+    session.execute("s 2").unwrap();
+    session.execute("break EXIT").unwrap();
+    assert_eq!(
+        session.execute("n").unwrap(),
+        "breakpoint at 1009 (EXIT), line 13\n1009  JMP 1003               13| EXIT JMP *\n"
+    );
+    assert_eq!(session.execute("n 10").unwrap(), "halted at 1006 (M = 0)\n");
+    assert_eq!(
+        session.execute("x SUM").unwrap().split_whitespace().nth(2),
+        Some("+0000000006")
+    );
+}
+
+#[test]
+fn test_breakpoints() {
+    let mut session = DebugSession::from_source(CODE).unwrap();
+    // 行番号は, その行か後で最初に語のある行
+    assert_eq!(
+        session.execute("b 7").unwrap(),
+        "breakpoint at 1003 (LOOP+1), line 7\n"
+    );
+    assert_eq!(
+        session.execute("b 1").unwrap(),
+        "breakpoint at 1000 (START), line 4\n"
+    );
+    assert_eq!(
+        session.execute("b *LOOP+2").unwrap(),
+        "breakpoint at 1004 (LOOP+2), line 8\n"
+    );
+    assert_eq!(
+        session.execute("b LOOP+1").unwrap_err(),
+        "breakpoint at 1003 already exists"
+    );
+    assert_eq!(
+        session.execute("info").unwrap(),
+        "breakpoint at 1000 (START), line 4\n\
+         breakpoint at 1003 (LOOP+1), line 7\n\
+         breakpoint at 1004 (LOOP+2), line 8\n"
+    );
+    assert_eq!(
+        session.execute("c").unwrap(),
+        "breakpoint at 1003 (LOOP+1), line 7\n1003  DEC1 1                  7|  DEC1 1\n"
+    );
+    assert_eq!(session.vm().reg_a().val(), 3);
+    session.execute("d *1003").unwrap();
+    assert_eq!(
+        session.execute("d *1003").unwrap_err(),
+        "no breakpoint at 1003"
+    );
+    session.execute("c").unwrap();
+    session.execute("c").unwrap();
+    assert_eq!(session.vm().reg_i(1).val(), 1);
+    assert_eq!(session.execute("d").unwrap(), "deleted 2 breakpoints\n");
+    assert_eq!(session.execute("c").unwrap(), "halted at 1006 (M = 0)\n");
+
+    assert_eq!(
+        session.execute("b 99").unwrap_err(),
+        "no code at or after line 99"
+    );
+    assert_eq!(
+        session.execute("b NOPE").unwrap_err(),
+        "symbol NOPE is not defined"
+    );
+}
+
+#[test]
+fn test_state() {
+    let mut session = DebugSession::from_source(CODE).unwrap();
+    session.execute("s 3").unwrap();
+    let registers = session.execute("registers").unwrap();
+    assert!(registers.starts_with("rA   +0000000000\nrX   +0000000000\nrI1  +0003\n"));
+    assert!(registers.contains("rJ   +1003  LOOP+1\n"));
+    assert!(registers.ends_with("OV   OFF  CI EQUAL  clock 3u\n"));
+
+    assert_eq!(session.execute("p N*2+1").unwrap(), "7\n");
+    assert_eq!(session.execute("p SUM").unwrap(), "1010\n");
+    assert_eq!(session.execute("p * + 1").unwrap(), "1008 (ADD+1)\n");
+    assert_eq!(session.execute("p 1/0").unwrap_err(), "division by zero");
+    let words: Vec<Vec<String>> = session
+        .execute("x LOOP 2")
+        .unwrap()
+        .lines()
+        .map(|line| line.split_whitespace().map(String::from).collect())
+        .collect();
+    assert_eq!(words[0][..2], ["1002", "LOOP"]);
+    assert_eq!(words[0][3..], ["JMP", "ADD"]);
+    assert_eq!(words[1][2..], ["DEC1", "1"]);
+    assert_eq!(
+        session.execute("x 3999 9223372036854775807").unwrap_err(),
+        "count 9223372036854775807 is too large"
+    );
+    assert_eq!(
+        session.execute("x LOOP -1").unwrap_err(),
+        "invalid count -1"
+    );
+    assert_eq!(
+        session.execute("x 3999 2").unwrap_err(),
+        "location 4000 is out of memory"
+    );
+    assert_eq!(
+        session.execute("list").unwrap(),
+        "      6  LOOP JMP ADD\n\
+        \x20     7   DEC1 1\n\
+        \x20     8   J1P LOOP\n\
+        \x20     9   STA SUM\n\
+        \x20    10   HLT\n\
+        =>   11  ADD STJ EXIT\n\
+        \x20    12   INCA 0,1\n\
+        \x20    13  EXIT JMP *\n\
+        \x20    14  SUM CON 0\n\
+        \x20    15   END START\n"
+    );
+    assert_eq!(
+        session.execute("bogus").unwrap_err(),
+        "unknown command bogus (try help)"
+    );
+}
+
+#[test]
+fn test_repl() {
+    let mut session = DebugSession::from_source(CODE).unwrap();
+    let mut output = vec![];
+    // 空行は直前のコマンドを繰り返す
+    let input = "b ADD\nc\n\np SUM-*\nq\nc\n";
+    repl(&mut session, input.as_bytes(), &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "1000  ENT1 N                  4| START ENT1 N
+(mix) breakpoint at 1007 (ADD), line 11
+(mix) breakpoint at 1007 (ADD), line 11
+1007  STJ EXIT               11| ADD STJ EXIT
+(mix) breakpoint at 1007 (ADD), line 11
+1007  STJ EXIT               11| ADD STJ EXIT
+(mix) 3
+(mix) "
+    );
+    assert_eq!(session.vm().reg_i(1).val(), 2);
+}
+
+#[test]
+fn test_evaluate() {
+    let mut symbols = BTreeMap::new();
+    symbols.insert("X".to_string(), 10);
+    assert_eq!(evaluate("X*2+*", &symbols, 100), Ok(120));
+    assert_eq!(evaluate("X//3", &symbols, 0), Ok(10 * 1073741824 / 3));
+    assert_eq!(
+        evaluate("Y", &symbols, 0),
+        Err("symbol Y is not defined".to_string())
+    );
+    assert!(evaluate("X+", &symbols, 0).is_err());
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use mix_emulator::tools::run;
+use mix_emulator::vm::{MixVM, SnapshotError};
+
+// 1..=10 を 1 行ずつ印字しながらテープにも書く
+const CODE: &str = "ORIG 3000
+                    START ENT1 1
+                    LOOP ST1 BUF
+                    OUT BUF(18)
+                    OUT BUF(0)
+                    INC1 1
+                    CMP1 =11=
+                    JL LOOP
+                    IOC 0(18)
+                    HLT
+                    BUF ORIG *+100
+                    END START";
+
+fn assert_same(expected: &MixVM, actual: &MixVM) {
+    assert_eq!(expected.state(), actual.state());
+    assert_eq!(expected.print(18), actual.print(18));
+    assert_eq!(expected.print_binary(0), actual.print_binary(0));
+}
+
+#[test]
+fn test_clone() {
+    let mut vm = common::setup(CODE);
+    for _ in 0..20 {
+        vm.step().unwrap();
+    }
+    let mut saved = vm.clone();
+    run(&mut vm).unwrap();
+    run(&mut saved).unwrap();
+    assert_same(&vm, &saved);
+}
+
+#[test]
+fn test_write_and_read() {
+    let mut vm = common::setup(CODE);
+    for _ in 0..20 {
+        vm.step().unwrap();
+    }
+
+    // 転送中の入出力も含めて保存する
+    let mut text = vec![];
+    vm.write_snapshot(&mut text).unwrap();
+    let mut restored = MixVM::read_snapshot(&text[..]).unwrap();
+    assert_eq!(vm.state(), restored.state());
+    let mut text2 = vec![];
+    restored.write_snapshot(&mut text2).unwrap();
+    assert_eq!(text, text2);
+
+    run(&mut vm).unwrap();
+    run(&mut restored).unwrap();
+    assert_same(&vm, &restored);
+    assert_eq!(10, vm.print(18).lines().count());
+}
+
+#[test]
+fn test_read_error() {
+    let error = MixVM::read_snapshot("hello\n".as_bytes()).unwrap_err();
+    assert!(matches!(error, SnapshotError::Parse(1, _)));
+
+    let text = "MIX SNAPSHOT 1\npc 3000\nrA +1.2.3\nend\n";
+    let error = MixVM::read_snapshot(text.as_bytes()).unwrap_err();
+    assert!(matches!(error, SnapshotError::Parse(3, _)));
+
+    // カード読取装置への出力, 負の位置や時刻
+    for (text, line) in [
+        ("MIX SNAPSHOT 1\nio 16 100 out 0\nend\n", 2),
+        ("MIX SNAPSHOT 1\nio 18 100 in 0 +0.0.0.0.0\nend\n", 2),
+        ("MIX SNAPSHOT 1\nio 16 100 in 0 +0.0.0.0.0\nend\n", 2),
+        ("MIX SNAPSHOT 1\nio 18 -1 control\nend\n", 2),
+        ("MIX SNAPSHOT 1\npc 0\nunit 0 -100 0 -\nend\n", 3),
+        ("MIX SNAPSHOT 1\nunit 0 0 0 -1 100\nend\n", 2),
+        ("MIX SNAPSHOT 1\npage -1\nend\n", 2),
+    ] {
+        let error = MixVM::read_snapshot(text.as_bytes()).unwrap_err();
+        assert!(
+            matches!(error, SnapshotError::Parse(n, _) if n == line),
+            "{}",
+            text
+        );
+    }
+
+    let text = "MIX SNAPSHOT 1\nbyte 100\nend\n";
+    let error = MixVM::read_snapshot(text.as_bytes()).unwrap_err();
+    assert!(matches!(error, SnapshotError::ByteSize(100)));
+}
@@ -0,0 +1,76 @@
+use mix_emulator::asm::debug_assemble;
+use mix_emulator::tools::run;
+use mix_emulator::vm::{FaultKind, MixVM, Sign, WordImpl};
+use std::cmp::Ordering;
+
+#[test]
+fn test_registers_and_memory() {
+    // rX までの和を rA と 2000 番地に求める
+    let code = "ORIG 3000
+                START ENTA 0
+                ENT1 0
+                LOOP INC1 1
+                ST1 TMP
+                ADD TMP
+                STX TMP
+                CMP1 TMP
+                JL LOOP
+                STA 2000
+                HLT
+                TMP CON 0
+                END START";
+    let (entry_point, binary, _table) = debug_assemble(code);
+    let mut vm = MixVM::new();
+    vm.load(&binary);
+    vm.set_pc(entry_point);
+    vm.set_reg_x(WordImpl::from_val(10));
+    run(&mut vm).unwrap();
+
+    assert_eq!(55, vm.reg_a().val());
+    assert_eq!(10, vm.reg_x().val());
+    assert_eq!(10, vm.reg_i(1).val());
+    assert_eq!(0, vm.reg_i(2).val());
+    assert_eq!(3008, vm.reg_j().val());
+    assert!(!vm.overflow());
+    assert_eq!(Ordering::Equal, vm.comparison());
+    assert_eq!(55, vm.memory(2000).unwrap().val());
+    assert_eq!(None, vm.memory(4000));
+
+    let state = vm.state();
+    assert_eq!(vm.pc(), state.pc);
+    assert_eq!(vm.clock(), state.clock);
+    assert_eq!(&state.reg_a, vm.reg_a());
+    assert_eq!(&state.reg_i[0], vm.reg_i(1));
+    assert_eq!(4000, state.memory.len());
+    assert_eq!(55, state.memory[2000].val());
+}
+
+#[test]
+fn test_setters() {
+    let mut vm = MixVM::new();
+    vm.set_reg_a(WordImpl::from_seq(Sign::Negative, &vec![0, 0, 0, 0, 0]));
+    assert_eq!(&Sign::Negative, vm.reg_a().sign());
+    vm.set_reg_i(6, WordImpl::from_val(-7));
+    assert_eq!(-7, vm.reg_i(6).val());
+    vm.set_reg_j(WordImpl::from_val(3000));
+    assert_eq!(3000, vm.reg_j().val());
+    vm.set_overflow(true);
+    assert!(vm.overflow());
+    vm.set_comparison(Ordering::Greater);
+    assert_eq!(Ordering::Greater, vm.comparison());
+
+    let words: Vec<_> = (1..=3).map(WordImpl::from_val).collect();
+    vm.set_memory_range(3997, &words).unwrap();
+    assert_eq!(Some(&words[..]), vm.memory_range(3997, 4000));
+    assert_eq!(None, vm.memory_range(3997, 4001));
+    assert_eq!(
+        Err(FaultKind::AddressOutOfRange(4000)),
+        vm.set_memory_range(3998, &words)
+    );
+    vm.set_memory(-1, WordImpl::from_val(9)).unwrap();
+    assert_eq!(9, vm.memory(-1).unwrap().val());
+    assert_eq!(
+        Err(FaultKind::AddressOutOfRange(-4000)),
+        vm.set_memory(-4000, WordImpl::from_val(0))
+    );
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use mix_emulator::vm::{MixVM, StepOutcome, WordImpl};
+
+// MAXIMUM (TAOCP 1.3.2 Program M) を呼び出し, 結果を印字する.
+// STJ EXIT で自分自身の命令を書き換える.
+const CODE: &str = "X EQU 1000
+                    ORIG 3000
+                    MAXIMUM STJ EXIT
+                    INIT ENT3 0,1
+                    JMP CHANGEM
+                    LOOP CMPA X,3
+                    JGE *+3
+                    CHANGEM ENT2 0,3
+                    LDA X,3
+                    DEC3 1
+                    J3P LOOP
+                    EXIT JMP *
+                    START IN 2000(16)
+                    ENT1 3
+                    JMP MAXIMUM
+                    STA 2000
+                    OUT 2000(18)
+                    HLT
+                    END START";
+
+// 入力のデータとカードを置く
+fn setup() -> MixVM {
+    let mut vm = common::setup(CODE);
+    for (i, x) in [5, 9, 2].iter().enumerate() {
+        vm.set_memory(1001 + i as i64, WordImpl::from_val(*x))
+            .unwrap();
+    }
+    let mut card = vec!["ABCDE".to_string()];
+    card.extend(vec!["     ".to_string(); 15]);
+    vm.read(16, card);
+    vm
+}
+
+fn run_to_halt(vm: &mut MixVM) -> usize {
+    let mut steps = 0;
+    loop {
+        steps += 1;
+        if let StepOutcome::Halted(_, _) = vm.step().unwrap() {
+            return steps;
+        }
+    }
+}
+
+#[test]
+fn test_step_back_to_start() {
+    let mut vm = setup();
+    vm.set_history_limit(1000);
+    let initial = vm.state();
+    let steps = run_to_halt(&mut vm);
+    assert_eq!(9, vm.reg_a().val());
+    assert_eq!(1, vm.print(18).lines().count());
+    assert_eq!(steps, vm.history_len());
+
+    // STJ EXIT で書き換えた命令, IN で読み込んだ memory, 印字もすべて元に戻る
+    let exit = vm.memory(3009).unwrap().clone();
+    for _ in 0..steps {
+        assert!(vm.step_back());
+    }
+    assert!(!vm.step_back());
+    assert_eq!(initial, vm.state());
+    assert_ne!(&exit, vm.memory(3009).unwrap());
+    assert_eq!("", vm.print(18));
+
+    // もう一度実行しても同じ結果になる
+    let again = run_to_halt(&mut vm);
+    assert_eq!(steps, again);
+    assert_eq!(9, vm.reg_a().val());
+    assert_eq!(1, vm.print(18).lines().count());
+}
+
+#[test]
+fn test_step_back_one() {
+    let mut vm = setup();
+    vm.set_history_limit(10);
+    for _ in 0..4 {
+        vm.step().unwrap();
+    }
+    // STJ EXIT を実行した直後まで戻す
+    let before = vm.state();
+    vm.step().unwrap();
+    vm.step().unwrap();
+    assert!(vm.step_back());
+    assert!(vm.step_back());
+    assert_eq!(before, vm.state());
+}
+
+#[test]
+fn test_history_limit() {
+    let mut vm = setup();
+    assert!(!vm.step_back());
+    vm.step().unwrap();
+    assert!(!vm.step_back());
+
+    vm.set_history_limit(3);
+    for _ in 0..10 {
+        vm.step().unwrap();
+    }
+    assert_eq!(3, vm.history_len());
+    for _ in 0..3 {
+        assert!(vm.step_back());
+    }
+    assert!(!vm.step_back());
+}
@@ -64,9 +64,9 @@ fn test_swap1() {
                 JXNZ *-3
                 HLT
                 *
-                LPREN ALF ____(
-                RPREN ALF )____
-                EQUALS ALF ____=
+                LPREN ALF     (
+                RPREN ALF \")    \"
+                EQUALS ALF \"    =\"
                 *
                 G0 MOVE LPREN
                 MOVE PERM,3
@@ -287,9 +287,9 @@ fn test_swap2() {
                 JXNZ *-3
                 HLT
                 *
-                LPREN ALF ____(
-                RPREN ALF )____
-                EQUALS ALF ____=
+                LPREN ALF     (
+                RPREN ALF \")    \"
+                EQUALS ALF \"    =\"
                 END BEGIN";
 
     let input1 = vec![
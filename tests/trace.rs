@@ -0,0 +1,117 @@
+use mix_emulator::asm::assemble;
+use mix_emulator::disasm::Disassembler;
+use mix_emulator::tools::{
+    read_trace, trace, trace_diff, write_trace, Change, TraceFilter, TraceStep,
+};
+use mix_emulator::vm::{Location, MixVM, WordImpl};
+use std::cmp::Ordering;
+
+const CODE: &str = " ORIG 1000
+START ENTA 1
+ ENN1 5
+ STA X
+ CMPA BIG
+ ADD BIG
+ ENTX -5
+ HLT
+X CON 0
+BIG CON 1073741823
+ END START";
+
+fn record(code: &str, filter: &TraceFilter) -> Vec<TraceStep> {
+    let program = assemble(code).unwrap();
+    let mut vm = MixVM::new();
+    vm.load(&program);
+    let (steps, info) = trace(
+        &mut vm,
+        Disassembler::with_symbols(&program.symbols),
+        filter,
+    )
+    .unwrap();
+    assert!(info.halt().is_some());
+    steps
+}
+
+#[test]
+fn test_trace() {
+    let steps = record(CODE, &TraceFilter::default());
+    let lines: Vec<String> = steps.iter().map(|step| step.to_string()).collect();
+    assert_eq!(
+        lines,
+        [
+            "1 1000 +0.1.0.2.48 m=1 clock=1 rA=+1 | ENTA 1",
+            "2 1001 +0.5.0.3.49 m=5 clock=2 rI1=-5 | ENN1 5",
+            "3 1002 +15.47.0.5.24 m=1007 clock=4 M[1007]=+1 | STA X",
+            "4 1003 +15.48.0.5.56 m=1008 clock=6 CI=L | CMPA BIG",
+            "5 1004 +15.48.0.5.1 m=1008 clock=8 rA=+0 OV=1 | ADD BIG",
+            "6 1005 -0.5.0.2.55 m=-5 clock=9 rX=-5 | ENTX -5",
+            "7 1006 +0.0.0.2.5 m=0 clock=19 | HLT",
+        ]
+    );
+    assert_eq!(steps[4].changes[1], Change::Overflow(true));
+    assert_eq!(steps[3].changes, [Change::Comparison(Ordering::Less)]);
+    assert_eq!(
+        steps[2].changes[0],
+        Change::Word(Location::Memory(1007), WordImpl::from_val(1))
+    );
+
+    // 書き出して読み戻す
+    let mut text = vec![];
+    write_trace(&steps, &mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.starts_with("MIX TRACE 1\n1 1000 "));
+    assert_eq!(read_trace(text.as_bytes()).unwrap(), steps);
+}
+
+#[test]
+fn test_filter_and_diff() {
+    // 番地の範囲と命令の名前で選ぶ. 番号は元のまま
+    let filter = TraceFilter {
+        range: Some((1002, 1005)),
+        operations: vec!["STA".to_string(), "ENTX".to_string(), "HLT".to_string()],
+    };
+    let steps = record(CODE, &filter);
+    let names: Vec<(usize, &str)> = steps
+        .iter()
+        .map(|step| (step.step, step.instruction.as_str()))
+        .collect();
+    assert_eq!(names, [(3, "STA X"), (6, "ENTX -5")]);
+
+    let reference = record(CODE, &TraceFilter::default());
+    assert_eq!(trace_diff(&reference, &reference), None);
+    // 定数だけ異なる解答は ADD で食い違う. シンボルの名前は比べない
+    let student = record(
+        &CODE
+            .replace("BIG CON 1073741823", "BIG CON 1073741822")
+            .replace("X CON", "Y CON")
+            .replace("A X", "A Y"),
+        &TraceFilter::default(),
+    );
+    assert_eq!(trace_diff(&reference, &student), Some(4));
+    assert_eq!(trace_diff(&reference[..3], &reference), Some(3));
+}
+
+#[test]
+fn test_read_errors() {
+    let read = |text: &str| read_trace(text.as_bytes()).unwrap_err();
+    assert_eq!(read("1 1000\n").0, 1);
+    assert_eq!(
+        read("MIX TRACE 1\n1 1000 +0.0.0.2.5 m=0 clock=10\n"),
+        (2, "expected | and an instruction".to_string())
+    );
+    assert_eq!(
+        read("MIX TRACE 1\n1 1000 +0.0.0.2.5 m=0 clock=10 rQ=+1 | HLT\n"),
+        (2, "invalid change rQ=+1".to_string())
+    );
+    let steps =
+        read_trace("MIX TRACE 1\n\n1 -5 +0.0.0.2.5 m=0 clock=10 CI=G OV=0 | HLT\n".as_bytes())
+            .unwrap();
+    assert_eq!(steps[0].pc, -5);
+    assert_eq!(
+        steps[0].changes,
+        [
+            Change::Comparison(Ordering::Greater),
+            Change::Overflow(false)
+        ]
+    );
+}